@@ -0,0 +1,239 @@
+#[allow(dead_code)]
+
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+use crate::sync::Once;
+use crate::vm::VM;
+
+/* Where the RSDP may live on BIOS systems */
+const EBDA_POINTER: u64     = 0x40e;
+const BIOS_AREA_START: u64  = 0xe0000;
+const BIOS_AREA_END: u64    = 0x100000;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/* MADT entry types */
+const MADT_IOAPIC: u8           = 1;
+const MADT_SOURCE_OVERRIDE: u8  = 2;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    /* ACPI 2.0+ */
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct HpetTable {
+    pub header: SdtHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+#[derive(Clone, Copy)]
+pub struct IOAPICEntry {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+#[derive(Clone, Copy)]
+struct Root {
+    address: u64,
+    /* XSDT entries are 64 bits wide, RSDT entries 32 */
+    extended: bool,
+}
+
+static ROOT: Once<Option<Root>> = Once::new();
+
+pub struct ACPI;
+
+impl ACPI {
+    fn read<T: Copy>(phys: u64) -> T {
+        unsafe { read_unaligned(VM::phys_to_virt(phys) as *const T) }
+    }
+
+    fn checksum(phys: u64, length: usize) -> bool {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(VM::phys_to_virt(phys) as *const u8, length)
+        };
+
+        bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+    }
+
+    fn scan_rsdp(start: u64, end: u64) -> Option<u64> {
+        (start..end).step_by(16).find(|&phys| {
+            let signature: [u8; 8] = ACPI::read(phys);
+            &signature == RSDP_SIGNATURE && ACPI::checksum(phys, 20)
+        })
+    }
+
+    fn find_root() -> Option<Root> {
+        let ebda = (ACPI::read::<u16>(EBDA_POINTER) as u64) << 4;
+
+        let rsdp = ACPI::scan_rsdp(ebda, ebda + 1024)
+            .or_else(|| ACPI::scan_rsdp(BIOS_AREA_START, BIOS_AREA_END))?;
+        let rsdp: Rsdp = ACPI::read(rsdp);
+
+        if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            Some(Root { address: rsdp.xsdt_address, extended: true })
+        } else {
+            Some(Root { address: rsdp.rsdt_address as u64, extended: false })
+        }
+    }
+
+    fn root() -> Option<Root> {
+        *ROOT.call_once(ACPI::find_root)
+    }
+
+    /* A table with a header that fits its length and a valid checksum */
+    fn valid_table(phys: u64) -> Option<SdtHeader> {
+        let header: SdtHeader = ACPI::read(phys);
+        let length = header.length as usize;
+
+        if length < size_of::<SdtHeader>() || !ACPI::checksum(phys, length) {
+            return None;
+        }
+
+        Some(header)
+    }
+
+    /// Returns the physical address of the first valid table with
+    /// `signature`.
+    pub fn find_table(signature: &[u8; 4]) -> Option<u64> {
+        let root = ACPI::root()?;
+        let header = ACPI::valid_table(root.address)?;
+        let entry_size = if root.extended { 8 } else { 4 };
+        let entries = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+        let first = root.address + size_of::<SdtHeader>() as u64;
+
+        for index in 0..entries {
+            let entry = first + (index * entry_size) as u64;
+            let table = if root.extended {
+                ACPI::read::<u64>(entry)
+            } else {
+                ACPI::read::<u32>(entry) as u64
+            };
+
+            let header: SdtHeader = ACPI::read(table);
+            if &header.signature == signature && ACPI::valid_table(table).is_some() {
+                return Some(table);
+            }
+        }
+
+        None
+    }
+
+    pub fn hpet() -> Option<HpetTable> {
+        ACPI::find_table(b"HPET").map(ACPI::read)
+    }
+
+    /* Calls `f` with the type, length and physical address of every MADT entry */
+    fn for_each_madt_entry<F: FnMut(u8, u8, u64)>(mut f: F) {
+        let madt = match ACPI::find_table(b"APIC") {
+            Some(madt) => madt,
+            None => return,
+        };
+
+        let header: SdtHeader = ACPI::read(madt);
+        let end = madt + header.length as u64;
+        /* Local APIC address and flags follow the header */
+        let mut entry = madt + size_of::<SdtHeader>() as u64 + 8;
+
+        while entry + 2 <= end {
+            let kind: u8 = ACPI::read(entry);
+            let length: u8 = ACPI::read(entry + 1);
+
+            if length < 2 {
+                break;
+            }
+
+            f(kind, length, entry);
+            entry += length as u64;
+        }
+    }
+
+    /// First I/O APIC listed in the MADT.
+    pub fn ioapic() -> Option<IOAPICEntry> {
+        let mut ioapic = None;
+
+        ACPI::for_each_madt_entry(|kind, _, entry| {
+            if kind == MADT_IOAPIC && ioapic.is_none() {
+                ioapic = Some(IOAPICEntry {
+                    id: ACPI::read(entry + 2),
+                    address: ACPI::read(entry + 4),
+                    gsi_base: ACPI::read(entry + 8),
+                });
+            }
+        });
+
+        ioapic
+    }
+
+    /// Interrupt source override for the ISA `irq`, if the firmware wired it
+    /// to a different global system interrupt.
+    pub fn source_override(irq: u8) -> Option<SourceOverride> {
+        let mut result = None;
+
+        ACPI::for_each_madt_entry(|kind, _, entry| {
+            if kind == MADT_SOURCE_OVERRIDE && ACPI::read::<u8>(entry + 3) == irq {
+                result = Some(SourceOverride {
+                    irq,
+                    gsi: ACPI::read(entry + 4),
+                    flags: ACPI::read(entry + 8),
+                });
+            }
+        });
+
+        result
+    }
+
+    /// Global system interrupt that the ISA `irq` is delivered on.
+    pub fn isa_irq_to_gsi(irq: u8) -> u32 {
+        ACPI::source_override(irq).map_or(irq as u32, |o| o.gsi)
+    }
+}
@@ -1,7 +1,11 @@
 #[allow(dead_code)]
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpuid::{self, Feature};
 use crate::msr::*;
 use crate::interrupt_controller::InterruptController;
+use crate::time::ClockEvent;
 
 /* APIC Timer Delivery Mode */
 const TIMER_MODE_ONE_SHOT: u32      = 0x0 << 17;
@@ -32,6 +36,10 @@ const DESTINATION_SHORTHAND_SELF: u32           = 0x1 << 18;
 const DESTINATION_SHORTHAND_ALL_INC_SELF: u32   = 0x2 << 18;
 const DESTINATION_SHORTHAND_ALL_EXC_SELF: u32   = 0x3 << 18;
 
+/* APIC Delivery Status */
+const DELIVERY_STATUS_IDLE: u32     = 0x0 << 12;
+const DELIVERY_STATUS_PENDING: u32  = 0x1 << 12;
+
 /* APIC Mask */
 const NOT_MASKED: u32   = 0 << 16;
 const MASKED: u32       = 1 << 16;
@@ -83,6 +91,12 @@ const BASE_MASK: u64    = 0x7fffff000;
 
 const SPURIOUS_VECTOR: u32 = 39;
 
+/* x2APIC registers are MSRs at this base plus the xAPIC offset / 16 */
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/* Set while the local APICs run in x2APIC mode */
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
 #[derive(Clone, Copy)]
 pub enum APICLVTEntry {
     APIC_LVT_TIMER,
@@ -144,6 +158,21 @@ pub enum APICDestinationShorthand {
     APIC_DESTINATION_SHORTHAND_NA,
 }
 
+#[derive(Clone, Copy)]
+pub enum APICDestination {
+    /// APIC ID, up to 255 outside x2APIC mode.
+    APIC_DESTINATION_PHYSICAL(u32),
+    APIC_DESTINATION_LOGICAL(u32),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum APICError {
+    /// The destination does not fit the 8-bit field of xAPIC mode.
+    APIC_ERROR_INVALID_DESTINATION,
+    /// The ESR bits latched while sending, see the `APIC::ESR_*` constants.
+    APIC_ERROR_SEND(u32),
+}
+
 #[derive(Clone, Copy)]
 pub struct APICInterrupt {
     vector: u8,
@@ -161,11 +190,37 @@ pub struct APIC;
 impl APIC {
     pub const ADDRESS: u32 = 0xfee00000;
 
+    /* APIC Error Status Register bits */
+    pub const ESR_SEND_CHECKSUM: u32            = 1 << 0;
+    pub const ESR_RECEIVE_CHECKSUM: u32         = 1 << 1;
+    pub const ESR_SEND_ACCEPT: u32              = 1 << 2;
+    pub const ESR_RECEIVE_ACCEPT: u32           = 1 << 3;
+    pub const ESR_REDIRECTABLE_IPI: u32         = 1 << 4;
+    pub const ESR_SEND_ILLEGAL_VECTOR: u32      = 1 << 5;
+    pub const ESR_RECEIVE_ILLEGAL_VECTOR: u32   = 1 << 6;
+    pub const ESR_ILLEGAL_REGISTER: u32         = 1 << 7;
+
+    fn x2apic_msr(index: usize) -> u32 {
+        X2APIC_MSR_BASE + (index >> 4) as u32
+    }
+
+    fn read_x2apic(index: usize) -> u64 {
+        unsafe { MSR::read_raw(APIC::x2apic_msr(index)) }
+    }
+
+    fn write_x2apic(index: usize, value: u64) {
+        unsafe { MSR::write_raw(APIC::x2apic_msr(index), value) }
+    }
+
     fn read32(index: usize) -> Option<u32> {
         if (index & 0xf) != 0 {
             return None;
         }
 
+        if APIC::x2apic() {
+            return Some(APIC::read_x2apic(index) as u32);
+        }
+
         return Some(
             unsafe {
                 let apic_page: *mut u32 = APIC::ADDRESS as *mut u32;
@@ -179,12 +234,21 @@ impl APIC {
             return
         }
 
+        if APIC::x2apic() {
+            return APIC::write_x2apic(index, value as u64);
+        }
+
         unsafe {
             let apic_page: *mut u32 = APIC::ADDRESS as *mut u32;
             apic_page.offset((index >> 2) as isize).write_volatile(value)
         };
     }
 
+    /// Whether the local APIC is accessed through x2APIC MSRs.
+    pub fn x2apic() -> bool {
+        X2APIC.load(Ordering::Relaxed)
+    }
+
     fn update_base(base: u32, xapic: bool, x2apic: bool) {
         let mut value = (base as u64) << BASE_SHIFT;
 
@@ -298,6 +362,7 @@ impl APIC {
         value |= APIC::masked(interrupt.masked);
         value |= APIC::trigger(interrupt.trigger);
         value |= APIC::timer_mode(interrupt.timer_mode);
+        value |= APIC::destination_shorthand(interrupt.dest_shorthand);
 
         value
     }
@@ -344,7 +409,17 @@ impl APIC {
         APIC::write32(TIMER_ICR, value);
     }
 
-    pub fn set_timer_tscdeadline_mode() {
+    pub fn timer_current() -> u32 {
+        APIC::read32(TIMER_CCR).unwrap()
+    }
+
+    /// Fails if the CPU has no TSC-deadline mode, the timer is left alone
+    /// then.
+    pub fn set_timer_tscdeadline_mode() -> Option<()> {
+        if !cpuid::has(Feature::FEATURE_TSC_DEADLINE) {
+            return None;
+        }
+
         let interrupt = APICInterrupt {
             vector: 32,
             delivery: APICDeliveryMode::APIC_DELIVERY_NA,
@@ -357,12 +432,102 @@ impl APIC {
         };
 
         APIC::update_lvt(&interrupt, APICLVTEntry::APIC_LVT_TIMER);
+        Some(())
     }
 
     pub fn set_timer_tscdeadline(value: u64) {
         unsafe { MSR::IA32_TSC_DEADLINE.write(value) }
     }
 
+    fn delivery_pending() -> bool {
+        (APIC::read32(ICR0).unwrap() & DELIVERY_STATUS_PENDING) != DELIVERY_STATUS_IDLE
+    }
+
+    /// Spin until the previously written IPI has been accepted by the local
+    /// APIC (the ICR delivery status bit goes back to idle).
+    pub fn wait_for_delivery() {
+        while APIC::delivery_pending() {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    /// Reads the Error Status Register.
+    ///
+    /// The ESR has to be written before it is read so that the errors latched
+    /// since the last write become visible, this also clears them.
+    pub fn error_status() -> u32 {
+        APIC::write32(ESR, 0);
+        APIC::read32(ESR).unwrap()
+    }
+
+    fn write_icr(destination: u32, interrupt: &APICInterrupt) {
+        /* A single 64-bit register with a 32-bit destination in x2APIC mode */
+        if APIC::x2apic() {
+            let value = ((destination as u64) << 32) | APIC::interrupt_entry(interrupt) as u64;
+            return APIC::write_x2apic(ICR0, value);
+        }
+
+        APIC::write32(ICR1, destination << 24);
+        APIC::write32(ICR0, APIC::interrupt_entry(interrupt));
+    }
+
+    /// Sends an IPI and waits for the local APIC to dispatch it.
+    ///
+    /// `dest` is ignored by the hardware unless `shorthand` is
+    /// `APIC_DESTINATION_SHORTHAND_NONE`. The vector is ignored for NMI, SMI
+    /// and INIT delivery. Fails without sending for a destination above 255
+    /// in xAPIC mode, it would reach the wrong CPU.
+    pub fn send_ipi(dest: APICDestination,
+                    vector: u8,
+                    delivery: APICDeliveryMode,
+                    shorthand: APICDestinationShorthand) -> Result<(), APICError> {
+        let (destination, apic_id) = match dest {
+            APICDestination::APIC_DESTINATION_PHYSICAL(id) =>
+                (APICDestinationMode::APIC_DESTINATION_PHYSICAL, id),
+            APICDestination::APIC_DESTINATION_LOGICAL(id) =>
+                (APICDestinationMode::APIC_DESTINATION_LOGICAL, id),
+        };
+
+        let addressed = match shorthand {
+            APICDestinationShorthand::APIC_DESTINATION_SHORTHAND_NONE => true,
+            _ => false,
+        };
+
+        if addressed && !APIC::x2apic() && apic_id > 0xff {
+            return Err(APICError::APIC_ERROR_INVALID_DESTINATION);
+        }
+
+        let vector = match delivery {
+            APICDeliveryMode::APIC_DELIVERY_NMI |
+            APICDeliveryMode::APIC_DELIVERY_SMI |
+            APICDeliveryMode::APIC_DELIVERY_INIT => 0,
+            _ => vector,
+        };
+
+        let interrupt = APICInterrupt {
+            vector,
+            delivery,
+            destination,
+            level: APICLevel::APIC_LEVEL_ASSERT,
+            trigger: APICTriggerMode::APIC_TRIGGER_EDGE,
+            dest_shorthand: shorthand,
+            timer_mode: APICTimerMode::APIC_TIMER_NA,
+            masked: false,
+        };
+
+        /* Drop any errors latched before this IPI */
+        APIC::error_status();
+
+        APIC::wait_for_delivery();
+        APIC::write_icr(apic_id, &interrupt);
+        APIC::wait_for_delivery();
+
+        match APIC::error_status() {
+            0 => Ok(()),
+            esr => Err(APICError::APIC_ERROR_SEND(esr)),
+        }
+    }
+
     pub fn self_ipi(ipi: u8) {
         let interrupt = APICInterrupt {
             vector: ipi,
@@ -375,8 +540,7 @@ impl APIC {
             masked: false,
         };
 
-        APIC::write32(ICR1, 0);
-        APIC::write32(ICR0, APIC::interrupt_entry(&interrupt));
+        APIC::write_icr(0, &interrupt);
     }
 
     pub fn wake_ap(apic_id: u32, address: u32) {
@@ -392,8 +556,7 @@ impl APIC {
             timer_mode: APICTimerMode::APIC_TIMER_NA,
             masked: false,
         };
-        APIC::write32(ICR1, apic_id << 24);
-        APIC::write32(ICR0, APIC::interrupt_entry(&interrupt));
+        APIC::write_icr(apic_id, &interrupt);
 
         interrupt = APICInterrupt {
             vector: 0,
@@ -405,8 +568,7 @@ impl APIC {
             timer_mode: APICTimerMode::APIC_TIMER_NA,
             masked: false,
         };
-        APIC::write32(ICR1, apic_id << 24);
-        APIC::write32(ICR0, APIC::interrupt_entry(&interrupt));
+        APIC::write_icr(apic_id, &interrupt);
 
         /* The CPU is now ready to receive the startup IPI */
         for _ in 0..2 {
@@ -420,13 +582,17 @@ impl APIC {
                 timer_mode: APICTimerMode::APIC_TIMER_NA,
                 masked: false,
             };
-            APIC::write32(ICR1, apic_id << 24);
-            APIC::write32(ICR0, APIC::interrupt_entry(&interrupt));
+            APIC::write_icr(apic_id, &interrupt);
         }
     }
 
     pub fn id() -> u32 {
-        APIC::read32(ID).unwrap() >> 24
+        /* The x2APIC ID takes the whole register */
+        if APIC::x2apic() {
+            APIC::read32(ID).unwrap()
+        } else {
+            APIC::read32(ID).unwrap() >> 24
+        }
     }
 
     pub fn start() {
@@ -442,18 +608,28 @@ impl APIC {
 }
 
 impl InterruptController for APIC {
+    /// Switches to x2APIC mode when the CPU has it, xAPIC otherwise.
     fn enable() {
-        /* no support for x2apic for now */
-        let x2apic = false;
+        let x2apic = cpuid::has(Feature::FEATURE_X2APIC);
 
         if x2apic {
             APIC::update_base(APIC::ADDRESS, true, true);
         } else {
             APIC::update_base(APIC::ADDRESS, true, false);
         }
+
+        X2APIC.store(x2apic, Ordering::Relaxed);
     }
 
+    /// Back to xAPIC mode.
     fn disable() {
+        /* x2APIC to xAPIC is an invalid transition, it has to go through
+         * the disabled state */
+        if APIC::x2apic() {
+            APIC::update_base(APIC::ADDRESS, false, false);
+            X2APIC.store(false, Ordering::Relaxed);
+        }
+
         APIC::update_base(APIC::ADDRESS, true, false);
     }
 
@@ -507,4 +683,57 @@ impl InterruptController for APIC {
     fn unmask(_irq: u32) {
 
     }
-}
\ No newline at end of file
+}
+
+/// The local APIC timer of the running CPU as a `ClockEvent`.
+///
+/// `frequency` is the tick rate with the current divide configuration, see
+/// `PIT::calibrate_apic_timer`.
+pub struct APICTimer {
+    frequency: u64,
+}
+
+impl APICTimer {
+    pub fn new(frequency: u64) -> Self {
+        APICTimer { frequency }
+    }
+
+    fn clamp(ticks: u64) -> u32 {
+        if ticks > 0xffffffff {
+            0xffffffff
+        } else if ticks == 0 {
+            1
+        } else {
+            ticks as u32
+        }
+    }
+}
+
+impl ClockEvent for APICTimer {
+    fn name(&self) -> &'static str {
+        "apic"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn set_oneshot(&self, ticks: u64) {
+        APIC::set_timer_oneshot_mode();
+        APIC::set_timer_oneshot(APICTimer::clamp(ticks));
+    }
+
+    fn set_periodic(&self, ticks: u64) {
+        APIC::set_timer_periodic_mode();
+        APIC::set_timer_period(APICTimer::clamp(ticks));
+    }
+
+    fn stop(&self) {
+        /* Writing 0 to the initial count stops the timer */
+        APIC::set_timer_oneshot(0);
+    }
+}
@@ -0,0 +1,21 @@
+use x86_64::instructions::port::PortWrite;
+
+use crate::console::Console;
+
+const PORT: u16 = 0xe9;
+
+/// QEMU's and Bochs' debug console, `-debugcon stdio` on the QEMU command
+/// line. Writes go nowhere on real hardware.
+pub struct DebugCon;
+
+impl Console for DebugCon {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        for &byte in bytes {
+            unsafe { PortWrite::write_to_port(PORT, byte) }
+        }
+    }
+}
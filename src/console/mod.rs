@@ -0,0 +1,104 @@
+//! Output sinks behind `print!`.
+//!
+//! Every enabled console gets everything printed. Until the first console is
+//! registered, output goes straight to COM1 so early boot messages are not
+//! lost.
+
+use crate::serial::SerialPort;
+use crate::sync::IrqSpinLock;
+
+mod debugcon;
+mod ring;
+
+pub use self::debugcon::DebugCon;
+pub use self::ring::{RingConsole, RING};
+
+pub const MAX_CONSOLES: usize = 8;
+
+pub trait Console: Sync {
+    /// Used to enable and disable the console at runtime.
+    fn name(&self) -> &'static str;
+
+    /// Called with the print lock held, output of different CPUs does not
+    /// interleave.
+    fn write(&self, bytes: &[u8]);
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    console: &'static dyn Console,
+    enabled: bool,
+}
+
+static CONSOLES: IrqSpinLock<[Option<Entry>; MAX_CONSOLES]> = IrqSpinLock::new([None; MAX_CONSOLES]);
+
+/// Adds an enabled console, `None` if the registry is full or a console with
+/// the same name exists.
+pub fn register(console: &'static dyn Console) -> Option<()> {
+    let mut consoles = CONSOLES.lock();
+
+    if consoles.iter().flatten().any(|entry| entry.console.name() == console.name()) {
+        return None;
+    }
+
+    let slot = consoles.iter_mut().find(|entry| entry.is_none())?;
+    *slot = Some(Entry { console, enabled: true });
+    Some(())
+}
+
+pub fn unregister(name: &str) -> bool {
+    let mut consoles = CONSOLES.lock();
+
+    match consoles.iter_mut().find(|entry| entry.map_or(false, |e| e.console.name() == name)) {
+        Some(entry) => {
+            *entry = None;
+            true
+        },
+        None => false,
+    }
+}
+
+fn set_enabled(name: &str, enabled: bool) -> bool {
+    let mut consoles = CONSOLES.lock();
+
+    match consoles.iter_mut().flatten().find(|entry| entry.console.name() == name) {
+        Some(entry) => {
+            entry.enabled = enabled;
+            true
+        },
+        None => false,
+    }
+}
+
+/// Returns false if there is no console called `name`.
+pub fn enable(name: &str) -> bool {
+    set_enabled(name, true)
+}
+
+pub fn disable(name: &str) -> bool {
+    set_enabled(name, false)
+}
+
+/// Calls `f` with the name and state of every registered console.
+pub fn for_each<F: FnMut(&'static str, bool)>(mut f: F) {
+    let consoles = *CONSOLES.lock();
+
+    for entry in consoles.iter().flatten() {
+        f(entry.console.name(), entry.enabled);
+    }
+}
+
+/// Writes `bytes` to all enabled consoles.
+pub fn write(bytes: &[u8]) {
+    /* A copy, so consoles can print (e.g. lockdep) without deadlocking */
+    let consoles = *CONSOLES.lock();
+
+    if consoles.iter().all(Option::is_none) {
+        Console::write(&SerialPort::COM1, bytes);
+        return;
+    }
+
+    for entry in consoles.iter().flatten().filter(|entry| entry.enabled) {
+        entry.console.write(bytes);
+    }
+}
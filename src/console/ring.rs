@@ -0,0 +1,66 @@
+use crate::console::Console;
+use crate::sync::IrqSpinLock;
+
+pub const RING_SIZE: usize = 16384;
+
+struct Ring {
+    data: [u8; RING_SIZE],
+    /* Total bytes ever written, the oldest ones got overwritten */
+    written: usize,
+}
+
+/// Keeps the last `RING_SIZE` bytes of output in memory, e.g. for a
+/// debugger or a crash dump.
+pub struct RingConsole {
+    ring: IrqSpinLock<Ring>,
+}
+
+pub static RING: RingConsole = RingConsole::new();
+
+impl RingConsole {
+    pub const fn new() -> Self {
+        RingConsole {
+            ring: IrqSpinLock::new(Ring { data: [0; RING_SIZE], written: 0 }),
+        }
+    }
+
+    /// Total bytes written so far, including the ones no longer kept.
+    pub fn written(&self) -> usize {
+        self.ring.lock().written
+    }
+
+    /// Copies output starting at `offset` (counted like `written()`) into
+    /// `buffer`. Skips ahead if `offset` was overwritten already; returns the
+    /// offset actually read from and the number of bytes copied.
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) -> (usize, usize) {
+        let ring = self.ring.lock();
+        let start = offset.max(ring.written.saturating_sub(RING_SIZE));
+        let len = buffer.len().min(ring.written.saturating_sub(start));
+
+        for (index, byte) in buffer[..len].iter_mut().enumerate() {
+            *byte = ring.data[(start + index) % RING_SIZE];
+        }
+
+        (start, len)
+    }
+
+    pub fn clear(&self) {
+        self.ring.lock().written = 0;
+    }
+}
+
+impl Console for RingConsole {
+    fn name(&self) -> &'static str {
+        "ring"
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        let mut ring = self.ring.lock();
+
+        for &byte in bytes {
+            let index = ring.written % RING_SIZE;
+            ring.data[index] = byte;
+            ring.written += 1;
+        }
+    }
+}
@@ -1,12 +1,79 @@
 #[allow(dead_code)]
 
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use x86_64::instructions::interrupts;
 use x86_64::registers::rflags;
 use x86_64::registers::rflags::RFlags;
 
+use crate::cpuid;
+use crate::msr::MSR;
+
+/* Per-CPU arrays are indexed by the logical CPU index, not the APIC ID:
+ * x2APIC IDs are sparse and can go past any sensible array size */
+pub const MAX_CPUS: usize = 256;
+
+const NO_APIC_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/* APIC ID of each registered index, for IPIs */
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [NO_APIC_ID; MAX_CPUS];
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
 pub struct CPU;
 
 impl CPU {
+    /// Logical index of the running CPU, dense from 0 in the order CPUs
+    /// called `register()`. Kept in GS_BASE, whose reset value makes it 0
+    /// before that, so it takes neither CPUID nor a lock.
+    pub fn id() -> usize {
+        unsafe { MSR::GS_BASE.read() as usize }
+    }
+
+    /// Gives the running CPU the next logical index, or the one it already
+    /// has. Fails once MAX_CPUS are registered. Loading a segment into GS
+    /// afterwards may clear the index again.
+    pub fn register() -> Option<usize> {
+        let apic_id = CPU::apic_id();
+        let online = ONLINE.load(Ordering::Acquire);
+
+        if let Some(index) = (0..online).find(|&i| APIC_IDS[i].load(Ordering::Relaxed) == apic_id) {
+            unsafe { MSR::GS_BASE.write(index as u64) };
+            return Some(index);
+        }
+
+        let index = ONLINE.fetch_update(Ordering::AcqRel, Ordering::Acquire, |online| {
+            if online < MAX_CPUS { Some(online + 1) } else { None }
+        }).ok()?;
+
+        APIC_IDS[index].store(apic_id, Ordering::Relaxed);
+        unsafe { MSR::GS_BASE.write(index as u64) };
+        Some(index)
+    }
+
+    /// Number of registered CPUs.
+    pub fn count() -> usize {
+        ONLINE.load(Ordering::Acquire)
+    }
+
+    /// x2APIC ID of the running CPU, the 8-bit initial APIC ID where the
+    /// CPU has no topology leaf. Read through CPUID so it works before (or
+    /// without) the local APIC being mapped.
+    pub fn apic_id() -> u32 {
+        cpuid::topology().x2apic_id
+    }
+
+    /// APIC ID of the CPU registered as `index`, the destination of IPIs
+    /// to it.
+    pub fn apic_id_of(index: usize) -> Option<u32> {
+        match APIC_IDS.get(index)?.load(Ordering::Relaxed) {
+            u32::MAX => None,
+            apic_id => Some(apic_id),
+        }
+    }
+
+    pub fn irq_enabled() -> bool {
+        rflags::read().contains(RFlags::INTERRUPT_FLAG)
+    }
+
     pub fn irq_save() -> RFlags {
         let mut flags = rflags::read();
         let backup = flags.clone();
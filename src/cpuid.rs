@@ -0,0 +1,603 @@
+//! CPUID queries.
+//!
+//! The feature leaves are read once, on first use, and cached; every CPU in
+//! the system is assumed to report the same features. Leaves that depend on
+//! the running CPU (APIC IDs) or on control registers (OSXSAVE) are read live
+//! or not exposed here.
+
+use core::arch::x86_64::{CpuidResult, __cpuid_count};
+
+use crate::sync::Once;
+
+const MAX_CACHES: usize = 8;
+
+/* Cached feature leaves, indices into `Info::leaves` */
+const LEAF_1: usize                 = 0;
+const LEAF_6: usize                 = 1;
+const LEAF_7: usize                 = 2;
+const LEAF_D_1: usize               = 3;
+const LEAF_80000001: usize          = 4;
+const LEAF_80000007: usize          = 5;
+const LEAF_8000000A: usize          = 6;
+const LEAVES: usize                 = 7;
+
+/* Registers within a leaf */
+const EAX: usize = 0;
+const EBX: usize = 1;
+const ECX: usize = 2;
+const EDX: usize = 3;
+
+/* Leaf 0xB/0x1F level types */
+const LEVEL_TYPE_INVALID: u32   = 0;
+const LEVEL_TYPE_SMT: u32       = 1;
+
+/* Leaf 4/0x8000001D cache types */
+const CACHE_TYPE_NULL: u32          = 0;
+const CACHE_TYPE_DATA: u32          = 1;
+const CACHE_TYPE_INSTRUCTION: u32   = 2;
+const CACHE_TYPE_UNIFIED: u32       = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Vendor {
+    VENDOR_INTEL,
+    VENDOR_AMD,
+    VENDOR_OTHER,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Feature {
+    /* Leaf 1, EDX */
+    FEATURE_FPU,
+    FEATURE_VME,
+    FEATURE_DE,
+    FEATURE_PSE,
+    FEATURE_TSC,
+    FEATURE_MSR,
+    FEATURE_PAE,
+    FEATURE_MCE,
+    FEATURE_CX8,
+    FEATURE_APIC,
+    FEATURE_SEP,
+    FEATURE_MTRR,
+    FEATURE_PGE,
+    FEATURE_MCA,
+    FEATURE_CMOV,
+    FEATURE_PAT,
+    FEATURE_PSE36,
+    FEATURE_CLFLUSH,
+    FEATURE_ACPI,
+    FEATURE_MMX,
+    FEATURE_FXSR,
+    FEATURE_SSE,
+    FEATURE_SSE2,
+    FEATURE_HTT,
+    /* Leaf 1, ECX */
+    FEATURE_SSE3,
+    FEATURE_PCLMULQDQ,
+    FEATURE_MONITOR,
+    FEATURE_VMX,
+    FEATURE_SMX,
+    FEATURE_SSSE3,
+    FEATURE_FMA,
+    FEATURE_CX16,
+    FEATURE_PCID,
+    FEATURE_SSE4_1,
+    FEATURE_SSE4_2,
+    FEATURE_X2APIC,
+    FEATURE_MOVBE,
+    FEATURE_POPCNT,
+    FEATURE_TSC_DEADLINE,
+    FEATURE_AES,
+    FEATURE_XSAVE,
+    FEATURE_AVX,
+    FEATURE_F16C,
+    FEATURE_RDRAND,
+    FEATURE_HYPERVISOR,
+    /* Leaf 6, EAX */
+    FEATURE_ARAT,
+    /* Leaf 7, EBX */
+    FEATURE_FSGSBASE,
+    FEATURE_TSC_ADJUST,
+    FEATURE_BMI1,
+    FEATURE_AVX2,
+    FEATURE_SMEP,
+    FEATURE_BMI2,
+    FEATURE_ERMS,
+    FEATURE_INVPCID,
+    FEATURE_AVX512F,
+    FEATURE_RDSEED,
+    FEATURE_ADX,
+    FEATURE_SMAP,
+    FEATURE_CLFLUSHOPT,
+    FEATURE_CLWB,
+    /* Leaf 7, ECX */
+    FEATURE_UMIP,
+    FEATURE_PKU,
+    FEATURE_LA57,
+    FEATURE_RDPID,
+    /* Leaf 7, EDX */
+    FEATURE_MD_CLEAR,
+    FEATURE_ARCH_CAPABILITIES,
+    /* Leaf 0xD subleaf 1, EAX */
+    FEATURE_XSAVEOPT,
+    FEATURE_XSAVEC,
+    FEATURE_XGETBV1,
+    FEATURE_XSAVES,
+    /* Leaf 0x80000001, ECX */
+    FEATURE_LAHF_LM,
+    FEATURE_SVM,
+    FEATURE_TOPOEXT,
+    /* Leaf 0x80000001, EDX */
+    FEATURE_SYSCALL,
+    FEATURE_NX,
+    FEATURE_PAGE_1GB,
+    FEATURE_RDTSCP,
+    FEATURE_LM,
+    /* Leaf 0x80000007, EDX */
+    FEATURE_INVARIANT_TSC,
+    /* Leaf 0x8000000A, EDX */
+    FEATURE_NPT,
+    FEATURE_LBR_VIRT,
+    FEATURE_SVM_LOCK,
+    FEATURE_NRIPS,
+    FEATURE_TSC_RATE,
+    FEATURE_VMCB_CLEAN,
+    FEATURE_FLUSH_BY_ASID,
+    FEATURE_DECODE_ASSISTS,
+    FEATURE_PAUSE_FILTER,
+    FEATURE_AVIC,
+    FEATURE_VGIF,
+}
+
+impl Feature {
+    /* (cached leaf, register, bit) */
+    fn location(self) -> (usize, usize, u32) {
+        match self {
+            Feature::FEATURE_FPU => (LEAF_1, EDX, 0),
+            Feature::FEATURE_VME => (LEAF_1, EDX, 1),
+            Feature::FEATURE_DE => (LEAF_1, EDX, 2),
+            Feature::FEATURE_PSE => (LEAF_1, EDX, 3),
+            Feature::FEATURE_TSC => (LEAF_1, EDX, 4),
+            Feature::FEATURE_MSR => (LEAF_1, EDX, 5),
+            Feature::FEATURE_PAE => (LEAF_1, EDX, 6),
+            Feature::FEATURE_MCE => (LEAF_1, EDX, 7),
+            Feature::FEATURE_CX8 => (LEAF_1, EDX, 8),
+            Feature::FEATURE_APIC => (LEAF_1, EDX, 9),
+            Feature::FEATURE_SEP => (LEAF_1, EDX, 11),
+            Feature::FEATURE_MTRR => (LEAF_1, EDX, 12),
+            Feature::FEATURE_PGE => (LEAF_1, EDX, 13),
+            Feature::FEATURE_MCA => (LEAF_1, EDX, 14),
+            Feature::FEATURE_CMOV => (LEAF_1, EDX, 15),
+            Feature::FEATURE_PAT => (LEAF_1, EDX, 16),
+            Feature::FEATURE_PSE36 => (LEAF_1, EDX, 17),
+            Feature::FEATURE_CLFLUSH => (LEAF_1, EDX, 19),
+            Feature::FEATURE_ACPI => (LEAF_1, EDX, 22),
+            Feature::FEATURE_MMX => (LEAF_1, EDX, 23),
+            Feature::FEATURE_FXSR => (LEAF_1, EDX, 24),
+            Feature::FEATURE_SSE => (LEAF_1, EDX, 25),
+            Feature::FEATURE_SSE2 => (LEAF_1, EDX, 26),
+            Feature::FEATURE_HTT => (LEAF_1, EDX, 28),
+
+            Feature::FEATURE_SSE3 => (LEAF_1, ECX, 0),
+            Feature::FEATURE_PCLMULQDQ => (LEAF_1, ECX, 1),
+            Feature::FEATURE_MONITOR => (LEAF_1, ECX, 3),
+            Feature::FEATURE_VMX => (LEAF_1, ECX, 5),
+            Feature::FEATURE_SMX => (LEAF_1, ECX, 6),
+            Feature::FEATURE_SSSE3 => (LEAF_1, ECX, 9),
+            Feature::FEATURE_FMA => (LEAF_1, ECX, 12),
+            Feature::FEATURE_CX16 => (LEAF_1, ECX, 13),
+            Feature::FEATURE_PCID => (LEAF_1, ECX, 17),
+            Feature::FEATURE_SSE4_1 => (LEAF_1, ECX, 19),
+            Feature::FEATURE_SSE4_2 => (LEAF_1, ECX, 20),
+            Feature::FEATURE_X2APIC => (LEAF_1, ECX, 21),
+            Feature::FEATURE_MOVBE => (LEAF_1, ECX, 22),
+            Feature::FEATURE_POPCNT => (LEAF_1, ECX, 23),
+            Feature::FEATURE_TSC_DEADLINE => (LEAF_1, ECX, 24),
+            Feature::FEATURE_AES => (LEAF_1, ECX, 25),
+            Feature::FEATURE_XSAVE => (LEAF_1, ECX, 26),
+            Feature::FEATURE_AVX => (LEAF_1, ECX, 28),
+            Feature::FEATURE_F16C => (LEAF_1, ECX, 29),
+            Feature::FEATURE_RDRAND => (LEAF_1, ECX, 30),
+            Feature::FEATURE_HYPERVISOR => (LEAF_1, ECX, 31),
+
+            Feature::FEATURE_ARAT => (LEAF_6, EAX, 2),
+
+            Feature::FEATURE_FSGSBASE => (LEAF_7, EBX, 0),
+            Feature::FEATURE_TSC_ADJUST => (LEAF_7, EBX, 1),
+            Feature::FEATURE_BMI1 => (LEAF_7, EBX, 3),
+            Feature::FEATURE_AVX2 => (LEAF_7, EBX, 5),
+            Feature::FEATURE_SMEP => (LEAF_7, EBX, 7),
+            Feature::FEATURE_BMI2 => (LEAF_7, EBX, 8),
+            Feature::FEATURE_ERMS => (LEAF_7, EBX, 9),
+            Feature::FEATURE_INVPCID => (LEAF_7, EBX, 10),
+            Feature::FEATURE_AVX512F => (LEAF_7, EBX, 16),
+            Feature::FEATURE_RDSEED => (LEAF_7, EBX, 18),
+            Feature::FEATURE_ADX => (LEAF_7, EBX, 19),
+            Feature::FEATURE_SMAP => (LEAF_7, EBX, 20),
+            Feature::FEATURE_CLFLUSHOPT => (LEAF_7, EBX, 23),
+            Feature::FEATURE_CLWB => (LEAF_7, EBX, 24),
+
+            Feature::FEATURE_UMIP => (LEAF_7, ECX, 2),
+            Feature::FEATURE_PKU => (LEAF_7, ECX, 3),
+            Feature::FEATURE_LA57 => (LEAF_7, ECX, 16),
+            Feature::FEATURE_RDPID => (LEAF_7, ECX, 22),
+
+            Feature::FEATURE_MD_CLEAR => (LEAF_7, EDX, 10),
+            Feature::FEATURE_ARCH_CAPABILITIES => (LEAF_7, EDX, 29),
+
+            Feature::FEATURE_XSAVEOPT => (LEAF_D_1, EAX, 0),
+            Feature::FEATURE_XSAVEC => (LEAF_D_1, EAX, 1),
+            Feature::FEATURE_XGETBV1 => (LEAF_D_1, EAX, 2),
+            Feature::FEATURE_XSAVES => (LEAF_D_1, EAX, 3),
+
+            Feature::FEATURE_LAHF_LM => (LEAF_80000001, ECX, 0),
+            Feature::FEATURE_SVM => (LEAF_80000001, ECX, 2),
+            Feature::FEATURE_TOPOEXT => (LEAF_80000001, ECX, 22),
+
+            Feature::FEATURE_SYSCALL => (LEAF_80000001, EDX, 11),
+            Feature::FEATURE_NX => (LEAF_80000001, EDX, 20),
+            Feature::FEATURE_PAGE_1GB => (LEAF_80000001, EDX, 26),
+            Feature::FEATURE_RDTSCP => (LEAF_80000001, EDX, 27),
+            Feature::FEATURE_LM => (LEAF_80000001, EDX, 29),
+
+            Feature::FEATURE_INVARIANT_TSC => (LEAF_80000007, EDX, 8),
+
+            Feature::FEATURE_NPT => (LEAF_8000000A, EDX, 0),
+            Feature::FEATURE_LBR_VIRT => (LEAF_8000000A, EDX, 1),
+            Feature::FEATURE_SVM_LOCK => (LEAF_8000000A, EDX, 2),
+            Feature::FEATURE_NRIPS => (LEAF_8000000A, EDX, 3),
+            Feature::FEATURE_TSC_RATE => (LEAF_8000000A, EDX, 4),
+            Feature::FEATURE_VMCB_CLEAN => (LEAF_8000000A, EDX, 5),
+            Feature::FEATURE_FLUSH_BY_ASID => (LEAF_8000000A, EDX, 6),
+            Feature::FEATURE_DECODE_ASSISTS => (LEAF_8000000A, EDX, 7),
+            Feature::FEATURE_PAUSE_FILTER => (LEAF_8000000A, EDX, 10),
+            Feature::FEATURE_AVIC => (LEAF_8000000A, EDX, 13),
+            Feature::FEATURE_VGIF => (LEAF_8000000A, EDX, 16),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CacheType {
+    CACHE_DATA,
+    CACHE_INSTRUCTION,
+    CACHE_UNIFIED,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheInfo {
+    pub level: u32,
+    pub kind: CacheType,
+    pub line_size: u32,
+    pub ways: u32,
+    pub partitions: u32,
+    pub sets: u32,
+    /// Logical CPUs sharing this cache.
+    pub shared_by: u32,
+}
+
+impl CacheInfo {
+    /// Size in bytes.
+    pub fn size(&self) -> u64 {
+        self.line_size as u64 * self.ways as u64 * self.partitions as u64 * self.sets as u64
+    }
+}
+
+/// Where the running CPU sits in the package, from its x2APIC ID.
+#[derive(Clone, Copy, Debug)]
+pub struct Topology {
+    pub x2apic_id: u32,
+    /* APIC ID bits below the core and the package parts */
+    smt_shift: u32,
+    package_shift: u32,
+}
+
+impl Topology {
+    pub fn thread_id(&self) -> u32 {
+        self.x2apic_id & ((1 << self.smt_shift) - 1)
+    }
+
+    /// Core within the package.
+    pub fn core_id(&self) -> u32 {
+        (self.x2apic_id & ((1 << self.package_shift) - 1)) >> self.smt_shift
+    }
+
+    pub fn package_id(&self) -> u32 {
+        self.x2apic_id >> self.package_shift
+    }
+
+    pub fn threads_per_core(&self) -> u32 {
+        1 << self.smt_shift
+    }
+
+    /// Upper bound of the logical CPUs in a package, APIC IDs may be sparse.
+    pub fn logical_per_package(&self) -> u32 {
+        1 << self.package_shift
+    }
+}
+
+struct Info {
+    max_leaf: u32,
+    max_extended: u32,
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    signature: u32,
+    leaves: [[u32; 4]; LEAVES],
+    /* Topology leaf in use, 0 for the legacy leaf 1/4 scheme */
+    topology_leaf: u32,
+    smt_shift: u32,
+    package_shift: u32,
+    caches: [Option<CacheInfo>; MAX_CACHES],
+}
+
+static INFO: Once<Info> = Once::new();
+
+/// Executes CPUID with `leaf` in EAX and `subleaf` in ECX.
+pub fn query(leaf: u32, subleaf: u32) -> CpuidResult {
+    unsafe { __cpuid_count(leaf, subleaf) }
+}
+
+fn registers(result: CpuidResult) -> [u32; 4] {
+    [result.eax, result.ebx, result.ecx, result.edx]
+}
+
+/* Bits needed to hold `count` distinct IDs */
+fn bits_for(count: u32) -> u32 {
+    match count {
+        0 | 1 => 0,
+        count => 32 - (count - 1).leading_zeros(),
+    }
+}
+
+impl Info {
+    fn leaf(&self, leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+        let max = if leaf >= 0x80000000 { self.max_extended } else { self.max_leaf };
+
+        if leaf <= max {
+            Some(query(leaf, subleaf))
+        } else {
+            None
+        }
+    }
+
+    fn load() -> Info {
+        let leaf0 = query(0, 0);
+        let mut info = Info {
+            max_leaf: leaf0.eax,
+            max_extended: query(0x80000000, 0).eax,
+            vendor: [0; 12],
+            brand: [0; 48],
+            signature: 0,
+            leaves: [[0; 4]; LEAVES],
+            topology_leaf: 0,
+            smt_shift: 0,
+            package_shift: 0,
+            caches: [None; MAX_CACHES],
+        };
+
+        /* No extended leaves at all reports garbage here */
+        if info.max_extended < 0x80000000 {
+            info.max_extended = 0;
+        }
+
+        info.vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        info.vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        info.vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        for &(index, leaf, subleaf) in &[(LEAF_1, 1, 0),
+                                         (LEAF_6, 6, 0),
+                                         (LEAF_7, 7, 0),
+                                         (LEAF_D_1, 0xd, 1),
+                                         (LEAF_80000001, 0x80000001, 0),
+                                         (LEAF_80000007, 0x80000007, 0),
+                                         (LEAF_8000000A, 0x8000000a, 0)] {
+            if let Some(result) = info.leaf(leaf, subleaf) {
+                info.leaves[index] = registers(result);
+            }
+        }
+
+        info.signature = info.leaves[LEAF_1][EAX];
+
+        if info.max_extended >= 0x80000004 {
+            for (index, leaf) in (0x80000002..=0x80000004).enumerate() {
+                for (register, value) in registers(query(leaf, 0)).iter().enumerate() {
+                    let offset = index * 16 + register * 4;
+                    info.brand[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+
+        info.load_topology();
+        info.load_caches();
+        info
+    }
+
+    fn load_topology(&mut self) {
+        for &leaf in &[0x1f, 0xb] {
+            /* Leaf 0x1F may exist but be left empty in favour of 0xB */
+            match self.leaf(leaf, 0) {
+                Some(result) if result.ebx != 0 => {},
+                _ => continue,
+            }
+
+            self.topology_leaf = leaf;
+
+            for subleaf in 0.. {
+                let result = query(leaf, subleaf);
+                let level_type = (result.ecx >> 8) & 0xff;
+
+                if level_type == LEVEL_TYPE_INVALID {
+                    break;
+                }
+
+                /* The shift of the last level gets to the package ID */
+                if level_type == LEVEL_TYPE_SMT {
+                    self.smt_shift = result.eax & 0x1f;
+                }
+                self.package_shift = result.eax & 0x1f;
+            }
+
+            return;
+        }
+
+        /* Legacy: leaf 1 has the logical CPUs per package, leaf 4 the cores */
+        if self.leaves[LEAF_1][EDX] & (1 << 28) == 0 {
+            return;
+        }
+
+        let logical = (self.leaves[LEAF_1][EBX] >> 16) & 0xff;
+        let cores = match self.leaf(4, 0) {
+            Some(result) if result.eax & 0x1f != CACHE_TYPE_NULL => (result.eax >> 26) + 1,
+            _ => 1,
+        };
+
+        self.package_shift = bits_for(logical);
+        self.smt_shift = bits_for(logical / cores.max(1));
+    }
+
+    fn load_caches(&mut self) {
+        /* Same layout, AMD only has it with topology extensions */
+        let leaf = match vendor_of(&self.vendor) {
+            Vendor::VENDOR_AMD if self.leaves[LEAF_80000001][ECX] & (1 << 22) != 0 => 0x8000001d,
+            Vendor::VENDOR_AMD => return,
+            _ => 4,
+        };
+
+        let mut count = 0;
+
+        for subleaf in 0..MAX_CACHES as u32 {
+            let result = match self.leaf(leaf, subleaf) {
+                Some(result) => result,
+                None => break,
+            };
+
+            let kind = match result.eax & 0x1f {
+                CACHE_TYPE_DATA => CacheType::CACHE_DATA,
+                CACHE_TYPE_INSTRUCTION => CacheType::CACHE_INSTRUCTION,
+                CACHE_TYPE_UNIFIED => CacheType::CACHE_UNIFIED,
+                _ => break,
+            };
+
+            self.caches[count] = Some(CacheInfo {
+                level: (result.eax >> 5) & 0x7,
+                kind,
+                line_size: (result.ebx & 0xfff) + 1,
+                partitions: ((result.ebx >> 12) & 0x3ff) + 1,
+                ways: ((result.ebx >> 22) & 0x3ff) + 1,
+                sets: result.ecx + 1,
+                shared_by: ((result.eax >> 14) & 0xfff) + 1,
+            });
+            count += 1;
+        }
+    }
+}
+
+fn info() -> &'static Info {
+    INFO.call_once(Info::load)
+}
+
+fn vendor_of(vendor: &[u8; 12]) -> Vendor {
+    match vendor {
+        b"GenuineIntel" => Vendor::VENDOR_INTEL,
+        b"AuthenticAMD" => Vendor::VENDOR_AMD,
+        _ => Vendor::VENDOR_OTHER,
+    }
+}
+
+/// Whether the CPU supports `feature`.
+pub fn has(feature: Feature) -> bool {
+    let (leaf, register, bit) = feature.location();
+
+    info().leaves[leaf][register] & (1 << bit) != 0
+}
+
+pub fn max_leaf() -> u32 {
+    info().max_leaf
+}
+
+pub fn max_extended_leaf() -> u32 {
+    info().max_extended
+}
+
+pub fn vendor() -> Vendor {
+    vendor_of(&info().vendor)
+}
+
+/// The 12 character vendor string, e.g. `GenuineIntel`.
+pub fn vendor_string() -> &'static str {
+    core::str::from_utf8(&info().vendor).unwrap_or("")
+}
+
+/// The processor brand string, empty if the CPU has none.
+pub fn brand() -> &'static str {
+    let brand = &info().brand;
+    let len = brand.iter().position(|&byte| byte == 0).unwrap_or(brand.len());
+
+    core::str::from_utf8(&brand[..len]).unwrap_or("").trim()
+}
+
+/// Display family, the base family plus the extended one for family 0xf.
+pub fn family() -> u32 {
+    let signature = info().signature;
+    let family = (signature >> 8) & 0xf;
+
+    if family == 0xf {
+        family + ((signature >> 20) & 0xff)
+    } else {
+        family
+    }
+}
+
+/// Display model, including the extended model for families 6 and 0xf.
+pub fn model() -> u32 {
+    let signature = info().signature;
+    let family = (signature >> 8) & 0xf;
+    let model = (signature >> 4) & 0xf;
+
+    if family == 0x6 || family == 0xf {
+        model | (((signature >> 16) & 0xf) << 4)
+    } else {
+        model
+    }
+}
+
+pub fn stepping() -> u32 {
+    info().signature & 0xf
+}
+
+/// Topology of the running CPU.
+pub fn topology() -> Topology {
+    let info = info();
+    let x2apic_id = match info.topology_leaf {
+        0 => query(1, 0).ebx >> 24,
+        leaf => query(leaf, 0).edx,
+    };
+
+    Topology {
+        x2apic_id,
+        smt_shift: info.smt_shift,
+        package_shift: info.package_shift,
+    }
+}
+
+/// The caches of the CPU, innermost first.
+pub fn caches() -> impl Iterator<Item = &'static CacheInfo> {
+    info().caches.iter().flatten()
+}
+
+/// (physical, linear) address widths in bits.
+pub fn address_widths() -> (u32, u32) {
+    match info().leaf(0x80000008, 0) {
+        Some(result) => (result.eax & 0xff, (result.eax >> 8) & 0xff),
+        None if has(Feature::FEATURE_PAE) => (36, 48),
+        None => (32, 48),
+    }
+}
+
+/// XSAVE state components the CPU supports, as an XCR0 mask.
+pub fn xsave_components() -> u64 {
+    match info().leaf(0xd, 0) {
+        Some(result) => ((result.edx as u64) << 32) | result.eax as u64,
+        None => 0,
+    }
+}
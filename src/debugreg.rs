@@ -0,0 +1,358 @@
+//! Hardware breakpoints and watchpoints through DR0-DR3 and DR7, and the #DB
+//! handler decoding DR6.
+//!
+//! Debug registers belong to the CPU, not to a task: breakpoints set here
+//! only fire on the running CPU and stay across task switches.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::rflags::{self, RFlags};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::cpu::{CPU, MAX_CPUS};
+
+pub const BREAKPOINTS: usize = 4;
+
+/* DR7 fields, per breakpoint `i`: local enable at 2 * i, R/W at 16 + 4 * i
+ * and LEN at 18 + 4 * i */
+const DR7_LOCAL_EXACT: u64  = 1 << 8;
+const DR7_RESERVED_1: u64   = 1 << 10;
+
+/* DR6 fields */
+const DR6_TRIGGERED: u64        = 0xf;
+const DR6_REGISTER_ACCESS: u64  = 1 << 13;
+const DR6_SINGLE_STEP: u64      = 1 << 14;
+const DR6_TASK_SWITCH: u64      = 1 << 15;
+/* What DR6 reads with nothing reported, RTM at bit 16 is active low */
+const DR6_CLEAR: u64            = 0xffff0ff0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BreakpointKind {
+    /// Instruction fetch, fires before the instruction runs. Length has to
+    /// be 1.
+    BREAKPOINT_EXECUTE = 0,
+    /// Data write, fires after it.
+    BREAKPOINT_WRITE = 1,
+    /// Data read or write, fires after it. There is no read-only condition.
+    BREAKPOINT_READ_WRITE = 3,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BreakpointLength {
+    BREAKPOINT_LENGTH_1 = 0,
+    BREAKPOINT_LENGTH_2 = 1,
+    BREAKPOINT_LENGTH_8 = 2,
+    BREAKPOINT_LENGTH_4 = 3,
+}
+
+impl BreakpointLength {
+    pub fn bytes(self) -> u64 {
+        match self {
+            BreakpointLength::BREAKPOINT_LENGTH_1 => 1,
+            BreakpointLength::BREAKPOINT_LENGTH_2 => 2,
+            BreakpointLength::BREAKPOINT_LENGTH_4 => 4,
+            BreakpointLength::BREAKPOINT_LENGTH_8 => 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DebugError {
+    /// Not one of DR0-DR3.
+    DEBUG_ERROR_INVALID_INDEX,
+    /// The address is not aligned to the length.
+    DEBUG_ERROR_UNALIGNED,
+    /// Execute breakpoints with a length other than 1.
+    DEBUG_ERROR_INVALID_LENGTH,
+    /// All four breakpoints are in use.
+    DEBUG_ERROR_NO_FREE_BREAKPOINT,
+}
+
+/// DR7. Breakpoint indices past DR3 read as disabled and leave it
+/// unchanged.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DebugControl(pub u64);
+
+impl DebugControl {
+    /// No breakpoints enabled.
+    pub fn new() -> Self {
+        DebugControl(DR7_RESERVED_1)
+    }
+
+    pub fn read() -> Self {
+        DebugControl(read_dr7())
+    }
+
+    pub fn write(self) {
+        write_dr7(self.0)
+    }
+
+    pub fn enabled(self, index: usize) -> bool {
+        index < BREAKPOINTS && self.0 & (1 << (index * 2)) != 0
+    }
+
+    /// Enables breakpoint `index` for `kind` and `length`, its address goes
+    /// in DR`index`.
+    pub fn set(self, index: usize, kind: BreakpointKind, length: BreakpointLength) -> Self {
+        if index >= BREAKPOINTS {
+            return self;
+        }
+
+        let shift = 16 + index * 4;
+        let fields = (kind as u64) | (length as u64) << 2;
+
+        DebugControl((self.0 & !(0xf << shift)) | fields << shift | 1 << (index * 2) | DR7_LOCAL_EXACT)
+    }
+
+    pub fn clear(self, index: usize) -> Self {
+        if index >= BREAKPOINTS {
+            return self;
+        }
+
+        DebugControl(self.0 & !(0xf << (16 + index * 4)) & !(3 << (index * 2)))
+    }
+
+    pub fn kind(self, index: usize) -> Option<BreakpointKind> {
+        if index >= BREAKPOINTS {
+            return None;
+        }
+
+        match (self.0 >> (16 + index * 4)) & 3 {
+            0 => Some(BreakpointKind::BREAKPOINT_EXECUTE),
+            1 => Some(BreakpointKind::BREAKPOINT_WRITE),
+            3 => Some(BreakpointKind::BREAKPOINT_READ_WRITE),
+            _ => None,
+        }
+    }
+
+    pub fn length(self, index: usize) -> Option<BreakpointLength> {
+        if index >= BREAKPOINTS {
+            return None;
+        }
+
+        match (self.0 >> (18 + index * 4)) & 3 {
+            0 => Some(BreakpointLength::BREAKPOINT_LENGTH_1),
+            1 => Some(BreakpointLength::BREAKPOINT_LENGTH_2),
+            2 => Some(BreakpointLength::BREAKPOINT_LENGTH_8),
+            _ => Some(BreakpointLength::BREAKPOINT_LENGTH_4),
+        }
+    }
+}
+
+/// DR6, what caused a #DB.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DebugStatus(pub u64);
+
+impl DebugStatus {
+    pub fn read() -> Self {
+        DebugStatus(read_dr6())
+    }
+
+    /// Whether breakpoint `index` matched. The CPU may report matches of
+    /// disabled breakpoints too, check DR7.
+    pub fn triggered(self, index: usize) -> bool {
+        index < BREAKPOINTS && self.0 & (1 << index) & DR6_TRIGGERED != 0
+    }
+
+    /// Trap after an instruction executed with RFLAGS.TF.
+    pub fn single_step(self) -> bool {
+        self.0 & DR6_SINGLE_STEP != 0
+    }
+
+    /// A MOV to or from a debug register with DR7.GD set.
+    pub fn register_access(self) -> bool {
+        self.0 & DR6_REGISTER_ACCESS != 0
+    }
+
+    pub fn task_switch(self) -> bool {
+        self.0 & DR6_TASK_SWITCH != 0
+    }
+}
+
+/// What the #DB handler passes to the callback.
+#[derive(Clone, Copy, Debug)]
+pub struct DebugEvent {
+    /// DR6 with the breakpoints not enabled in DR7 masked out.
+    pub status: DebugStatus,
+    /// The breakpoint that fired, the lowest if several did.
+    pub breakpoint: Option<usize>,
+    /// Its address, from DR0-DR3.
+    pub address: Option<u64>,
+    /// The instruction that hit an execute breakpoint, otherwise the one
+    /// after the access or step.
+    pub rip: u64,
+}
+
+/* The callback as a plain address, 0 for none. #DB can hit with any lock
+ * held, so the handler must not take one to find it */
+static HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/* CPUs single-stepping, TF stays set in the frames #DB returns to */
+const NOT_STEPPING: AtomicBool = AtomicBool::new(false);
+static STEPPING: [AtomicBool; MAX_CPUS] = [NOT_STEPPING; MAX_CPUS];
+
+/* MOV to and from debug registers only takes the register number in the
+ * instruction */
+fn read_dr7() -> u64 {
+    let value: u64;
+    unsafe { llvm_asm!("mov %dr7, $0" : "=r" (value) ::: "volatile") };
+    value
+}
+
+fn write_dr7(value: u64) {
+    unsafe { llvm_asm!("mov $0, %dr7" :: "r" (value) :: "volatile") };
+}
+
+fn read_dr6() -> u64 {
+    let value: u64;
+    unsafe { llvm_asm!("mov %dr6, $0" : "=r" (value) ::: "volatile") };
+    value
+}
+
+fn write_dr6(value: u64) {
+    unsafe { llvm_asm!("mov $0, %dr6" :: "r" (value) :: "volatile") };
+}
+
+/// The address in DR`index`, 0 to 3.
+pub fn address(index: usize) -> Option<u64> {
+    let value: u64;
+
+    unsafe {
+        match index {
+            0 => llvm_asm!("mov %dr0, $0" : "=r" (value) ::: "volatile"),
+            1 => llvm_asm!("mov %dr1, $0" : "=r" (value) ::: "volatile"),
+            2 => llvm_asm!("mov %dr2, $0" : "=r" (value) ::: "volatile"),
+            3 => llvm_asm!("mov %dr3, $0" : "=r" (value) ::: "volatile"),
+            _ => return None,
+        }
+    }
+
+    Some(value)
+}
+
+fn set_address(index: usize, value: u64) {
+    unsafe {
+        match index {
+            0 => llvm_asm!("mov $0, %dr0" :: "r" (value) :: "volatile"),
+            1 => llvm_asm!("mov $0, %dr1" :: "r" (value) :: "volatile"),
+            2 => llvm_asm!("mov $0, %dr2" :: "r" (value) :: "volatile"),
+            3 => llvm_asm!("mov $0, %dr3" :: "r" (value) :: "volatile"),
+            _ => panic!("no debug register DR{}", index),
+        }
+    }
+}
+
+/// Programs breakpoint `index` on the running CPU, replacing whatever it
+/// was used for.
+pub fn set_breakpoint(index: usize, address: u64, kind: BreakpointKind,
+                      length: BreakpointLength) -> Result<(), DebugError> {
+    if index >= BREAKPOINTS {
+        return Err(DebugError::DEBUG_ERROR_INVALID_INDEX);
+    }
+
+    if kind == BreakpointKind::BREAKPOINT_EXECUTE && length != BreakpointLength::BREAKPOINT_LENGTH_1 {
+        return Err(DebugError::DEBUG_ERROR_INVALID_LENGTH);
+    }
+
+    if address & (length.bytes() - 1) != 0 {
+        return Err(DebugError::DEBUG_ERROR_UNALIGNED);
+    }
+
+    /* Disabled while the address changes so a stale match cannot fire */
+    let control = DebugControl::read().clear(index);
+    control.write();
+    set_address(index, address);
+    control.set(index, kind, length).write();
+    Ok(())
+}
+
+/// Programs the first unused breakpoint of the running CPU and returns its
+/// index.
+pub fn add_breakpoint(address: u64, kind: BreakpointKind,
+                      length: BreakpointLength) -> Result<usize, DebugError> {
+    let control = DebugControl::read();
+    let index = (0..BREAKPOINTS)
+        .find(|&index| !control.enabled(index))
+        .ok_or(DebugError::DEBUG_ERROR_NO_FREE_BREAKPOINT)?;
+
+    set_breakpoint(index, address, kind, length)?;
+    Ok(index)
+}
+
+pub fn clear_breakpoint(index: usize) {
+    if index < BREAKPOINTS {
+        DebugControl::read().clear(index).write();
+    }
+}
+
+/// Disables all breakpoints of the running CPU.
+pub fn clear_all() {
+    DebugControl::new().write();
+}
+
+/// Called for every #DB, breakpoint or single step. Without one they are
+/// only acknowledged: logging could deadlock on a lock the interrupted code
+/// holds. The same goes for the handler, it must not take locks either.
+pub fn set_handler(handler: Option<fn(DebugEvent)>) {
+    HANDLER.store(handler.map_or(0, |handler| handler as usize), Ordering::SeqCst);
+}
+
+/// Single-steps the running CPU's current code: sets RFLAGS.TF, which traps
+/// after the next instruction, and keeps it set until `stop_single_step()`.
+pub fn single_step() {
+    STEPPING[CPU::id()].store(true, Ordering::Relaxed);
+    rflags::write(rflags::read() | RFlags::TRAP_FLAG);
+}
+
+/// Stops single-stepping, the #DB after the next instruction is the last.
+pub fn stop_single_step() {
+    STEPPING[CPU::id()].store(false, Ordering::Relaxed);
+    rflags::write(rflags::read() - RFlags::TRAP_FLAG);
+}
+
+/// #DB. Reports the event and resumes: past execute breakpoints through
+/// RFLAGS.RF, and with TF cleared once single-stepping stopped.
+pub extern "x86-interrupt" fn debug_handler(
+    stack_frame: &mut InterruptStackFrame)
+{
+    let control = DebugControl::read();
+    let raw = DebugStatus::read();
+
+    /* DR6 is sticky */
+    write_dr6(DR6_CLEAR);
+
+    let enabled = (0..BREAKPOINTS)
+        .filter(|&index| control.enabled(index))
+        .fold(0, |mask, index| mask | 1 << index);
+    let status = DebugStatus(raw.0 & !(DR6_TRIGGERED & !enabled));
+    let breakpoint = (0..BREAKPOINTS).find(|&index| status.triggered(index));
+
+    let event = DebugEvent {
+        status,
+        breakpoint,
+        address: breakpoint.and_then(address),
+        rip: stack_frame.instruction_pointer.as_u64(),
+    };
+
+    let frame = unsafe { stack_frame.as_mut() };
+    let mut flags = frame.cpu_flags;
+
+    /* Instruction breakpoints are faults, they would fire again */
+    if let Some(index) = breakpoint {
+        if control.kind(index) == Some(BreakpointKind::BREAKPOINT_EXECUTE) {
+            flags |= RFlags::RESUME_FLAG.bits();
+        }
+    }
+
+    if !STEPPING[CPU::id()].load(Ordering::Relaxed) {
+        flags &= !RFlags::TRAP_FLAG.bits();
+    }
+    frame.cpu_flags = flags;
+
+    let handler = HANDLER.load(Ordering::SeqCst);
+
+    if handler != 0 {
+        /* Only ever stored from a fn(DebugEvent) */
+        let handler: fn(DebugEvent) = unsafe { core::mem::transmute(handler) };
+        handler(event);
+    }
+}
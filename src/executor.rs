@@ -0,0 +1,339 @@
+//! A small executor for `async` code.
+//!
+//! Tasks are borrowed, pinned futures, so nothing gets allocated. Each CPU
+//! runs at most one executor at a time; a task is polled again once its waker
+//! fired, and the CPU sits in `hlt` while nothing is ready. Wakers may be used
+//! from interrupt handlers and from other CPUs, which get an IPI so they
+//! leave `hlt`.
+
+use core::future::Future;
+use core::marker::PhantomPinned;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+use crate::apic::{APIC, APICDeliveryMode, APICDestination, APICDestinationShorthand};
+use crate::cpu::{CPU, MAX_CPUS};
+use crate::sync::IrqSpinLock;
+use crate::time::{self, TimerId};
+
+/// Vector of the IPI that wakes an idle executor on another CPU.
+pub const WAKE_VECTOR: u8 = 0xf0;
+
+/// Number of tasks an `Executor` can hold, one bit of the ready mask each.
+pub const MAX_TASKS: usize = 64;
+
+/* Waker data is the CPU number with the task index in the low bits */
+const INDEX_BITS: usize = 6;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+const NOTHING_READY: AtomicU64 = AtomicU64::new(0);
+const IDLE: AtomicBool = AtomicBool::new(false);
+
+static READY: [AtomicU64; MAX_CPUS] = [NOTHING_READY; MAX_CPUS];
+static ACTIVE: [AtomicBool; MAX_CPUS] = [IDLE; MAX_CPUS];
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake_waker, wake_waker, drop_waker);
+
+fn this_cpu() -> usize {
+    CPU::id()
+}
+
+fn waker(cpu: usize, index: usize) -> Waker {
+    let data = (cpu << INDEX_BITS) | index;
+    unsafe { Waker::from_raw(RawWaker::new(data as *const (), &VTABLE)) }
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake_waker(data: *const ()) {
+    let cpu = data as usize >> INDEX_BITS;
+    let index = data as usize & INDEX_MASK;
+
+    READY[cpu].fetch_or(1 << index, Ordering::Release);
+
+    if cpu != this_cpu() {
+        if let Some(apic_id) = CPU::apic_id_of(cpu) {
+            let _ = APIC::send_ipi(APICDestination::APIC_DESTINATION_PHYSICAL(apic_id), WAKE_VECTOR,
+                                   APICDeliveryMode::APIC_DELIVERY_FIXED,
+                                   APICDestinationShorthand::APIC_DESTINATION_SHORTHAND_NONE);
+        }
+    }
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
+
+/* Claims the ready mask of this CPU for the lifetime of an executor */
+struct Active(usize);
+
+impl Active {
+    fn claim() -> Active {
+        let cpu = this_cpu();
+
+        if ACTIVE[cpu].swap(true, Ordering::Acquire) {
+            panic!("executor already running on CPU {}", cpu);
+        }
+
+        READY[cpu].store(0, Ordering::Relaxed);
+        Active(cpu)
+    }
+
+    /* Returns the ready tasks, halting until there are some */
+    fn wait(&self) -> u64 {
+        loop {
+            let enabled = CPU::irq_enabled();
+
+            /* A wake between the check and hlt must not be missed */
+            interrupts::disable();
+            let ready = READY[self.0].swap(0, Ordering::Acquire);
+
+            if ready != 0 {
+                if enabled {
+                    interrupts::enable();
+                }
+                return ready;
+            }
+
+            if enabled {
+                interrupts::enable_interrupts_and_hlt();
+            } else {
+                /* Nothing could wake us from hlt, wakers on other CPUs can still */
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE[self.0].store(false, Ordering::Release);
+    }
+}
+
+/// Runs `future` to completion on the current CPU.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = future;
+    /* Shadowed, so it can not be moved again */
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+
+    let active = Active::claim();
+    let waker = waker(active.0, 0);
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+
+        active.wait();
+    }
+}
+
+/// Runs up to `MAX_TASKS` futures concurrently until all of them finished.
+///
+/// ```ignore
+/// let mut first = first_test();
+/// let mut second = second_test();
+///
+/// let mut executor = Executor::new();
+/// executor.spawn(unsafe { Pin::new_unchecked(&mut first) });
+/// executor.spawn(unsafe { Pin::new_unchecked(&mut second) });
+/// executor.run();
+/// ```
+pub struct Executor<'a> {
+    tasks: [Option<Pin<&'a mut dyn Future<Output = ()>>>; MAX_TASKS],
+}
+
+impl<'a> Executor<'a> {
+    const NO_TASK: Option<Pin<&'a mut dyn Future<Output = ()>>> = None;
+
+    pub fn new() -> Self {
+        Executor { tasks: [Self::NO_TASK; MAX_TASKS] }
+    }
+
+    /// Adds a task, returns its index or `None` if the executor is full.
+    pub fn spawn(&mut self, task: Pin<&'a mut dyn Future<Output = ()>>) -> Option<usize> {
+        let index = self.tasks.iter().position(Option::is_none)?;
+
+        self.tasks[index] = Some(task);
+        Some(index)
+    }
+
+    /// Polls the tasks until all of them completed.
+    pub fn run(&mut self) {
+        let active = Active::claim();
+        /* Everything gets polled once to start with */
+        let mut ready = !0u64;
+
+        while self.tasks.iter().any(Option::is_some) {
+            for index in 0..MAX_TASKS {
+                if ready & (1 << index) == 0 {
+                    continue;
+                }
+
+                if let Some(task) = self.tasks[index].as_mut() {
+                    let waker = waker(active.0, index);
+                    let mut context = Context::from_waker(&waker);
+
+                    if task.as_mut().poll(&mut context).is_ready() {
+                        self.tasks[index] = None;
+                    }
+                }
+            }
+
+            if self.tasks.iter().any(Option::is_some) {
+                ready = active.wait();
+            }
+        }
+    }
+}
+
+/// Event raised by an interrupt handler and awaited by a task.
+///
+/// Signals are counted, every `wait()` consumes one.
+///
+/// ```ignore
+/// static RX: InterruptEvent = InterruptEvent::new();
+///
+/// extern "x86-interrupt" fn rx_handler(_frame: &mut InterruptStackFrame) {
+///     RX.signal();
+///     APIC::eoi(0);
+/// }
+///
+/// idt::set_handler(VECTOR, rx_handler);
+/// RX.wait().await;
+/// ```
+pub struct InterruptEvent {
+    pending: AtomicUsize,
+    waker: IrqSpinLock<Option<Waker>>,
+}
+
+impl InterruptEvent {
+    pub const fn new() -> Self {
+        InterruptEvent {
+            pending: AtomicUsize::new(0),
+            waker: IrqSpinLock::new(None),
+        }
+    }
+
+    /// Safe to call from interrupt handlers.
+    pub fn signal(&self) {
+        self.pending.fetch_add(1, Ordering::Release);
+
+        let waker = self.waker.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn try_take(&self) -> bool {
+        self.pending.fetch_update(Ordering::Acquire, Ordering::Relaxed, |pending| {
+            if pending > 0 { Some(pending - 1) } else { None }
+        }).is_ok()
+    }
+
+    /// Completes once the event was signalled.
+    pub fn wait(&self) -> EventWait<'_> {
+        EventWait { event: self }
+    }
+}
+
+pub struct EventWait<'a> {
+    event: &'a InterruptEvent,
+}
+
+impl Future for EventWait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.event.try_take() {
+            return Poll::Ready(());
+        }
+
+        *self.event.waker.lock() = Some(context.waker().clone());
+
+        /* A signal may have come in before the waker was stored */
+        if self.event.try_take() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Completes after `duration`, driven by the timer interrupt.
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::until(time::now() + duration)
+}
+
+pub struct Sleep {
+    deadline: Duration,
+    timer: Option<TimerId>,
+    fired: AtomicBool,
+    waker: IrqSpinLock<Option<Waker>>,
+    /* The timer holds our address */
+    _pinned: PhantomPinned,
+}
+
+impl Sleep {
+    /// Completes at `deadline`, as returned by `time::now()`.
+    pub fn until(deadline: Duration) -> Self {
+        Sleep {
+            deadline,
+            timer: None,
+            fired: AtomicBool::new(false),
+            waker: IrqSpinLock::new(None),
+            _pinned: PhantomPinned,
+        }
+    }
+}
+
+fn sleep_expired(data: usize) {
+    let sleep = unsafe { &*(data as *const Sleep) };
+
+    sleep.fired.store(true, Ordering::Release);
+
+    let waker = sleep.waker.lock().take();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.fired.load(Ordering::Acquire) || time::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        *self.waker.lock() = Some(context.waker().clone());
+
+        if self.timer.is_none() {
+            let data = &*self as *const Sleep as usize;
+            let this = unsafe { self.get_unchecked_mut() };
+
+            match time::add_timer_at(this.deadline, sleep_expired, data) {
+                Some(timer) => this.timer = Some(timer),
+                /* No timer slot left, keep polling */
+                None => context.waker().wake_by_ref(),
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        /* Also waits for a `sleep_expired` running on another CPU, it
+         * holds our address */
+        if let Some(timer) = self.timer.take() {
+            time::cancel_timer(timer);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+#[allow(dead_code)]
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use log::error;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::cpuid::{self, Feature};
+use crate::vm::VM;
+
+/* Legacy region and XSAVE header */
+const LEGACY_SIZE: usize    = 512;
+const HEADER_SIZE: usize    = 64;
+
+/* XCR0 components */
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX512: u64 = 0x7 << 5;
+const XCR0_AMX: u64 = 0x3 << 17;
+
+/* FXSAVE image offsets and reset values */
+const FXSAVE_FCW: usize     = 0;
+const FXSAVE_MXCSR: usize   = 24;
+const FCW_DEFAULT: u16      = 0x037f;
+const MXCSR_DEFAULT: u32    = 0x1f80;
+
+static XSAVE: AtomicBool = AtomicBool::new(false);
+static XSAVEOPT: AtomicBool = AtomicBool::new(false);
+static XCR0: AtomicU64 = AtomicU64::new(0);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(LEGACY_SIZE);
+
+/// Register state of the x87 FPU, SSE and, with XSAVE, the extended
+/// components enabled in XCR0.
+///
+/// The area is sized by `area_size()` and comes from `page_alloc`, call
+/// `alloc` once `init()` ran.
+pub struct FpuState {
+    /* Virtual address of the area, page aligned, 0 until allocated */
+    area: u64,
+    size: usize,
+}
+
+impl FpuState {
+    /// A state without an area.
+    pub const fn new() -> Self {
+        FpuState { area: 0, size: 0 }
+    }
+
+    /// Allocates an area of `area_size()` bytes, unless the current one is
+    /// big enough. Areas are never freed. Fails without memory.
+    pub fn alloc(&mut self) -> Option<()> {
+        let size = area_size();
+
+        if self.size < size {
+            let pages = (size + 4095) / 4096;
+
+            self.area = VM::phys_to_virt(VM::alloc_pages(pages)?);
+            self.size = pages * 4096;
+        }
+
+        Some(())
+    }
+
+    fn area(&self) -> *mut u8 {
+        assert_ne!(self.area, 0, "FPU state without an area");
+        self.area as *mut u8
+    }
+
+    /// Sets the state a CPU has after FNINIT, with all exceptions masked.
+    pub fn reset(&mut self) {
+        let area = unsafe { core::slice::from_raw_parts_mut(self.area(), LEGACY_SIZE + HEADER_SIZE) };
+
+        area.iter_mut().for_each(|byte| *byte = 0);
+        area[FXSAVE_FCW..FXSAVE_FCW + 2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        area[FXSAVE_MXCSR..FXSAVE_MXCSR + 4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        /* A zero XSTATE_BV makes XRSTOR put the other components in their
+         * init state */
+    }
+
+    /// Saves the state of the running CPU.
+    pub fn save(&mut self) {
+        let area = self.area();
+
+        if !XSAVE.load(Ordering::Relaxed) {
+            unsafe { llvm_asm!("fxsave64 ($0)" :: "r" (area) : "memory" : "volatile") }
+        } else if XSAVEOPT.load(Ordering::Relaxed) {
+            unsafe { llvm_asm!("xsaveopt64 ($0)" :: "r" (area), "{eax}" (u32::MAX), "{edx}" (u32::MAX) : "memory" : "volatile") }
+        } else {
+            unsafe { llvm_asm!("xsave64 ($0)" :: "r" (area), "{eax}" (u32::MAX), "{edx}" (u32::MAX) : "memory" : "volatile") }
+        }
+    }
+
+    /// Loads the state into the running CPU.
+    pub fn restore(&self) {
+        let area = self.area();
+
+        if XSAVE.load(Ordering::Relaxed) {
+            unsafe { llvm_asm!("xrstor64 ($0)" :: "r" (area), "{eax}" (u32::MAX), "{edx}" (u32::MAX) : "memory" : "volatile") }
+        } else {
+            unsafe { llvm_asm!("fxrstor64 ($0)" :: "r" (area) : "memory" : "volatile") }
+        }
+    }
+}
+
+fn xsetbv(register: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+
+    unsafe {
+        llvm_asm!("xsetbv" :: "{ecx}" (register), "{eax}" (low), "{edx}" (high) :: "volatile");
+    }
+}
+
+/* Supported user state components, the area is sized for all of them */
+fn xcr0_components() -> u64 {
+    let mut xcr0 = cpuid::xsave_components() | XCR0_X87 | XCR0_SSE;
+
+    /* XSETBV faults unless these are all set or all clear */
+    for &group in &[XCR0_AVX512, XCR0_AMX] {
+        if xcr0 & group != group {
+            xcr0 &= !group;
+        }
+    }
+
+    xcr0
+}
+
+/// Enables the FPU, SSE and, when the CPU has XSAVE, the extended state
+/// components. Has to run on every CPU before it touches SSE registers.
+/// Fails if the CPU has no FXSAVE.
+pub fn init() -> Option<()> {
+    if !cpuid::has(Feature::FEATURE_FXSR) {
+        return None;
+    }
+
+    unsafe {
+        let mut cr0 = Cr0::read();
+        cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        Cr0::write(cr0);
+
+        let mut cr4 = Cr4::read();
+        cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        if cpuid::has(Feature::FEATURE_XSAVE) {
+            cr4.insert(Cr4Flags::OSXSAVE);
+        }
+        Cr4::write(cr4);
+
+        llvm_asm!("fninit" :::: "volatile");
+    }
+
+    if cpuid::has(Feature::FEATURE_XSAVE) {
+        let xcr0 = xcr0_components();
+
+        xsetbv(0, xcr0);
+
+        /* EBX is the size needed for what XCR0 enables right now */
+        let size = cpuid::query(0xd, 0).ebx as usize;
+
+        XCR0.store(xcr0, Ordering::Relaxed);
+        AREA_SIZE.store(size, Ordering::Relaxed);
+        XSAVEOPT.store(cpuid::has(Feature::FEATURE_XSAVEOPT), Ordering::Relaxed);
+        XSAVE.store(true, Ordering::Relaxed);
+    }
+
+    Some(())
+}
+
+/// Whether state is saved with XSAVE rather than FXSAVE.
+pub fn xsave_enabled() -> bool {
+    XSAVE.load(Ordering::Relaxed)
+}
+
+/// The components enabled in XCR0, 0 without XSAVE.
+pub fn xcr0() -> u64 {
+    XCR0.load(Ordering::Relaxed)
+}
+
+/// Bytes of the `FpuState` area used by `save` and `restore`, from CPUID
+/// leaf 0xd for the components in XCR0.
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::Relaxed)
+}
+
+/// #NM. libos keeps CR0.TS clear, if someone set it the FPU is given back
+/// and the instruction restarted. Otherwise CR0.EM is still set.
+pub extern "x86-interrupt" fn device_not_available_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    if Cr0::read().contains(Cr0Flags::TASK_SWITCHED) {
+        unsafe { llvm_asm!("clts" :::: "volatile") }
+        return;
+    }
+
+    error!("device not available, FPU disabled in CR0, fpu::init() not done?");
+    loop {}
+}
@@ -0,0 +1,276 @@
+#[allow(dead_code)]
+
+use crate::console::Console;
+use crate::font::{self, FONT_HEIGHT, FONT_WIDTH};
+use crate::sync::IrqSpinLock;
+use crate::vm::VM;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    /// Red in the lowest byte.
+    PIXEL_FORMAT_RGB,
+    /// Blue in the lowest byte, what VBE and most GOP modes use.
+    PIXEL_FORMAT_BGR,
+}
+
+/// Where the bootloader left the framebuffer and how it is laid out.
+///
+/// bootloader 0.9 does not pass graphics modes on and its `BootInfo` carries
+/// no framebuffer information at all, so this is filled in by the caller from
+/// whatever set up the mode (e.g. a VBE/GOP aware loader, or the BAR of
+/// QEMU's `-device bochs-display`).
+#[derive(Clone, Copy, Debug)]
+pub struct FramebufferInfo {
+    /// Physical address of the first pixel.
+    pub address: u64,
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from one line to the next.
+    pub stride: usize,
+    /// 3 or 4.
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::new(0, 0, 0);
+    pub const WHITE: Color = Color::new(0xff, 0xff, 0xff);
+    pub const LIGHT_GREY: Color = Color::new(0xaa, 0xaa, 0xaa);
+
+    pub const fn new(red: u8, green: u8, blue: u8) -> Self {
+        Color { red, green, blue }
+    }
+
+    /// From 0xRRGGBB.
+    pub const fn from_rgb(rgb: u32) -> Self {
+        Color::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+    }
+}
+
+struct State {
+    info: FramebufferInfo,
+    /* Virtual address of the mapping */
+    base: u64,
+    /* Text console */
+    row: usize,
+    column: usize,
+    foreground: Color,
+    background: Color,
+}
+
+static STATE: IrqSpinLock<Option<State>> = IrqSpinLock::new(None);
+
+impl State {
+    fn columns(&self) -> usize {
+        self.info.width / FONT_WIDTH
+    }
+
+    fn rows(&self) -> usize {
+        self.info.height / FONT_HEIGHT
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> *mut u8 {
+        (self.base as usize + y * self.info.stride + x * self.info.bytes_per_pixel) as *mut u8
+    }
+
+    fn encode(&self, color: Color) -> [u8; 4] {
+        match self.info.format {
+            PixelFormat::PIXEL_FORMAT_RGB => [color.red, color.green, color.blue, 0],
+            PixelFormat::PIXEL_FORMAT_BGR => [color.blue, color.green, color.red, 0],
+        }
+    }
+
+    fn put(&self, x: usize, y: usize, encoded: &[u8; 4]) {
+        let pixel = self.pixel(x, y);
+
+        for (index, &byte) in encoded[..self.info.bytes_per_pixel].iter().enumerate() {
+            unsafe { pixel.add(index).write_volatile(byte) }
+        }
+    }
+
+    /* Clips to the screen */
+    fn fill_rect(&self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let encoded = self.encode(color);
+        let right = (x + width).min(self.info.width);
+        let bottom = (y + height).min(self.info.height);
+
+        for y in y..bottom {
+            for x in x..right {
+                self.put(x, y, &encoded);
+            }
+        }
+    }
+
+    fn draw_char(&self, x: usize, y: usize, byte: u8, foreground: Color, background: Color) {
+        let foreground = self.encode(foreground);
+        let background = self.encode(background);
+
+        for (row, bits) in font::glyph(byte).iter().enumerate() {
+            if y + row >= self.info.height {
+                break;
+            }
+
+            for column in 0..FONT_WIDTH.min(self.info.width.saturating_sub(x)) {
+                let set = (bits & (0x80 >> column)) != 0;
+                self.put(x + column, y + row, if set { &foreground } else { &background });
+            }
+        }
+    }
+
+    /* Moves everything up by `lines` pixel lines */
+    fn scroll(&self, lines: usize, background: Color) {
+        let lines = lines.min(self.info.height);
+        let moved = (self.info.height - lines) * self.info.stride;
+
+        unsafe {
+            core::ptr::copy(self.pixel(0, lines), self.pixel(0, 0), moved);
+        }
+
+        self.fill_rect(0, self.info.height - lines, self.info.width, lines, background);
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        self.row += 1;
+
+        if self.row == self.rows() {
+            self.scroll(FONT_HEIGHT, self.background);
+            self.row -= 1;
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            byte => {
+                if self.column == self.columns() {
+                    self.newline();
+                }
+
+                self.draw_char(self.column * FONT_WIDTH, self.row * FONT_HEIGHT,
+                               byte, self.foreground, self.background);
+                self.column += 1;
+            },
+        }
+    }
+}
+
+/// A linear framebuffer with drawing primitives and a text console using
+/// the 8x13 font from `font`.
+///
+/// Under QEMU the result can be checked with the monitor's `screendump`, the
+/// tests draw into plain RAM and read the pixels back.
+pub struct Framebuffer;
+
+impl Framebuffer {
+    /// Maps the framebuffer and clears it. `info` has to come from the
+    /// caller, bootloader 0.9's `BootInfo` has none.
+    pub fn init(info: FramebufferInfo) -> Option<()> {
+        if info.bytes_per_pixel < 3 || info.bytes_per_pixel > 4 ||
+           info.stride < info.width * info.bytes_per_pixel {
+            return None;
+        }
+
+        let base = VM::map_mmio(info.address, (info.stride * info.height) as u64)?;
+        let state = State {
+            info,
+            base,
+            row: 0,
+            column: 0,
+            foreground: Color::LIGHT_GREY,
+            background: Color::BLACK,
+        };
+
+        state.fill_rect(0, 0, info.width, info.height, state.background);
+        *STATE.lock() = Some(state);
+        Some(())
+    }
+
+    pub fn info() -> Option<FramebufferInfo> {
+        STATE.lock().as_ref().map(|state| state.info)
+    }
+
+    pub fn put_pixel(x: usize, y: usize, color: Color) {
+        if let Some(state) = STATE.lock().as_ref() {
+            if x < state.info.width && y < state.info.height {
+                state.put(x, y, &state.encode(color));
+            }
+        }
+    }
+
+    /// Fills a rectangle, the parts outside the screen are dropped.
+    pub fn fill_rect(x: usize, y: usize, width: usize, height: usize, color: Color) {
+        if let Some(state) = STATE.lock().as_ref() {
+            state.fill_rect(x, y, width, height, color);
+        }
+    }
+
+    /// Copies `width` x `height` 0xRRGGBB pixels, line by line, to (x, y).
+    pub fn blit(x: usize, y: usize, width: usize, height: usize, pixels: &[u32]) {
+        if let Some(state) = STATE.lock().as_ref() {
+            for row in 0..height.min(state.info.height.saturating_sub(y)) {
+                for column in 0..width.min(state.info.width.saturating_sub(x)) {
+                    if let Some(&rgb) = pixels.get(row * width + column) {
+                        state.put(x + column, y + row, &state.encode(Color::from_rgb(rgb)));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn draw_char(x: usize, y: usize, byte: u8, foreground: Color, background: Color) {
+        if let Some(state) = STATE.lock().as_ref() {
+            state.draw_char(x, y, byte, foreground, background);
+        }
+    }
+
+    /// Moves the picture up by `lines` pixel lines.
+    pub fn scroll(lines: usize, background: Color) {
+        if let Some(state) = STATE.lock().as_ref() {
+            state.scroll(lines, background);
+        }
+    }
+
+    /// Clears the screen and moves the text cursor home.
+    pub fn clear() {
+        if let Some(state) = STATE.lock().as_mut() {
+            state.fill_rect(0, 0, state.info.width, state.info.height, state.background);
+            state.row = 0;
+            state.column = 0;
+        }
+    }
+
+    /// Colours for text written from now on.
+    pub fn set_colors(foreground: Color, background: Color) {
+        if let Some(state) = STATE.lock().as_mut() {
+            state.foreground = foreground;
+            state.background = background;
+        }
+    }
+
+    pub fn write_bytes(bytes: &[u8]) {
+        if let Some(state) = STATE.lock().as_mut() {
+            for &byte in bytes {
+                state.write_byte(byte);
+            }
+        }
+    }
+}
+
+impl Console for Framebuffer {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        Framebuffer::write_bytes(bytes);
+    }
+}
@@ -0,0 +1,347 @@
+#[allow(dead_code)]
+
+use core::convert::TryFrom;
+
+use crate::acpi::ACPI;
+use crate::apic::APIC;
+use crate::ioapic::{IOAPIC, IOAPICPolarity, IOAPICTrigger};
+use crate::sync::Once;
+use crate::time::{ClockEvent, ClockSource};
+use crate::vm::VM;
+
+/* HPET registers */
+const GCAP_ID: u64      = 0x000;
+const GEN_CONF: u64     = 0x010;
+const GINTR_STA: u64    = 0x020;
+const MAIN_CNT: u64     = 0x0f0;
+
+const fn timer_conf(n: usize) -> u64 { 0x100 + 0x20 * n as u64 }
+const fn timer_comparator(n: usize) -> u64 { 0x108 + 0x20 * n as u64 }
+const fn timer_fsb_route(n: usize) -> u64 { 0x110 + 0x20 * n as u64 }
+
+/* GCAP_ID fields */
+const NUM_TIMERS_SHIFT: u8      = 8;
+const NUM_TIMERS_MASK: u64      = 0x1f;
+const COUNT_SIZE_CAP: u64       = 1 << 13;
+const LEG_RT_CAP: u64           = 1 << 15;
+const PERIOD_SHIFT: u8          = 32;
+
+/* GEN_CONF fields */
+const ENABLE_CNF: u64   = 1 << 0;
+const LEG_RT_CNF: u64   = 1 << 1;
+
+/* Tn_CONF fields */
+const TN_INT_TYPE_LEVEL: u64    = 1 << 1;
+const TN_INT_ENB: u64           = 1 << 2;
+const TN_TYPE_PERIODIC: u64     = 1 << 3;
+const TN_PER_INT_CAP: u64       = 1 << 4;
+const TN_SIZE_CAP: u64          = 1 << 5;
+const TN_VAL_SET: u64           = 1 << 6;
+const TN_32MODE: u64            = 1 << 8;
+const TN_INT_ROUTE_SHIFT: u8    = 9;
+const TN_INT_ROUTE_MASK: u64    = 0x1f;
+const TN_FSB_EN: u64            = 1 << 14;
+const TN_FSB_DEL_CAP: u64       = 1 << 15;
+const TN_INT_ROUTE_CAP_SHIFT: u8 = 32;
+
+const FEMTOSECONDS: u64 = 1_000_000_000_000_000;
+/* Longest tick period the specification allows, 100 ns */
+const MAX_PERIOD: u64   = 100_000_000;
+
+struct Block {
+    base: u64,
+    /* Nonzero, the block is not used otherwise */
+    period: u64,
+    timers: usize,
+}
+
+impl Block {
+    fn read64(&self, register: u64) -> u64 {
+        HPET::read_at(self.base, register)
+    }
+
+    fn write64(&self, register: u64, value: u64) {
+        unsafe { ((self.base + register) as *mut u64).write_volatile(value) }
+    }
+
+    fn frequency(&self) -> u64 {
+        FEMTOSECONDS / self.period
+    }
+}
+
+static HPET_STATE: Once<Option<Block>> = Once::new();
+
+#[derive(Clone, Copy)]
+pub enum HPETRoute {
+    /// Deliver through the IOAPIC input `gsi`.
+    HPET_ROUTE_IOAPIC(u32),
+    /// Deliver as an MSI message straight to a local APIC.
+    HPET_ROUTE_FSB(u8),
+}
+
+#[derive(Debug)]
+pub enum HPETError {
+    HPET_ERROR_NOT_PRESENT,
+    HPET_ERROR_NO_SUCH_TIMER,
+    HPET_ERROR_ROUTE_UNSUPPORTED,
+}
+
+pub struct HPET;
+
+impl HPET {
+    fn block() -> Option<&'static Block> {
+        HPET_STATE.call_once(|| {
+            let table = ACPI::hpet()?;
+            let base = VM::map_mmio(table.base_address.address, 0x400)?;
+            let caps = HPET::read_at(base, GCAP_ID);
+            let period = caps >> PERIOD_SHIFT;
+
+            /* A bogus period would divide by zero in frequency() */
+            if period == 0 || period > MAX_PERIOD {
+                return None;
+            }
+
+            Some(Block {
+                base,
+                period,
+                timers: (((caps >> NUM_TIMERS_SHIFT) & NUM_TIMERS_MASK) + 1) as usize,
+            })
+        }).as_ref()
+    }
+
+    fn present_block() -> Result<&'static Block, HPETError> {
+        HPET::block().ok_or(HPETError::HPET_ERROR_NOT_PRESENT)
+    }
+
+    fn timer_block(index: usize) -> Result<&'static Block, HPETError> {
+        let block = HPET::present_block()?;
+
+        if index >= block.timers {
+            return Err(HPETError::HPET_ERROR_NO_SUCH_TIMER);
+        }
+
+        Ok(block)
+    }
+
+    fn read_at(base: u64, register: u64) -> u64 {
+        unsafe { ((base + register) as *const u64).read_volatile() }
+    }
+
+    pub fn present() -> bool {
+        HPET::block().is_some()
+    }
+
+    /// Locates the HPET through ACPI, maps it and starts the main counter.
+    /// Legacy replacement routing is turned off so the PIT and RTC keep
+    /// their interrupts.
+    pub fn init() -> Result<(), HPETError> {
+        let block = HPET::present_block()?;
+
+        let conf = block.read64(GEN_CONF) & !LEG_RT_CNF;
+        block.write64(GEN_CONF, conf | ENABLE_CNF);
+        Ok(())
+    }
+
+    /// Counter tick period in femtoseconds.
+    pub fn period() -> Result<u64, HPETError> {
+        Ok(HPET::present_block()?.period)
+    }
+
+    pub fn frequency() -> Result<u64, HPETError> {
+        Ok(HPET::present_block()?.frequency())
+    }
+
+    pub fn counter() -> Result<u64, HPETError> {
+        Ok(HPET::present_block()?.read64(MAIN_CNT))
+    }
+
+    pub fn is_64bit() -> Result<bool, HPETError> {
+        Ok((HPET::present_block()?.read64(GCAP_ID) & COUNT_SIZE_CAP) != 0)
+    }
+
+    pub fn timers() -> usize {
+        HPET::block().map_or(0, |block| block.timers)
+    }
+
+    /// Bitmap of the IOAPIC inputs comparator `index` can be routed to.
+    pub fn ioapic_routes(index: usize) -> Result<u32, HPETError> {
+        let block = HPET::timer_block(index)?;
+        Ok((block.read64(timer_conf(index)) >> TN_INT_ROUTE_CAP_SHIFT) as u32)
+    }
+
+    /// Acknowledges a level triggered interrupt of comparator `index`.
+    pub fn ack(index: usize) -> Result<(), HPETError> {
+        HPET::timer_block(index)?.write64(GINTR_STA, 1 << index);
+        Ok(())
+    }
+
+    /// Sets up comparator `index` to deliver `vector` through `route`. The
+    /// comparator stays disarmed until one of the `ClockEvent` methods is used.
+    pub fn comparator(index: usize, vector: u8, route: HPETRoute) -> Result<HPETComparator, HPETError> {
+        let block = HPET::timer_block(index)?;
+
+        let mut conf = block.read64(timer_conf(index));
+        conf &= !(TN_INT_ENB | TN_TYPE_PERIODIC | TN_INT_TYPE_LEVEL | TN_FSB_EN |
+                  (TN_INT_ROUTE_MASK << TN_INT_ROUTE_SHIFT));
+
+        match route {
+            HPETRoute::HPET_ROUTE_IOAPIC(gsi) => {
+                if gsi >= 32 || (HPET::ioapic_routes(index)? & (1 << gsi)) == 0 {
+                    return Err(HPETError::HPET_ERROR_ROUTE_UNSUPPORTED);
+                }
+
+                let apic_id = u8::try_from(APIC::id())
+                    .map_err(|_| HPETError::HPET_ERROR_ROUTE_UNSUPPORTED)?;
+
+                IOAPIC::route(gsi, vector, apic_id,
+                              IOAPICTrigger::IOAPIC_TRIGGER_EDGE,
+                              IOAPICPolarity::IOAPIC_POLARITY_HIGH)
+                    .ok_or(HPETError::HPET_ERROR_ROUTE_UNSUPPORTED)?;
+
+                conf |= (gsi as u64) << TN_INT_ROUTE_SHIFT;
+            },
+            HPETRoute::HPET_ROUTE_FSB(apic_id) => {
+                if (conf & TN_FSB_DEL_CAP) == 0 {
+                    return Err(HPETError::HPET_ERROR_ROUTE_UNSUPPORTED);
+                }
+
+                /* MSI address in the upper half, data in the lower */
+                let address = (APIC::ADDRESS as u64) | ((apic_id as u64) << 12);
+                block.write64(timer_fsb_route(index), (address << 32) | vector as u64);
+
+                conf |= TN_FSB_EN;
+            },
+        }
+
+        block.write64(timer_conf(index), conf);
+
+        Ok(HPETComparator { block, index, periodic_capable: (conf & TN_PER_INT_CAP) != 0 })
+    }
+}
+
+pub struct HPETComparator {
+    block: &'static Block,
+    index: usize,
+    periodic_capable: bool,
+}
+
+impl HPETComparator {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn periodic_capable(&self) -> bool {
+        self.periodic_capable
+    }
+
+    fn conf(&self) -> u64 {
+        self.block.read64(timer_conf(self.index))
+    }
+
+    fn set_conf(&self, conf: u64) {
+        self.block.write64(timer_conf(self.index), conf);
+    }
+
+    fn counter(&self) -> u64 {
+        self.block.read64(MAIN_CNT)
+    }
+}
+
+impl ClockEvent for HPETComparator {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn frequency(&self) -> u64 {
+        self.block.frequency()
+    }
+
+    fn set_oneshot(&self, ticks: u64) {
+        let conf = self.conf() & !TN_TYPE_PERIODIC;
+
+        self.set_conf(conf & !TN_INT_ENB);
+        self.block.write64(timer_comparator(self.index), self.counter().wrapping_add(ticks));
+        self.set_conf(conf | TN_INT_ENB);
+    }
+
+    /// Falls back to one-shot if the comparator can not do periodic mode.
+    fn set_periodic(&self, ticks: u64) {
+        if !self.periodic_capable {
+            self.set_oneshot(ticks);
+            return;
+        }
+
+        let conf = self.conf() & !TN_INT_ENB;
+        let wide = (conf & TN_SIZE_CAP) != 0 && (conf & TN_32MODE) == 0;
+        self.set_conf(conf);
+
+        /* With VAL_SET the first write sets the comparator, the second the
+         * period. The main counter keeps running for the clocksource, so
+         * start over further out if it got past the first deadline in
+         * between. */
+        let mut lead = ticks.max(1);
+
+        loop {
+            let start = self.counter().wrapping_add(lead);
+
+            self.set_conf(conf | TN_TYPE_PERIODIC | TN_VAL_SET);
+            self.block.write64(timer_comparator(self.index), start);
+            self.block.write64(timer_comparator(self.index), ticks);
+
+            let ahead = start.wrapping_sub(self.counter());
+            let pending = if wide { (ahead as i64) > 0 } else { (ahead as i32) > 0 };
+
+            if pending {
+                break;
+            }
+
+            lead = lead.saturating_mul(2);
+        }
+
+        self.set_conf(conf | TN_TYPE_PERIODIC | TN_INT_ENB);
+    }
+
+    fn stop(&self) {
+        let conf = self.conf() & !(TN_INT_ENB | TN_TYPE_PERIODIC);
+        self.set_conf(conf);
+    }
+}
+
+/// The HPET main counter as a `ClockSource`.
+///
+/// A 32-bit counter wraps every few minutes, so it is rated below the TSC
+/// and only meant as a fallback or calibration reference.
+pub struct HPETClockSource {
+    block: &'static Block,
+    wide: bool,
+}
+
+impl HPETClockSource {
+    pub fn new() -> Result<Self, HPETError> {
+        let block = HPET::present_block()?;
+
+        Ok(HPETClockSource { block, wide: HPET::is_64bit()? })
+    }
+}
+
+impl ClockSource for HPETClockSource {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        if self.wide { 250 } else { 100 }
+    }
+
+    fn frequency(&self) -> u64 {
+        self.block.frequency()
+    }
+
+    fn read(&self) -> u64 {
+        self.block.read64(MAIN_CNT)
+    }
+}
@@ -1,27 +1,38 @@
 #[allow(dead_code)]
 
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::println;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use log::{debug, error, info, warn};
 use crate::pic::PIC;
 use crate::apic::APIC;
+use crate::debugreg;
+use crate::executor;
+use crate::fpu;
+use crate::sched;
+use crate::time;
 use crate::interrupt_controller::InterruptController;
+use crate::sync::{lockdep, IrqSpinLock};
 use lazy_static::lazy_static;
 
+/// Vector of the test IPI handler, IRQ3 would land there through the PIC.
+pub const IPI_VECTOR: u8 = 35;
+
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
+    /* Behind a lock so drivers can add handlers after it was loaded */
+    static ref IDT: IrqSpinLock<InterruptDescriptorTable> = {
         let mut idt = InterruptDescriptorTable::new();
         idt[32].set_handler_fn(timer_handler);
-        idt[35].set_handler_fn(ipi_handler);
+        idt[IPI_VECTOR as usize].set_handler_fn(ipi_handler);
         idt[39].set_handler_fn(spurious_handler);
+        idt[executor::WAKE_VECTOR as usize].set_handler_fn(wake_handler);
 
         idt.divide_error.set_handler_fn(generic_handler);
-        idt.debug.set_handler_fn(generic_handler);
+        idt.debug.set_handler_fn(debugreg::debug_handler);
         idt.non_maskable_interrupt.set_handler_fn(generic_handler);
         idt.breakpoint.set_handler_fn(generic_handler);
         idt.overflow.set_handler_fn(generic_handler);
         idt.bound_range_exceeded.set_handler_fn(generic_handler);
         idt.invalid_opcode.set_handler_fn(generic_handler);
-        idt.device_not_available.set_handler_fn(generic_handler);
+        idt.device_not_available.set_handler_fn(fpu::device_not_available_handler);
         idt.x87_floating_point.set_handler_fn(generic_handler);
         idt.machine_check.set_handler_fn(machine_check_handler);
         idt.virtualization.set_handler_fn(generic_handler);
@@ -31,21 +42,29 @@ lazy_static! {
 
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.page_fault.set_handler_fn(pagefault_handler);
-        idt
+        IrqSpinLock::new(idt)
     };
 }
 
 pub fn init_idt() {
-    IDT.load();
+    /* The table lives in a static, so it stays valid after the guard is gone */
+    unsafe { IDT.lock().load_unsafe() }
+}
+
+/// Installs `handler` for the external interrupt `vector`, on all CPUs.
+/// Vectors below 32 are reserved for exceptions.
+pub fn set_handler(vector: u8, handler: HandlerFunc) {
+    assert!(vector >= 32, "vector {} is an exception", vector);
+    IDT.lock()[vector as usize].set_handler_fn(handler);
 }
 
 extern "x86-interrupt" fn pagefault_handler(
     _stack_frame: &mut InterruptStackFrame,
     error_code: PageFaultErrorCode)
 {
-    println!("page fault -> error: {:?}", error_code);
-    println!("fault_address {:?}", x86_64::registers::control::Cr2::read());
-    println!("page-table address {:?}", x86_64::registers::control::Cr3::read());
+    error!("page fault -> error: {:?}", error_code);
+    error!("fault_address {:?}", x86_64::registers::control::Cr2::read());
+    error!("page-table address {:?}", x86_64::registers::control::Cr3::read());
     loop {}
 }
 
@@ -53,9 +72,9 @@ extern "x86-interrupt" fn double_fault_handler(
     _stack_frame: &mut InterruptStackFrame,
     error_code: u64) -> !
 {
-    println!("double fault -> error: {:?}", error_code);
-    println!("fault_address {:?}", x86_64::registers::control::Cr2::read());
-    println!("page-table address {:?}", x86_64::registers::control::Cr3::read());
+    error!("double fault -> error: {:?}", error_code);
+    error!("fault_address {:?}", x86_64::registers::control::Cr2::read());
+    error!("page-table address {:?}", x86_64::registers::control::Cr3::read());
     loop {}
 }
 
@@ -63,50 +82,62 @@ extern "x86-interrupt" fn gpf_handler(
     _stack_frame: &mut InterruptStackFrame,
     _error_code: u64)
 {
-    println!("gp fault");
+    error!("gp fault");
     loop {}
 }
 
 extern "x86-interrupt" fn ipi_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
-    println!("IPI handler!");
+    lockdep::irq_enter();
+    debug!("IPI handler!");
     APIC::eoi(0);
+    lockdep::irq_exit();
 }
 
 extern "x86-interrupt" fn spurious_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
-    println!("Spurious handler!");
+    warn!("Spurious handler!");
     loop {}
 }
 
 extern "x86-interrupt" fn generic_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
-    println!("Generic handler!");
+    error!("Generic handler!");
     loop {}
 }
 
 extern "x86-interrupt" fn machine_check_handler(
     _stack_frame: &mut InterruptStackFrame) -> !
 {
-    println!("Machine check handler!");
+    error!("Machine check handler!");
     loop {}
 }
 
 extern "x86-interrupt" fn timer_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
-    println!("-- isr: {}", PIC::isr());
-    println!("-- irr: {}", PIC::irr());
+    lockdep::irq_enter();
+    time::interrupt();
 
     PIC::eoi(0);
     APIC::eoi(0);
+    lockdep::irq_exit();
+
+    sched::preempt();
+}
+
+extern "x86-interrupt" fn wake_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    /* Only there to get a CPU out of hlt, the waker already set the task ready */
+    APIC::eoi(0);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: &mut InterruptStackFrame)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -0,0 +1,217 @@
+#[allow(dead_code)]
+
+use core::convert::TryFrom;
+
+use crate::acpi::ACPI;
+use crate::apic::APIC;
+use crate::interrupt_controller::InterruptController;
+use crate::sync::{IrqSpinLock, Once};
+use crate::vm::VM;
+
+const DEFAULT_ADDRESS: u64 = 0xfec00000;
+
+/* Indirect register access */
+const IOREGSEL: usize   = 0x00;
+const IOWIN: usize      = 0x10;
+
+/* IOAPIC registers */
+const ID: u32           = 0x00;
+const VERSION: u32      = 0x01;
+const REDTBL: u32       = 0x10;
+
+/* Redirection table entry fields */
+const DELIVERY_MODE_FIXED: u64      = 0x0 << 8;
+const DELIVERY_MODE_LP: u64         = 0x1 << 8;
+const DELIVERY_MODE_NMI: u64        = 0x4 << 8;
+const DESTINATION_MODE_PHYSICAL: u64 = 0x0 << 11;
+const DESTINATION_MODE_LOGICAL: u64 = 0x1 << 11;
+const POLARITY_HIGH: u64            = 0x0 << 13;
+const POLARITY_LOW: u64             = 0x1 << 13;
+const TRIGGER_MODE_EDGE: u64        = 0x0 << 15;
+const TRIGGER_MODE_LEVEL: u64       = 0x1 << 15;
+const MASKED: u64                   = 0x1 << 16;
+const DESTINATION_SHIFT: u8         = 56;
+
+#[derive(Clone, Copy)]
+pub enum IOAPICTrigger {
+    IOAPIC_TRIGGER_EDGE,
+    IOAPIC_TRIGGER_LEVEL,
+}
+
+#[derive(Clone, Copy)]
+pub enum IOAPICPolarity {
+    IOAPIC_POLARITY_HIGH,
+    IOAPIC_POLARITY_LOW,
+}
+
+struct Controller {
+    base: u64,
+    gsi_base: u32,
+    entries: u32,
+}
+
+static IOAPIC_STATE: Once<Controller> = Once::new();
+/* Serializes IOREGSEL/IOWIN accesses */
+static LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+pub struct IOAPIC;
+
+impl IOAPIC {
+    fn controller() -> &'static Controller {
+        IOAPIC_STATE.call_once(|| {
+            let (address, gsi_base) = match ACPI::ioapic() {
+                Some(ioapic) => (ioapic.address as u64, ioapic.gsi_base),
+                None => (DEFAULT_ADDRESS, 0),
+            };
+
+            let base = VM::map_mmio(address, 0x20).unwrap();
+            let version = IOAPIC::read_at(base, VERSION);
+
+            Controller {
+                base,
+                gsi_base,
+                entries: ((version >> 16) & 0xff) + 1,
+            }
+        })
+    }
+
+    fn read_at(base: u64, register: u32) -> u32 {
+        let _guard = LOCK.lock();
+
+        unsafe {
+            ((base as usize + IOREGSEL) as *mut u32).write_volatile(register);
+            ((base as usize + IOWIN) as *mut u32).read_volatile()
+        }
+    }
+
+    fn write_at(base: u64, register: u32, value: u32) {
+        let _guard = LOCK.lock();
+
+        unsafe {
+            ((base as usize + IOREGSEL) as *mut u32).write_volatile(register);
+            ((base as usize + IOWIN) as *mut u32).write_volatile(value);
+        }
+    }
+
+    fn read32(register: u32) -> u32 {
+        IOAPIC::read_at(IOAPIC::controller().base, register)
+    }
+
+    fn write32(register: u32, value: u32) {
+        IOAPIC::write_at(IOAPIC::controller().base, register, value)
+    }
+
+    fn pin(gsi: u32) -> Option<u32> {
+        let controller = IOAPIC::controller();
+
+        if gsi < controller.gsi_base || gsi - controller.gsi_base >= controller.entries {
+            return None;
+        }
+
+        Some(gsi - controller.gsi_base)
+    }
+
+    fn read_entry(pin: u32) -> u64 {
+        let low = IOAPIC::read32(REDTBL + pin * 2);
+        let high = IOAPIC::read32(REDTBL + pin * 2 + 1);
+
+        ((high as u64) << 32) | (low as u64)
+    }
+
+    fn write_entry(pin: u32, value: u64) {
+        /* Write the upper half first, the entry may get unmasked by the lower */
+        IOAPIC::write32(REDTBL + pin * 2 + 1, (value >> 32) as u32);
+        IOAPIC::write32(REDTBL + pin * 2, value as u32);
+    }
+
+    pub fn id() -> u32 {
+        (IOAPIC::read32(ID) >> 24) & 0xf
+    }
+
+    pub fn entries() -> u32 {
+        IOAPIC::controller().entries
+    }
+
+    /// Routes `gsi` as a fixed interrupt with `vector` to the local APIC
+    /// `apic_id`. The entry is left unmasked.
+    pub fn route(gsi: u32, vector: u8, apic_id: u8,
+                 trigger: IOAPICTrigger, polarity: IOAPICPolarity) -> Option<()> {
+        let pin = IOAPIC::pin(gsi)?;
+
+        let mut value = vector as u64;
+        value |= DELIVERY_MODE_FIXED | DESTINATION_MODE_PHYSICAL;
+        value |= match trigger {
+            IOAPICTrigger::IOAPIC_TRIGGER_EDGE => TRIGGER_MODE_EDGE,
+            IOAPICTrigger::IOAPIC_TRIGGER_LEVEL => TRIGGER_MODE_LEVEL,
+        };
+        value |= match polarity {
+            IOAPICPolarity::IOAPIC_POLARITY_HIGH => POLARITY_HIGH,
+            IOAPICPolarity::IOAPIC_POLARITY_LOW => POLARITY_LOW,
+        };
+        value |= (apic_id as u64) << DESTINATION_SHIFT;
+
+        IOAPIC::write_entry(pin, value);
+        Some(())
+    }
+
+    /// Routes the legacy ISA `irq` (edge, active high unless the MADT says
+    /// otherwise) to `vector` on the current CPU. Fails if its APIC ID does
+    /// not fit the 8-bit destination field.
+    pub fn route_isa(irq: u8, vector: u8) -> Option<()> {
+        let (gsi, trigger, polarity) = match ACPI::source_override(irq) {
+            Some(o) => {
+                /* MPS INTI flags: polarity in bits 1:0, trigger mode in bits 3:2 */
+                let polarity = match o.flags & 0x3 {
+                    0x3 => IOAPICPolarity::IOAPIC_POLARITY_LOW,
+                    _ => IOAPICPolarity::IOAPIC_POLARITY_HIGH,
+                };
+                let trigger = match (o.flags >> 2) & 0x3 {
+                    0x3 => IOAPICTrigger::IOAPIC_TRIGGER_LEVEL,
+                    _ => IOAPICTrigger::IOAPIC_TRIGGER_EDGE,
+                };
+                (o.gsi, trigger, polarity)
+            },
+            None => (irq as u32, IOAPICTrigger::IOAPIC_TRIGGER_EDGE, IOAPICPolarity::IOAPIC_POLARITY_HIGH),
+        };
+
+        IOAPIC::route(gsi, vector, u8::try_from(APIC::id()).ok()?, trigger, polarity)
+    }
+}
+
+impl InterruptController for IOAPIC {
+    fn enable() {
+        IOAPIC::controller();
+    }
+
+    fn disable() {
+        for pin in 0..IOAPIC::entries() {
+            IOAPIC::write_entry(pin, IOAPIC::read_entry(pin) | MASKED);
+        }
+    }
+
+    fn reset() {
+        for pin in 0..IOAPIC::entries() {
+            IOAPIC::write_entry(pin, MASKED);
+        }
+    }
+
+    fn eoi(_irq: u32) {
+        APIC::eoi(0);
+    }
+
+    fn spurious_irq() -> u32 {
+        APIC::spurious_irq()
+    }
+
+    fn mask(irq: u32) {
+        if let Some(pin) = IOAPIC::pin(irq) {
+            IOAPIC::write_entry(pin, IOAPIC::read_entry(pin) | MASKED);
+        }
+    }
+
+    fn unmask(irq: u32) {
+        if let Some(pin) = IOAPIC::pin(irq) {
+            IOAPIC::write_entry(pin, IOAPIC::read_entry(pin) & !MASKED);
+        }
+    }
+}
@@ -1,10 +1,11 @@
+use crate::cpu::CPU;
+use crate::fpu;
 use crate::page_alloc::page_alloc_init;
 use crate::vm::VM;
 use bootloader::BootInfo;
 use lazy_static::lazy_static;
 use x86_64::instructions::segmentation::*;
 use x86_64::instructions::tables::*;
-use x86_64::registers::control::{Cr0, Cr0Flags};
 use x86_64::structures::gdt::*;
 use x86_64::structures::tss::*;
 
@@ -38,11 +39,13 @@ fn init_segmentation() {
 }
 
 pub fn kernel_init(boot_info: &'static BootInfo) {
-    unsafe {
-        Cr0::write_raw(Cr0::read_raw() | Cr0Flags::NUMERIC_ERROR.bits());
-    }
+    /* Also sets CR0.NE */
+    fpu::init().expect("no FXSAVE support");
 
     VM::set_phys_offset(boot_info.physical_memory_offset);
     page_alloc_init(boot_info);
     init_segmentation();
+
+    /* After the segment loads, they may clear GS_BASE */
+    CPU::register().expect("too many CPUs");
 }
@@ -2,6 +2,8 @@
 #![feature(llvm_asm)]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(global_asm)]
+#![cfg_attr(feature = "lockdep", feature(const_caller_location))]
 
 extern crate x86_64;
 extern crate alloc;
@@ -13,9 +15,31 @@ pub mod pic;
 pub mod msr;
 pub mod apic;
 pub mod cpu;
+pub mod cpuid;
+pub mod fpu;
+pub mod debugreg;
 pub mod idt;
 #[macro_use]
 pub mod output;
 pub mod kernel;
 pub mod page_alloc;
 pub mod interrupt_controller;
+pub mod sync;
+pub mod tsc;
+pub mod pit;
+pub mod time;
+pub mod acpi;
+pub mod ioapic;
+pub mod hpet;
+pub mod rtc;
+pub mod sched;
+pub mod executor;
+pub mod serial;
+pub mod console;
+pub mod vga;
+pub mod logger;
+pub mod font;
+pub mod framebuffer;
+pub mod virt;
+pub mod vmx;
+pub mod svm;
@@ -0,0 +1,217 @@
+//! `log` crate backend.
+//!
+//! Records go to the consoles and to an in-memory ring buffer, prefixed with
+//! the time since boot, the CPU and the level:
+//!
+//! ```text
+//! [    1.002345] cpu0  WARN libos::idt: Spurious interrupt
+//! ```
+//!
+//! Which records get through is set by a filter in `env_logger` syntax, a
+//! default level followed by per-module overrides: `info,libos::pit=debug,libos::idt=off`.
+//! The longest matching module prefix wins.
+
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::console::{self, RingConsole};
+use crate::cpu::CPU;
+use crate::sync::IrqSpinLock;
+use crate::time;
+
+pub const MAX_RULES: usize = 16;
+const MAX_MODULE: usize = 64;
+/* Longer lines get truncated */
+const MAX_LINE: usize = 512;
+
+/* Kernel command line option holding the filter */
+const CMDLINE_OPTION: &str = "log=";
+
+#[derive(Clone, Copy)]
+struct Rule {
+    module: [u8; MAX_MODULE],
+    len: usize,
+    level: LevelFilter,
+}
+
+impl Rule {
+    fn module(&self) -> &str {
+        core::str::from_utf8(&self.module[..self.len]).unwrap_or("")
+    }
+
+    /* `libos::pit` matches `libos::pit` and `libos::pit::foo`, not `libos::pitfall` */
+    fn matches(&self, target: &str) -> bool {
+        let module = self.module();
+
+        target.starts_with(module) &&
+            (target.len() == module.len() || target[module.len()..].starts_with("::"))
+    }
+}
+
+struct Filter {
+    default: LevelFilter,
+    rules: [Option<Rule>; MAX_RULES],
+}
+
+impl Filter {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.rules.iter()
+            .flatten()
+            .filter(|rule| rule.matches(target))
+            .max_by_key(|rule| rule.len)
+            .map_or(self.default, |rule| rule.level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.rules.iter()
+            .flatten()
+            .map(|rule| rule.level)
+            .fold(self.default, |max, level| max.max(level))
+    }
+}
+
+#[derive(Debug)]
+pub enum LoggerError {
+    LOGGER_ERROR_INVALID_LEVEL,
+    LOGGER_ERROR_MODULE_TOO_LONG,
+    LOGGER_ERROR_TOO_MANY_RULES,
+    LOGGER_ERROR_ALREADY_SET,
+}
+
+static FILTER: IrqSpinLock<Filter> = IrqSpinLock::new(Filter {
+    default: LevelFilter::Info,
+    rules: [None; MAX_RULES],
+});
+
+/// Everything logged, also what the filter let through but no console was
+/// enabled for. Dump it with `dump()` after a crash.
+pub static DMESG: RingConsole = RingConsole::new();
+
+static LOGGER: Logger = Logger;
+
+struct Logger;
+
+/* Formats into a fixed buffer, dropping what does not fit. The last byte
+ * is kept for the newline. */
+struct LineBuffer {
+    data: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl fmt::Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(MAX_LINE - 1 - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.data[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.lock().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = time::now();
+        let mut line = LineBuffer { data: [0; MAX_LINE], len: 0 };
+
+        let _ = write!(line, "[{:5}.{:06}] cpu{} {:5} {}: {}",
+                       now.as_secs(), now.subsec_micros(), CPU::id(),
+                       record.level(), record.target(), record.args());
+
+        line.data[line.len] = b'\n';
+        line.len += 1;
+
+        console::Console::write(&DMESG, &line.data[..line.len]);
+        print!("{}", core::str::from_utf8(&line.data[..line.len]).unwrap_or(""));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Replaces the filter, see the module documentation for the syntax. On
+/// error the old filter stays.
+pub fn set_filter(spec: &str) -> Result<(), LoggerError> {
+    let mut filter = Filter {
+        default: LevelFilter::Info,
+        rules: [None; MAX_RULES],
+    };
+    let mut count = 0;
+
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let (module, level) = match directive.find('=') {
+            Some(index) => (Some(&directive[..index]), &directive[index + 1..]),
+            None => (None, directive),
+        };
+
+        let level = LevelFilter::from_str(level.trim())
+            .map_err(|_| LoggerError::LOGGER_ERROR_INVALID_LEVEL)?;
+
+        let module = match module {
+            Some(module) => module.trim(),
+            None => {
+                filter.default = level;
+                continue;
+            },
+        };
+
+        if module.len() > MAX_MODULE {
+            return Err(LoggerError::LOGGER_ERROR_MODULE_TOO_LONG);
+        }
+        if count == MAX_RULES {
+            return Err(LoggerError::LOGGER_ERROR_TOO_MANY_RULES);
+        }
+
+        let mut rule = Rule { module: [0; MAX_MODULE], len: module.len(), level };
+        rule.module[..module.len()].copy_from_slice(module.as_bytes());
+        filter.rules[count] = Some(rule);
+        count += 1;
+    }
+
+    log::set_max_level(filter.max_level());
+    *FILTER.lock() = filter;
+    Ok(())
+}
+
+/// Installs the logger with `filter`.
+pub fn init(filter: &str) -> Result<(), LoggerError> {
+    set_filter(filter)?;
+    log::set_logger(&LOGGER).map_err(|_| LoggerError::LOGGER_ERROR_ALREADY_SET)
+}
+
+/// Installs the logger with the filter from a `log=` option on the kernel
+/// command line, or `default` if there is none.
+pub fn init_from_cmdline(cmdline: &str, default: &str) -> Result<(), LoggerError> {
+    let filter = cmdline.split_whitespace()
+        .find(|option| option.starts_with(CMDLINE_OPTION))
+        .map_or(default, |option| &option[CMDLINE_OPTION.len()..]);
+
+    init(filter)
+}
+
+/// Writes the ring buffer to the consoles, bypassing the print lock a
+/// crashed CPU may still hold.
+pub fn dump() {
+    let mut buffer = [0u8; 256];
+    let (mut offset, _) = DMESG.read(0, &mut []);
+
+    loop {
+        let (start, len) = DMESG.read(offset, &mut buffer);
+        if len == 0 {
+            break;
+        }
+
+        console::write(&buffer[..len]);
+        offset = start + len;
+    }
+}
@@ -0,0 +1,108 @@
+mod registers;
+
+pub use self::registers::*;
+
+#[derive(Copy, Clone)]
+pub enum MSR {
+    IA32_TSC = 0x10,
+    IA32_APIC_BASE = 0x1b,
+    IA32_FEATURE_CONTROL = 0x3a,
+
+    IA32_MTRRCAP = 0xfe,
+
+    IA32_SYSENTER_CS = 0x174,
+    IA32_SYSENTER_ESP = 0x175,
+    IA32_SYSENTER_EIP = 0x176,
+
+    IA32_MISC_ENABLE = 0x1a0,
+
+    IA32_DEBUGCTLMSR = 0x1d9,
+
+    IA32_MTRR_FIX64K_00000 = 0x250,
+    IA32_MTRR_FIX16K_80000 = 0x258,
+    IA32_MTRR_FIX16K_A0000 = 0x259,
+    IA32_MTRR_FIX4K_C0000 = 0x268,
+    IA32_MTRR_FIX4K_C8000 = 0x269,
+    IA32_MTRR_FIX4K_D0000 = 0x26a,
+    IA32_MTRR_FIX4K_D8000 = 0x26b,
+    IA32_MTRR_FIX4K_E0000 = 0x26c,
+    IA32_MTRR_FIX4K_E8000 = 0x26d,
+    IA32_MTRR_FIX4K_F0000 = 0x26e,
+    IA32_MTRR_FIX4K_F8000 = 0x26f,
+
+    IA32_CR_PAT = 0x277,
+    IA32_MTRR_DEF_TYPE = 0x2ff,
+
+    IA32_VMX_BASIC = 0x480,
+    IA32_VMX_PINBASED_CTLS = 0x481,
+    IA32_VMX_PROCBASED_CTLS = 0x482,
+    IA32_VMX_EXIT_CTLS = 0x483,
+    IA32_VMX_ENTRY_CTLS = 0x484,
+    IA32_VMX_MISC = 0x485,
+    IA32_VMX_CR0_FIXED0 = 0x486,
+    IA32_VMX_CR0_FIXED1 = 0x487,
+    IA32_VMX_CR4_FIXED0 = 0x488,
+    IA32_VMX_CR4_FIXED1 = 0x489,
+    IA32_VMX_VMCS_ENUM = 0x48a,
+    IA32_VMX_PROCBASED_CTLS2 = 0x48b,
+    IA32_VMX_EPT_VPID_CAP = 0x48c,
+    IA32_VMX_TRUE_PINBASED_CTLS = 0x48d,
+    IA32_VMX_TRUE_PROCBASED_CTLS = 0x48e,
+    IA32_VMX_TRUE_EXIT_CTLS = 0x48f,
+    IA32_VMX_TRUE_ENTRY_CTLS = 0x490,
+    IA32_VMX_VMFUNC = 0x491,
+
+    IA32_TSC_DEADLINE = 0x6e0,
+
+    IA32_EFER = 0xc0000080,
+    IA32_STAR = 0xc0000081,
+    IA32_LSTAR = 0xc0000082,
+    IA32_CSTAR = 0xc0000083,
+    IA32_FMASK = 0xc0000084,
+    FS_BASE = 0xc0000100,
+    GS_BASE = 0xc0000101,
+    KERNEL_GS_BASE = 0xc0000102,
+    IA32_TSC_AUX = 0xc0000103,
+
+    /* AMD */
+    VM_CR = 0xc0010114,
+    VM_HSAVE_PA = 0xc0010117,
+}
+
+/* Variable range MTRRs are base/mask pairs from here */
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+
+impl MSR {
+    pub unsafe fn read(&self) -> u64 {
+        MSR::read_raw(*self as u32)
+    }
+
+    pub unsafe fn write(&self, value: u64) {
+        MSR::write_raw(*self as u32, value)
+    }
+
+    /// Reads any MSR by number, also those without an `MSR` variant.
+    pub unsafe fn read_raw(msr: u32) -> u64 {
+        let low: u32;
+        let high: u32;
+
+        llvm_asm!("rdmsr" : "={eax}" (low), "={edx}" (high) : "{ecx}" (msr) : "memory" : "volatile");
+        ((high as u64) << 32) | (low as u64)
+    }
+
+    pub unsafe fn write_raw(msr: u32, value: u64) {
+        let low = value as u32;
+        let high = (value >> 32) as u32;
+
+        llvm_asm!("wrmsr" :: "{ecx}" (msr), "{eax}" (low), "{edx}" (high) : "memory" : "volatile" );
+    }
+
+    /// IA32_MTRR_PHYSBASEn, the mask is the MSR after it.
+    pub fn mtrr_physbase(index: u32) -> u32 {
+        IA32_MTRR_PHYSBASE0 + index * 2
+    }
+
+    pub fn mtrr_physmask(index: u32) -> u32 {
+        IA32_MTRR_PHYSBASE0 + index * 2 + 1
+    }
+}
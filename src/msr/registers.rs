@@ -0,0 +1,337 @@
+//! Typed views of the commonly used MSRs.
+//!
+//! Each wrapper holds the raw value: `read()` it, change fields through the
+//! accessors and `write()` it back.
+
+use super::MSR;
+use crate::cpuid;
+
+macro_rules! msr_register {
+    ($(#[$meta:meta])* $name:ident, $msr:expr) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        pub struct $name(pub u64);
+
+        impl $name {
+            pub unsafe fn read() -> Self {
+                $name($msr.read())
+            }
+
+            pub unsafe fn write(self) {
+                $msr.write(self.0)
+            }
+
+            pub fn bits(self) -> u64 {
+                self.0
+            }
+        }
+    };
+}
+
+/* A single bit flag, getter and setter */
+macro_rules! msr_flag {
+    ($get:ident, $set:ident, $bit:expr) => {
+        pub fn $get(self) -> bool {
+            self.0 & (1 << $bit) != 0
+        }
+
+        pub fn $set(self, value: bool) -> Self {
+            if value {
+                Self(self.0 | (1 << $bit))
+            } else {
+                Self(self.0 & !(1 << $bit))
+            }
+        }
+    };
+}
+
+/* Bits above the physical address width */
+fn physical_address_mask() -> u64 {
+    let (physical, _) = cpuid::address_widths();
+    (1 << physical) - 1
+}
+
+/// Memory types of the PAT and MTRRs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemoryType {
+    MEMORY_TYPE_UC = 0,
+    MEMORY_TYPE_WC = 1,
+    MEMORY_TYPE_WT = 4,
+    MEMORY_TYPE_WP = 5,
+    MEMORY_TYPE_WB = 6,
+    /* PAT only */
+    MEMORY_TYPE_UC_MINUS = 7,
+}
+
+impl MemoryType {
+    pub fn from_bits(bits: u64) -> Option<MemoryType> {
+        match bits {
+            0 => Some(MemoryType::MEMORY_TYPE_UC),
+            1 => Some(MemoryType::MEMORY_TYPE_WC),
+            4 => Some(MemoryType::MEMORY_TYPE_WT),
+            5 => Some(MemoryType::MEMORY_TYPE_WP),
+            6 => Some(MemoryType::MEMORY_TYPE_WB),
+            7 => Some(MemoryType::MEMORY_TYPE_UC_MINUS),
+            _ => None,
+        }
+    }
+}
+
+msr_register!(ApicBase, MSR::IA32_APIC_BASE);
+
+impl ApicBase {
+    const BASE_MASK: u64 = !0xfff;
+
+    msr_flag!(bsp, set_bsp, 8);
+    msr_flag!(x2apic_enabled, set_x2apic_enabled, 10);
+    msr_flag!(enabled, set_enabled, 11);
+
+    /// Physical address of the xAPIC page.
+    pub fn base(self) -> u64 {
+        self.0 & ApicBase::BASE_MASK & physical_address_mask()
+    }
+
+    pub fn set_base(self, base: u64) -> Self {
+        ApicBase((self.0 & !ApicBase::BASE_MASK) | (base & ApicBase::BASE_MASK))
+    }
+}
+
+msr_register!(
+    /// Writes fault once `locked` is set, until the next reset.
+    FeatureControl, MSR::IA32_FEATURE_CONTROL);
+
+impl FeatureControl {
+    msr_flag!(locked, set_locked, 0);
+    msr_flag!(vmx_inside_smx, set_vmx_inside_smx, 1);
+    msr_flag!(vmx_outside_smx, set_vmx_outside_smx, 2);
+    msr_flag!(sgx_launch_control, set_sgx_launch_control, 17);
+    msr_flag!(sgx_enabled, set_sgx_enabled, 18);
+    msr_flag!(lmce_enabled, set_lmce_enabled, 20);
+}
+
+msr_register!(Efer, MSR::IA32_EFER);
+
+impl Efer {
+    msr_flag!(syscall_enabled, set_syscall_enabled, 0);
+    msr_flag!(long_mode_enabled, set_long_mode_enabled, 8);
+    /* Read only */
+    msr_flag!(long_mode_active, set_long_mode_active, 10);
+    msr_flag!(nx_enabled, set_nx_enabled, 11);
+    /* AMD */
+    msr_flag!(svm_enabled, set_svm_enabled, 12);
+    msr_flag!(fast_fxsave, set_fast_fxsave, 14);
+}
+
+msr_register!(
+    /// AMD SVM configuration.
+    VmCr, MSR::VM_CR);
+
+impl VmCr {
+    msr_flag!(debug_port_disabled, set_debug_port_disabled, 0);
+    msr_flag!(r_init, set_r_init, 1);
+    msr_flag!(a20m_disabled, set_a20m_disabled, 2);
+    /* Makes svm_disabled read only */
+    msr_flag!(locked, set_locked, 3);
+    msr_flag!(svm_disabled, set_svm_disabled, 4);
+}
+
+msr_register!(Pat, MSR::IA32_CR_PAT);
+
+impl Pat {
+    pub const ENTRIES: usize = 8;
+
+    /// The memory type of PAT entry `index`, None past the last entry.
+    pub fn entry(self, index: usize) -> Option<MemoryType> {
+        if index >= Pat::ENTRIES {
+            return None;
+        }
+
+        MemoryType::from_bits((self.0 >> (index * 8)) & 0x7)
+    }
+
+    pub fn set_entry(self, index: usize, memory_type: MemoryType) -> Option<Self> {
+        if index >= Pat::ENTRIES {
+            return None;
+        }
+
+        let shift = index * 8;
+        Some(Pat((self.0 & !(0xff << shift)) | ((memory_type as u64) << shift)))
+    }
+}
+
+msr_register!(MiscEnable, MSR::IA32_MISC_ENABLE);
+
+impl MiscEnable {
+    msr_flag!(fast_strings, set_fast_strings, 0);
+    msr_flag!(thermal_control, set_thermal_control, 3);
+    msr_flag!(performance_monitoring, set_performance_monitoring, 7);
+    msr_flag!(enhanced_speedstep, set_enhanced_speedstep, 16);
+    msr_flag!(monitor, set_monitor, 18);
+    msr_flag!(limit_cpuid, set_limit_cpuid, 22);
+    msr_flag!(xd_disable, set_xd_disable, 34);
+    msr_flag!(turbo_disable, set_turbo_disable, 38);
+}
+
+msr_register!(
+    /// What RDTSCP and RDPID return, by convention the CPU number.
+    TscAux, MSR::IA32_TSC_AUX);
+
+msr_register!(
+    /// Segment selector bases for SYSCALL and SYSRET.
+    Star, MSR::IA32_STAR);
+
+impl Star {
+    /// CS is this, SS this + 8 after SYSCALL.
+    pub fn syscall_selector(self) -> u16 {
+        (self.0 >> 32) as u16
+    }
+
+    /// CS is this + 16, SS this + 8 after a 64-bit SYSRET.
+    pub fn sysret_selector(self) -> u16 {
+        (self.0 >> 48) as u16
+    }
+
+    pub fn set_selectors(self, syscall: u16, sysret: u16) -> Self {
+        Star((self.0 & 0xffffffff) | ((syscall as u64) << 32) | ((sysret as u64) << 48))
+    }
+}
+
+msr_register!(
+    /// SYSCALL entry point in 64-bit mode.
+    Lstar, MSR::IA32_LSTAR);
+
+msr_register!(
+    /// RFLAGS bits cleared on SYSCALL.
+    Sfmask, MSR::IA32_FMASK);
+
+msr_register!(
+    /// Swapped with GS_BASE by SWAPGS.
+    KernelGsBase, MSR::KERNEL_GS_BASE);
+
+msr_register!(MtrrCap, MSR::IA32_MTRRCAP);
+
+impl MtrrCap {
+    /// Number of variable range MTRRs.
+    pub fn variable_count(self) -> u32 {
+        (self.0 & 0xff) as u32
+    }
+
+    pub fn fixed_supported(self) -> bool {
+        self.0 & (1 << 8) != 0
+    }
+
+    pub fn write_combining_supported(self) -> bool {
+        self.0 & (1 << 10) != 0
+    }
+
+    pub fn smrr_supported(self) -> bool {
+        self.0 & (1 << 11) != 0
+    }
+}
+
+msr_register!(MtrrDefType, MSR::IA32_MTRR_DEF_TYPE);
+
+impl MtrrDefType {
+    msr_flag!(fixed_enabled, set_fixed_enabled, 10);
+    msr_flag!(enabled, set_enabled, 11);
+
+    /// Memory type outside of all ranges.
+    pub fn default_type(self) -> Option<MemoryType> {
+        MemoryType::from_bits(self.0 & 0xff)
+    }
+
+    pub fn set_default_type(self, memory_type: MemoryType) -> Self {
+        MtrrDefType((self.0 & !0xff) | memory_type as u64)
+    }
+}
+
+/// A variable range MTRR, IA32_MTRR_PHYSBASEn and IA32_MTRR_PHYSMASKn.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MtrrRange {
+    pub base: u64,
+    pub mask: u64,
+}
+
+impl MtrrRange {
+    const ADDRESS_MASK: u64 = !0xfff;
+    const VALID: u64 = 1 << 11;
+
+    pub unsafe fn read(index: u32) -> Self {
+        MtrrRange {
+            base: MSR::read_raw(MSR::mtrr_physbase(index)),
+            mask: MSR::read_raw(MSR::mtrr_physmask(index)),
+        }
+    }
+
+    /// MTRRs have to be disabled in IA32_MTRR_DEF_TYPE while ranges change.
+    pub unsafe fn write(self, index: u32) {
+        MSR::write_raw(MSR::mtrr_physbase(index), self.base);
+        MSR::write_raw(MSR::mtrr_physmask(index), self.mask);
+    }
+
+    /// A range of `size` bytes, a power of two of at least 4 KiB, with
+    /// `address` aligned to it. None for other sizes or unaligned addresses.
+    pub fn new(address: u64, size: u64, memory_type: MemoryType) -> Option<Self> {
+        if !size.is_power_of_two() || size & MtrrRange::ADDRESS_MASK == 0 ||
+           address & (size - 1) != 0 {
+            return None;
+        }
+
+        let mask = !(size - 1) & physical_address_mask() & MtrrRange::ADDRESS_MASK;
+
+        Some(MtrrRange {
+            base: address | memory_type as u64,
+            mask: mask | MtrrRange::VALID,
+        })
+    }
+
+    pub fn valid(self) -> bool {
+        self.mask & MtrrRange::VALID != 0
+    }
+
+    pub fn address(self) -> u64 {
+        self.base & MtrrRange::ADDRESS_MASK & physical_address_mask()
+    }
+
+    /// Bytes covered, for the usual contiguous masks.
+    pub fn size(self) -> u64 {
+        let mask = self.mask & MtrrRange::ADDRESS_MASK & physical_address_mask();
+        (!mask & physical_address_mask()) + 1
+    }
+
+    pub fn memory_type(self) -> Option<MemoryType> {
+        MemoryType::from_bits(self.base & 0xff)
+    }
+}
+
+/// The 88 fixed range MTRRs below 1 MiB, 8 types per MSR.
+pub struct FixedMtrrs;
+
+impl FixedMtrrs {
+    /* (MSR, start, bytes per type) */
+    const RANGES: [(MSR, u64, u64); 11] = [
+        (MSR::IA32_MTRR_FIX64K_00000, 0x00000, 0x10000),
+        (MSR::IA32_MTRR_FIX16K_80000, 0x80000, 0x4000),
+        (MSR::IA32_MTRR_FIX16K_A0000, 0xa0000, 0x4000),
+        (MSR::IA32_MTRR_FIX4K_C0000, 0xc0000, 0x1000),
+        (MSR::IA32_MTRR_FIX4K_C8000, 0xc8000, 0x1000),
+        (MSR::IA32_MTRR_FIX4K_D0000, 0xd0000, 0x1000),
+        (MSR::IA32_MTRR_FIX4K_D8000, 0xd8000, 0x1000),
+        (MSR::IA32_MTRR_FIX4K_E0000, 0xe0000, 0x1000),
+        (MSR::IA32_MTRR_FIX4K_E8000, 0xe8000, 0x1000),
+        (MSR::IA32_MTRR_FIX4K_F0000, 0xf0000, 0x1000),
+        (MSR::IA32_MTRR_FIX4K_F8000, 0xf8000, 0x1000),
+    ];
+
+    /// Memory type of `address`, below 1 MiB.
+    pub unsafe fn memory_type(address: u64) -> Option<MemoryType> {
+        FixedMtrrs::RANGES.iter()
+            .rev()
+            .find(|&&(_, start, _)| address >= start)
+            .filter(|_| address < 0x100000)
+            .and_then(|&(msr, start, step)| {
+                let index = (address - start) / step;
+                MemoryType::from_bits((msr.read() >> (index * 8)) & 0xff)
+            })
+    }
+}
@@ -1,15 +1,16 @@
 use core::fmt;
 use core::fmt::Write;
 
+use crate::console;
+use crate::sync::IrqSpinLock;
+
+static WRITER: IrqSpinLock<Writer> = IrqSpinLock::new(Writer);
+
 pub struct Writer;
 
 impl Writer {
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            unsafe {
-                x86_64::instructions::port::PortWrite::write_to_port(0x3f8, byte as u8);
-            }
-        }
+        console::write(s.as_bytes());
     }
 }
 
@@ -33,5 +34,5 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    Writer.write_fmt(args).unwrap();
+    WRITER.lock().write_fmt(args).unwrap();
 }
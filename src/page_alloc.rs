@@ -1,3 +1,5 @@
+use core::ops::Range;
+
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use bootloader::BootInfo;
 use x86_64::structures::paging::FrameAllocator;
@@ -5,12 +7,19 @@ use x86_64::structures::paging::PhysFrame;
 use x86_64::structures::paging::Size4KiB;
 use x86_64::PhysAddr;
 
-static mut ALLOCATOR: Option<BootInfoFrameAllocator> = None;
+use crate::sync::{IrqSpinLock, IrqSpinLockGuard, Once};
+
+static ALLOCATOR: Once<IrqSpinLock<BootInfoFrameAllocator>> = Once::new();
+
+/* Frames skipped by `allocate_contiguous`, as indices into usable_frames() */
+const SPARE_RANGES: usize = 8;
+const NO_SPARE: Range<usize> = 0..0;
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    spare: [Range<usize>; SPARE_RANGES],
 }
 
 impl BootInfoFrameAllocator {
@@ -23,6 +32,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            spare: [NO_SPARE; SPARE_RANGES],
         }
     }
 
@@ -38,22 +48,86 @@ impl BootInfoFrameAllocator {
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Allocates `count` physically contiguous frames and returns the first.
+    ///
+    /// Frames skipped to find the run are handed out by later calls to
+    /// `allocate_frame`. Fails if there is no such run, or if too many
+    /// skipped ranges are pending already.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+
+        let mut start = self.next;
+        let mut run = 0;
+        let mut last = None;
+        let mut found = None;
+
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            let addr = frame.start_address().as_u64();
+
+            if last == Some(addr.wrapping_sub(4096)) {
+                run += 1;
+            } else {
+                start = index;
+                run = 1;
+            }
+            last = Some(addr);
+
+            if run == count {
+                found = Some(start);
+                break;
+            }
+        }
+
+        let start = found?;
+
+        if start > self.next {
+            let slot = self.spare.iter().position(|range| range.is_empty())?;
+            self.spare[slot] = self.next..start;
+        }
+
+        self.next = start + count;
+        self.usable_frames().nth(start)
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(range) = self.spare.iter_mut().find(|range| !range.is_empty()) {
+            let index = range.start;
+
+            range.start += 1;
+            return self.usable_frames().nth(index);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
-pub fn page_alloc() -> &'static mut BootInfoFrameAllocator {
-    unsafe { ALLOCATOR.as_mut().unwrap() }
+/// Locks and returns the global frame allocator.
+///
+/// Interrupts stay disabled until the returned guard is dropped, so keep it
+/// short-lived.
+pub fn page_alloc() -> IrqSpinLockGuard<'static, BootInfoFrameAllocator> {
+    ALLOCATOR.get().unwrap().lock()
 }
 
 pub fn page_alloc_init(boot_info: &'static BootInfo) {
-    unsafe {
-        ALLOCATOR.replace(BootInfoFrameAllocator::init(&boot_info.memory_map));
+    ALLOCATOR.call_once(|| {
+        IrqSpinLock::new(unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) })
+    });
+}
+
+/// `page_alloc()` as a `FrameAllocator` that can be kept around, each
+/// allocation takes the lock.
+pub struct PageAllocFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for PageAllocFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        page_alloc().allocate_frame()
     }
 }
@@ -25,6 +25,8 @@ const CMD_BUF_SLAVE: u8 = 0x8;
 const CMD_BUF_MASTER: u8 = 0xc;
 const CMD_SFNM: u8 = 0x10;
 
+const CASCADE_IRQ: u32 = 2;
+
 const MASTER_VECTOR_OFFSET: u8 = 0x20;
 const SLAVE_VECTOR_OFFSET: u8 = 0x28;
 
@@ -100,7 +102,7 @@ impl InterruptController for PIC {
 
     fn eoi(irq: u32) {
         unsafe {
-            if irq > 8 {
+            if irq >= 8 {
                 PortWrite::write_to_port(SLAVE_COMMAND, 0x20 as u8);
             }
 
@@ -116,7 +118,7 @@ impl InterruptController for PIC {
         let data: u16;
         let mask: u8;
 
-        if irq > 8 {
+        if irq >= 8 {
             data = SLAVE_DATA;
             mask = 1 << ((irq as u8) - 8);
         } else {
@@ -134,7 +136,7 @@ impl InterruptController for PIC {
         let data: u16;
         let mask: u8;
 
-        if irq > 8 {
+        if irq >= 8 {
             data = SLAVE_DATA;
             mask = 1 << ((irq as u8) - 8);
         } else {
@@ -146,5 +148,10 @@ impl InterruptController for PIC {
             let value: u8 = PortRead::read_from_port(data);
             PortWrite::write_to_port(data as u16, !mask & value);
         }
+
+        /* The slave is only heard through the cascade input */
+        if irq >= 8 {
+            PIC::unmask(CASCADE_IRQ);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,260 @@
+#[allow(dead_code)]
+
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+use crate::apic::APIC;
+use crate::interrupt_controller::InterruptController;
+use crate::pic::PIC;
+use crate::sync::SpinLock;
+use crate::time::ClockEvent;
+use crate::tsc::TSC;
+
+const CHANNEL0_DATA: u16    = 0x40;
+const CHANNEL1_DATA: u16    = 0x41;
+const CHANNEL2_DATA: u16    = 0x42;
+const COMMAND: u16          = 0x43;
+
+/* Port 0x61, channel 2 gate and output */
+const CHANNEL2_CONTROL: u16 = 0x61;
+const CHANNEL2_GATE: u8     = 1 << 0;
+const SPEAKER_ENABLE: u8    = 1 << 1;
+const CHANNEL2_OUT: u8      = 1 << 5;
+
+/* Command register: channel select */
+const SELECT_CHANNEL0: u8   = 0x0 << 6;
+const SELECT_CHANNEL1: u8   = 0x1 << 6;
+const SELECT_CHANNEL2: u8   = 0x2 << 6;
+const SELECT_READBACK: u8   = 0x3 << 6;
+
+/* Command register: access mode */
+const ACCESS_LATCH: u8      = 0x0 << 4;
+const ACCESS_LOBYTE: u8     = 0x1 << 4;
+const ACCESS_HIBYTE: u8     = 0x2 << 4;
+const ACCESS_LOHIBYTE: u8   = 0x3 << 4;
+
+/* Command register: operating mode */
+const MODE_TERMINAL_COUNT: u8   = 0x0 << 1;
+const MODE_ONE_SHOT: u8         = 0x1 << 1;
+const MODE_RATE_GENERATOR: u8   = 0x2 << 1;
+const MODE_SQUARE_WAVE: u8      = 0x3 << 1;
+const MODE_SW_STROBE: u8        = 0x4 << 1;
+const MODE_HW_STROBE: u8        = 0x5 << 1;
+
+const BINARY: u8 = 0x0;
+
+/* Read-back command */
+const READBACK_NO_COUNT: u8     = 1 << 5;
+const READBACK_NO_STATUS: u8    = 1 << 4;
+const READBACK_CHANNEL0: u8     = 1 << 1;
+
+/* Serializes the multi-byte sequences on the command/data ports */
+static LOCK: SpinLock<()> = SpinLock::new(());
+
+pub struct PIT;
+
+impl PIT {
+    /// Input clock of all three channels in Hz.
+    pub const FREQUENCY: u64 = 1193182;
+
+    pub const IRQ: u32 = 0;
+
+    fn program(command: u8, data: u16, count: u16) {
+        unsafe {
+            PortWrite::write_to_port(COMMAND, command);
+            PortWrite::write_to_port(data, count as u8);
+            PortWrite::write_to_port(data, (count >> 8) as u8);
+        }
+    }
+
+    /* The divisor closest to `hz`, clamped to what mode 2 can count: a
+     * reload value of 1 is illegal there and 0 would mean 65536 */
+    fn divisor(hz: u32) -> Option<u16> {
+        if hz == 0 {
+            return None;
+        }
+
+        let divisor = PIT::FREQUENCY / (hz as u64);
+        Some(divisor.max(2).min(0xffff) as u16)
+    }
+
+    /// Programs channel 0 as a rate generator (mode 2) firing IRQ0 every
+    /// `divisor` input clocks.
+    pub fn set_rate_generator(divisor: u16) {
+        let _guard = LOCK.lock();
+
+        PIT::program(SELECT_CHANNEL0 | ACCESS_LOHIBYTE | MODE_RATE_GENERATOR | BINARY,
+                     CHANNEL0_DATA, divisor);
+    }
+
+    /// Same as `set_rate_generator` with the divisor closest to `hz`, which
+    /// is clamped to 18-596591 Hz. Fails for 0 Hz.
+    pub fn set_periodic(hz: u32) -> Option<()> {
+        PIT::set_rate_generator(PIT::divisor(hz)?);
+        Some(())
+    }
+
+    /// Programs channel 0 to raise IRQ0 once after `count` input clocks
+    /// (mode 0, interrupt on terminal count).
+    pub fn set_oneshot(count: u16) {
+        let _guard = LOCK.lock();
+
+        PIT::program(SELECT_CHANNEL0 | ACCESS_LOHIBYTE | MODE_TERMINAL_COUNT | BINARY,
+                     CHANNEL0_DATA, count);
+    }
+
+    /// Latches and reads the current count of channel 0.
+    pub fn read_counter() -> u16 {
+        let _guard = LOCK.lock();
+
+        unsafe {
+            PortWrite::write_to_port(COMMAND, SELECT_CHANNEL0 | ACCESS_LATCH);
+
+            let low: u8 = PortRead::read_from_port(CHANNEL0_DATA);
+            let high: u8 = PortRead::read_from_port(CHANNEL0_DATA);
+
+            (low as u16) | ((high as u16) << 8)
+        }
+    }
+
+    /// Reads the status byte of channel 0 through the read-back command.
+    pub fn read_status() -> u8 {
+        let _guard = LOCK.lock();
+
+        unsafe {
+            PortWrite::write_to_port(COMMAND, SELECT_READBACK | READBACK_NO_COUNT | READBACK_CHANNEL0);
+            PortRead::read_from_port(CHANNEL0_DATA)
+        }
+    }
+
+    /// Busy-waits for `count` input clocks using channel 2.
+    ///
+    /// Channel 2 is gated through port 0x61 and its output can be polled
+    /// there, so this needs neither interrupts nor channel 0. The speaker is
+    /// kept disconnected.
+    pub fn wait_ticks(count: u16) {
+        let _guard = LOCK.lock();
+
+        unsafe {
+            let control: u8 = PortRead::read_from_port(CHANNEL2_CONTROL);
+
+            /* Stop the counter while it is reprogrammed */
+            PortWrite::write_to_port(CHANNEL2_CONTROL, control & !(CHANNEL2_GATE | SPEAKER_ENABLE));
+
+            PIT::program(SELECT_CHANNEL2 | ACCESS_LOHIBYTE | MODE_TERMINAL_COUNT | BINARY,
+                         CHANNEL2_DATA, count);
+
+            /* Raising the gate starts the count, OUT goes high at zero */
+            PortWrite::write_to_port(CHANNEL2_CONTROL, (control & !SPEAKER_ENABLE) | CHANNEL2_GATE);
+
+            loop {
+                let status: u8 = PortRead::read_from_port(CHANNEL2_CONTROL);
+                if (status & CHANNEL2_OUT) != 0 {
+                    break;
+                }
+            }
+
+            PortWrite::write_to_port(CHANNEL2_CONTROL, control);
+        }
+    }
+
+    pub fn udelay(us: u64) {
+        let mut ticks = ((us as u128 * PIT::FREQUENCY as u128) / 1_000_000) as u64;
+
+        while ticks > 0 {
+            let chunk = if ticks > 0xffff { 0xffff } else { ticks };
+
+            PIT::wait_ticks(chunk as u16);
+            ticks -= chunk;
+        }
+    }
+
+    pub fn mdelay(ms: u64) {
+        PIT::udelay(ms * 1000);
+    }
+
+    /// Measures the TSC frequency in Hz over `ms` milliseconds of channel 2.
+    /// Fails for 0 ms, or if the TSC did not move.
+    pub fn calibrate_tsc(ms: u64) -> Option<u64> {
+        if ms == 0 {
+            return None;
+        }
+
+        let start = TSC::read();
+        PIT::mdelay(ms);
+        let end = TSC::read();
+
+        match end.wrapping_sub(start) * 1000 / ms {
+            0 => None,
+            hz => Some(hz),
+        }
+    }
+
+    /// Measures how many APIC timer ticks elapse per second with the current
+    /// divide configuration.
+    ///
+    /// The APIC timer is left in one-shot mode, stopped. Fails for 0 ms, or
+    /// if the timer did not count.
+    pub fn calibrate_apic_timer(ms: u64) -> Option<u64> {
+        if ms == 0 {
+            return None;
+        }
+
+        APIC::set_timer_oneshot_mode();
+        APIC::set_timer_oneshot(0xffffffff);
+        PIT::mdelay(ms);
+        let remaining = APIC::timer_current();
+        APIC::set_timer_oneshot(0);
+
+        match ((0xffffffff - remaining) as u64) * 1000 / ms {
+            0 => None,
+            hz => Some(hz),
+        }
+    }
+}
+
+/// Channel 0 on IRQ0 as a `ClockEvent`, for when there is nothing better.
+pub struct PITTimer;
+
+impl PITTimer {
+    fn count(ticks: u64) -> u16 {
+        if ticks > 0xffff {
+            0xffff
+        } else if ticks == 0 {
+            1
+        } else {
+            ticks as u16
+        }
+    }
+}
+
+impl ClockEvent for PITTimer {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn frequency(&self) -> u64 {
+        PIT::FREQUENCY
+    }
+
+    /// Longer delays are cut to the 16-bit counter, the timer code simply
+    /// re-arms when the interrupt comes in early.
+    fn set_oneshot(&self, ticks: u64) {
+        PIT::set_oneshot(PITTimer::count(ticks));
+        PIC::unmask(PIT::IRQ);
+    }
+
+    /* Mode 2 can not count from 1 */
+    fn set_periodic(&self, ticks: u64) {
+        PIT::set_rate_generator(PITTimer::count(ticks).max(2));
+        PIC::unmask(PIT::IRQ);
+    }
+
+    /// The PIT can not be stopped, IRQ0 is masked instead.
+    fn stop(&self) {
+        PIC::mask(PIT::IRQ);
+    }
+}
@@ -0,0 +1,332 @@
+#[allow(dead_code)]
+
+use core::fmt;
+use x86_64::instructions::port::{PortRead, PortWrite};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::acpi::ACPI;
+use crate::idt;
+use crate::interrupt_controller::InterruptController;
+use crate::ioapic::IOAPIC;
+use crate::pic::PIC;
+use crate::sync::lockdep;
+use crate::sync::{IrqSpinLock, Once};
+use crate::vm::VM;
+
+const CMOS_INDEX: u16   = 0x70;
+const CMOS_DATA: u16    = 0x71;
+
+/* Setting this bit in the index port masks NMIs */
+const NMI_DISABLE: u8   = 0x80;
+
+/* RTC registers */
+const SECONDS: u8       = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8       = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8         = 0x04;
+const HOURS_ALARM: u8   = 0x05;
+const DAY_OF_WEEK: u8   = 0x06;
+const DAY: u8           = 0x07;
+const MONTH: u8         = 0x08;
+const YEAR: u8          = 0x09;
+const STATUS_A: u8      = 0x0a;
+const STATUS_B: u8      = 0x0b;
+const STATUS_C: u8      = 0x0c;
+const STATUS_D: u8      = 0x0d;
+
+/* Status register A */
+const UPDATE_IN_PROGRESS: u8    = 1 << 7;
+const RATE_MASK: u8             = 0x0f;
+
+/* Status register B */
+const SET: u8                   = 1 << 7;
+const PERIODIC_ENABLE: u8       = 1 << 6;
+const ALARM_ENABLE: u8          = 1 << 5;
+const UPDATE_ENDED_ENABLE: u8   = 1 << 4;
+const BINARY_MODE: u8           = 1 << 2;
+const HOUR_24: u8               = 1 << 1;
+
+/* Hours register in 12 hour mode */
+const HOUR_PM: u8               = 1 << 7;
+
+/* Alarm fields with the top two bits set match any value */
+const ALARM_DONT_CARE: u8       = 0xc0;
+
+/* FADT offset of the CMOS century register index */
+const FADT_CENTURY: u64         = 108;
+
+static CENTURY: Once<u8> = Once::new();
+static HANDLER: IrqSpinLock<Option<fn(u8)>> = IrqSpinLock::new(None);
+
+/* CMOS index/data accesses have to stay paired */
+static LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+pub struct RTC;
+
+impl RTC {
+    pub const IRQ: u32 = 8;
+    /// Vector IRQ8 arrives on with the default PIC offsets.
+    pub const VECTOR: u8 = 40;
+
+    /* Interrupt flags returned by `RTC::interrupt` (status register C) */
+    pub const INTERRUPT_UPDATE_ENDED: u8    = 1 << 4;
+    pub const INTERRUPT_ALARM: u8           = 1 << 5;
+    pub const INTERRUPT_PERIODIC: u8        = 1 << 6;
+    pub const INTERRUPT_REQUEST: u8         = 1 << 7;
+
+    fn read_register(register: u8) -> u8 {
+        unsafe {
+            PortWrite::write_to_port(CMOS_INDEX, register | NMI_DISABLE);
+            let value = PortRead::read_from_port(CMOS_DATA);
+            PortWrite::write_to_port(CMOS_INDEX, STATUS_D);
+            value
+        }
+    }
+
+    fn write_register(register: u8, value: u8) {
+        unsafe {
+            PortWrite::write_to_port(CMOS_INDEX, register | NMI_DISABLE);
+            PortWrite::write_to_port(CMOS_DATA, value);
+            PortWrite::write_to_port(CMOS_INDEX, STATUS_D);
+        }
+    }
+
+    fn century_register() -> u8 {
+        *CENTURY.call_once(|| {
+            match ACPI::find_table(b"FACP") {
+                Some(fadt) => unsafe {
+                    (VM::phys_to_virt(fadt + FADT_CENTURY) as *const u8).read_volatile()
+                },
+                None => 0,
+            }
+        })
+    }
+
+    fn from_bcd(value: u8) -> u8 {
+        (value & 0x0f) + ((value >> 4) * 10)
+    }
+
+    fn to_bcd(value: u8) -> u8 {
+        ((value / 10) << 4) | (value % 10)
+    }
+
+    fn update_in_progress() -> bool {
+        (RTC::read_register(STATUS_A) & UPDATE_IN_PROGRESS) != 0
+    }
+
+    /* Raw register values, (seconds, minutes, hours, day, month, year, century) */
+    fn read_raw(century: u8) -> [u8; 7] {
+        while RTC::update_in_progress() {}
+
+        [
+            RTC::read_register(SECONDS),
+            RTC::read_register(MINUTES),
+            RTC::read_register(HOURS),
+            RTC::read_register(DAY),
+            RTC::read_register(MONTH),
+            RTC::read_register(YEAR),
+            if century != 0 { RTC::read_register(century) } else { 0 },
+        ]
+    }
+
+    /// Reads the current date and time.
+    ///
+    /// The registers are read until two reads in a row agree, so an update
+    /// that starts while reading can not produce a torn value.
+    pub fn read() -> DateTime {
+        let century_register = RTC::century_register();
+        let _guard = LOCK.lock();
+
+        let mut raw = RTC::read_raw(century_register);
+        loop {
+            let again = RTC::read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let status = RTC::read_register(STATUS_B);
+        let binary = (status & BINARY_MODE) != 0;
+        let convert = |value: u8| if binary { value } else { RTC::from_bcd(value) };
+
+        let pm = (raw[2] & HOUR_PM) != 0;
+        let mut hour = convert(raw[2] & !HOUR_PM);
+        if (status & HOUR_24) == 0 {
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let century = if century_register != 0 { convert(raw[6]) as u16 } else { 20 };
+
+        DateTime {
+            year: century * 100 + convert(raw[5]) as u16,
+            month: convert(raw[4]),
+            day: convert(raw[3]),
+            hour,
+            minute: convert(raw[1]),
+            second: convert(raw[0]),
+        }
+    }
+
+    /// Sets the clock, in whatever format (BCD/binary, 12/24h) it is in.
+    pub fn set(time: &DateTime) {
+        let century_register = RTC::century_register();
+        let _guard = LOCK.lock();
+
+        let status = RTC::read_register(STATUS_B);
+        let binary = (status & BINARY_MODE) != 0;
+        let convert = |value: u8| if binary { value } else { RTC::to_bcd(value) };
+
+        let hour = if (status & HOUR_24) != 0 {
+            convert(time.hour)
+        } else {
+            let hour12 = match time.hour % 12 { 0 => 12, hour => hour };
+            convert(hour12) | if time.hour >= 12 { HOUR_PM } else { 0 }
+        };
+
+        /* Stop updates while the registers are inconsistent */
+        RTC::write_register(STATUS_B, status | SET);
+
+        RTC::write_register(SECONDS, convert(time.second));
+        RTC::write_register(MINUTES, convert(time.minute));
+        RTC::write_register(HOURS, hour);
+        RTC::write_register(DAY, convert(time.day));
+        RTC::write_register(MONTH, convert(time.month));
+        RTC::write_register(YEAR, convert((time.year % 100) as u8));
+        if century_register != 0 {
+            RTC::write_register(century_register, convert((time.year / 100) as u8));
+        }
+
+        RTC::write_register(STATUS_B, status & !SET);
+    }
+
+    fn update_status_b(set: u8, clear: u8) {
+        let _guard = LOCK.lock();
+
+        let status = RTC::read_register(STATUS_B);
+        RTC::write_register(STATUS_B, (status & !clear) | set);
+
+        /* Throw away anything pending so the next edge is seen */
+        RTC::read_register(STATUS_C);
+    }
+
+    /// Enables the periodic interrupt at 32768 >> (`rate` - 1) Hz, `rate`
+    /// being between 3 (8192 Hz) and 15 (2 Hz).
+    pub fn enable_periodic(rate: u8) {
+        let rate = if rate < 3 { 3 } else if rate > 15 { 15 } else { rate };
+
+        {
+            let _guard = LOCK.lock();
+            let status = RTC::read_register(STATUS_A);
+            RTC::write_register(STATUS_A, (status & !RATE_MASK) | rate);
+        }
+
+        RTC::update_status_b(PERIODIC_ENABLE, 0);
+    }
+
+    /// Enables the alarm interrupt. `None` matches every value of that field.
+    pub fn enable_alarm(hour: Option<u8>, minute: Option<u8>, second: Option<u8>) {
+        {
+            let _guard = LOCK.lock();
+            let status = RTC::read_register(STATUS_B);
+            let binary = (status & BINARY_MODE) != 0;
+            let convert = |value: Option<u8>| match value {
+                Some(value) if binary => value,
+                Some(value) => RTC::to_bcd(value),
+                None => ALARM_DONT_CARE,
+            };
+
+            RTC::write_register(SECONDS_ALARM, convert(second));
+            RTC::write_register(MINUTES_ALARM, convert(minute));
+            RTC::write_register(HOURS_ALARM, convert(hour));
+        }
+
+        RTC::update_status_b(ALARM_ENABLE, 0);
+    }
+
+    /// Enables the interrupt raised once a second after each clock update.
+    pub fn enable_update_ended() {
+        RTC::update_status_b(UPDATE_ENDED_ENABLE, 0);
+    }
+
+    pub fn disable_interrupts() {
+        RTC::update_status_b(0, PERIODIC_ENABLE | ALARM_ENABLE | UPDATE_ENDED_ENABLE);
+    }
+
+    /// Unmasks IRQ8 on the PIC, interrupts arrive on `RTC::VECTOR`.
+    pub fn route_pic() {
+        idt::set_handler(RTC::VECTOR, pic_handler);
+        PIC::unmask(RTC::IRQ);
+    }
+
+    /// Routes IRQ8 through the IOAPIC to `vector` on the current CPU.
+    pub fn route_ioapic(vector: u8) -> Option<()> {
+        idt::set_handler(vector, ioapic_handler);
+        IOAPIC::route_isa(RTC::IRQ as u8, vector)
+    }
+
+    /// Called for every RTC interrupt, `handler` gets the flags of
+    /// `RTC::interrupt`.
+    pub fn set_handler(handler: Option<fn(u8)>) {
+        *HANDLER.lock() = handler;
+    }
+
+    /// Acknowledges the interrupt and returns which events were pending.
+    ///
+    /// Reading status register C is what re-arms IRQ8, so this has to run
+    /// for every RTC interrupt.
+    pub fn interrupt() -> u8 {
+        let flags = {
+            let _guard = LOCK.lock();
+            RTC::read_register(STATUS_C)
+        };
+
+        let handler = *HANDLER.lock();
+        if let Some(handler) = handler {
+            handler(flags);
+        }
+
+        flags
+    }
+}
+
+extern "x86-interrupt" fn pic_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    lockdep::irq_enter();
+    RTC::interrupt();
+
+    PIC::eoi(RTC::IRQ);
+    lockdep::irq_exit();
+}
+
+extern "x86-interrupt" fn ioapic_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    lockdep::irq_enter();
+    RTC::interrupt();
+
+    IOAPIC::eoi(RTC::IRQ);
+    lockdep::irq_exit();
+}
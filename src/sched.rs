@@ -0,0 +1,559 @@
+//! Kernel threads and a preemptive round-robin scheduler.
+//!
+//! Every CPU that calls `init()` gets its own run queue and idle thread; the
+//! code that called `init()` becomes a thread itself. Threads stay on the CPU
+//! they were spawned on. A periodic software timer marks the running thread
+//! for preemption, which happens on the way out of the timer interrupt.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, spin_loop_hint};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+use crate::cpu::{CPU, MAX_CPUS};
+use crate::fpu::FpuState;
+use crate::sync::{lockdep, IrqSpinLock, WaitQueue};
+use crate::time;
+use crate::vm::VM;
+
+pub const MAX_THREADS: usize = 64;
+
+/* 16 KiB stacks */
+const STACK_PAGES: u64 = 4;
+
+const TIME_SLICE: Duration = Duration::from_millis(10);
+
+const NO_THREAD: usize = usize::MAX;
+
+/* RFLAGS of a new thread, interrupts stay off until thread_start */
+const INITIAL_RFLAGS: u64 = 0x2;
+
+/* Saves the callee-saved registers and RFLAGS on the current stack, stores
+ * the stack pointer to *rdi and resumes the thread whose stack is in rsi. */
+global_asm!(r#"
+.global libos_switch_context
+libos_switch_context:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    pushfq
+    movq %rsp, (%rdi)
+    movq %rsi, %rsp
+    popfq
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    retq
+"#);
+
+extern "C" {
+    fn libos_switch_context(save: *mut u64, load: u64);
+}
+
+/// Handle of a thread. The generation tells it apart from later threads
+/// in the same slot, a stale handle refers to no thread.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ThreadId {
+    slot: usize,
+    generation: u32,
+}
+
+impl ThreadId {
+    /* As timer data, the slot in the low half */
+    fn pack(self) -> usize {
+        (self.generation as usize) << 32 | self.slot
+    }
+
+    fn unpack(data: usize) -> ThreadId {
+        ThreadId { slot: data & 0xffff_ffff, generation: (data >> 32) as u32 }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Free,
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+#[derive(Clone, Copy)]
+struct Thread {
+    state: State,
+    cpu: usize,
+    entry: fn(usize),
+    arg: usize,
+    /* 0 for the threads created from a boot stack */
+    stack_top: u64,
+    /* Bumped whenever the slot gets a new thread */
+    generation: u32,
+    /* Freed on exit instead of by join() */
+    detached: bool,
+    idle: bool,
+}
+
+fn nop(_arg: usize) {}
+
+impl Thread {
+    const EMPTY: Thread = Thread {
+        state: State::Free,
+        cpu: 0,
+        entry: nop,
+        arg: 0,
+        stack_top: 0,
+        generation: 0,
+        detached: false,
+        idle: false,
+    };
+}
+
+/* Only touched by the CPU the thread runs on, with interrupts disabled */
+struct Context {
+    rsp: u64,
+    fpu: FpuState,
+    locks: lockdep::HeldLocks,
+    /* Still running on its stack, cleared by whoever got switched to */
+    on_cpu: AtomicBool,
+}
+
+struct Contexts(UnsafeCell<[Context; MAX_THREADS]>);
+
+unsafe impl Sync for Contexts {}
+
+struct RunQueue {
+    threads: [u16; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const EMPTY: RunQueue = RunQueue {
+        threads: [0; MAX_THREADS],
+        head: 0,
+        len: 0,
+    };
+
+    fn push(&mut self, id: usize) {
+        let tail = (self.head + self.len) % MAX_THREADS;
+        self.threads[tail] = id as u16;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let id = self.threads[self.head] as usize;
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(id)
+    }
+}
+
+const CONTEXT: Context = Context {
+    rsp: 0,
+    fpu: FpuState::new(),
+    locks: lockdep::HeldLocks::EMPTY,
+    on_cpu: AtomicBool::new(false),
+};
+const QUEUE: IrqSpinLock<RunQueue> = IrqSpinLock::new(RunQueue::EMPTY);
+const THREAD_NONE: AtomicUsize = AtomicUsize::new(NO_THREAD);
+const FLAG_CLEAR: AtomicBool = AtomicBool::new(false);
+
+/* Lock order: THREADS, then RUNQUEUES */
+static THREADS: IrqSpinLock<[Thread; MAX_THREADS]> = IrqSpinLock::new([Thread::EMPTY; MAX_THREADS]);
+static CONTEXTS: Contexts = Contexts(UnsafeCell::new([CONTEXT; MAX_THREADS]));
+static RUNQUEUES: [IrqSpinLock<RunQueue>; MAX_CPUS] = [QUEUE; MAX_CPUS];
+
+static CURRENT: [AtomicUsize; MAX_CPUS] = [THREAD_NONE; MAX_CPUS];
+static IDLE: [AtomicUsize; MAX_CPUS] = [THREAD_NONE; MAX_CPUS];
+static NEED_RESCHED: [AtomicBool; MAX_CPUS] = [FLAG_CLEAR; MAX_CPUS];
+
+/* Threads in join(), per slot */
+const NO_JOINERS: WaitQueue = WaitQueue::new();
+static JOINERS: [WaitQueue; MAX_THREADS] = [NO_JOINERS; MAX_THREADS];
+
+fn context(id: usize) -> &'static mut Context {
+    unsafe { &mut (*CONTEXTS.0.get())[id] }
+}
+
+fn this_cpu() -> usize {
+    CPU::id()
+}
+
+fn current_id() -> usize {
+    CURRENT[this_cpu()].load(Ordering::Relaxed)
+}
+
+/// True once `init()` ran on this CPU.
+pub fn initialized() -> bool {
+    current_id() != NO_THREAD
+}
+
+pub fn current() -> ThreadId {
+    let slot = current_id();
+    ThreadId { slot, generation: THREADS.lock()[slot].generation }
+}
+
+/* Whether `thread` still names the thread in its slot */
+fn live(threads: &[Thread; MAX_THREADS], thread: ThreadId) -> bool {
+    thread.slot < MAX_THREADS &&
+        threads[thread.slot].state != State::Free &&
+        threads[thread.slot].generation == thread.generation
+}
+
+fn enqueue(threads: &mut [Thread; MAX_THREADS], id: usize) {
+    threads[id].state = State::Ready;
+    RUNQUEUES[threads[id].cpu].lock().push(id);
+}
+
+fn create(threads: &mut [Thread; MAX_THREADS], entry: fn(usize), arg: usize, cpu: usize) -> Option<usize> {
+    let id = threads.iter().position(|t| t.state == State::Free)?;
+
+    /* Stacks and FPU areas stay with their slot and are reused by the next
+     * thread */
+    let stack_top = match threads[id].stack_top {
+        0 => VM::alloc_stack(STACK_PAGES)?,
+        top => top,
+    };
+    threads[id].stack_top = stack_top;
+    context(id).fpu.alloc()?;
+
+    threads[id] = Thread {
+        state: State::Ready,
+        cpu,
+        entry,
+        arg,
+        stack_top,
+        generation: threads[id].generation.wrapping_add(1),
+        detached: false,
+        idle: false,
+    };
+
+    /* What libos_switch_context pops, returning into thread_start. The
+     * zero on top keeps the stack aligned like after a call. */
+    let frame = [
+        INITIAL_RFLAGS,
+        0, 0, 0, 0, 0, 0,
+        thread_start as u64,
+        0,
+    ];
+
+    let ctx = context(id);
+    ctx.rsp = stack_top - (frame.len() * 8) as u64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(frame.as_ptr(), ctx.rsp as *mut u64, frame.len());
+    }
+    ctx.fpu.reset();
+    ctx.locks = lockdep::HeldLocks::EMPTY;
+    ctx.on_cpu.store(false, Ordering::Relaxed);
+
+    Some(id)
+}
+
+/// Turns the running code into a thread of this CPU, creates the CPU's idle
+/// thread and starts the preemption tick. Needs `time::init()` first.
+pub fn init() -> Option<()> {
+    let cpu = this_cpu();
+
+    {
+        let mut threads = THREADS.lock();
+
+        let main = threads.iter().position(|t| t.state == State::Free)?;
+        context(main).fpu.alloc()?;
+        threads[main] = Thread {
+            state: State::Running,
+            cpu,
+            generation: threads[main].generation.wrapping_add(1),
+            ..Thread::EMPTY
+        };
+        context(main).on_cpu.store(true, Ordering::Relaxed);
+
+        let idle = create(&mut threads, idle_loop, 0, cpu)?;
+        threads[idle].idle = true;
+
+        IDLE[cpu].store(idle, Ordering::Relaxed);
+        CURRENT[cpu].store(main, Ordering::Relaxed);
+    }
+
+    time::add_periodic_timer(TIME_SLICE, tick, 0)?;
+    Some(())
+}
+
+/// Starts a thread running `entry(arg)` on the current CPU.
+pub fn spawn(entry: fn(usize), arg: usize) -> Option<ThreadId> {
+    spawn_on(this_cpu(), entry, arg)
+}
+
+/// Starts a thread on `cpu`. Fails if `cpu` did not call `init()`.
+pub fn spawn_on(cpu: usize, entry: fn(usize), arg: usize) -> Option<ThreadId> {
+    if cpu >= MAX_CPUS || IDLE[cpu].load(Ordering::Relaxed) == NO_THREAD {
+        return None;
+    }
+
+    let mut threads = THREADS.lock();
+    let id = create(&mut threads, entry, arg, cpu)?;
+
+    enqueue(&mut threads, id);
+    Some(ThreadId { slot: id, generation: threads[id].generation })
+}
+
+/* Picks the next thread of this CPU and switches to it. The caller has
+ * already moved the current thread out of Running, or wants it to keep
+ * running if there is nothing else. */
+fn schedule() {
+    let flags = CPU::irq_save();
+    let cpu = this_cpu();
+    let prev = CURRENT[cpu].load(Ordering::Relaxed);
+
+    let next = {
+        let mut threads = THREADS.lock();
+        let next = RUNQUEUES[cpu].lock().pop().unwrap_or_else(|| {
+            /* Nothing queued, a thread that is still runnable goes on */
+            if threads[prev].state == State::Running {
+                prev
+            } else {
+                IDLE[cpu].load(Ordering::Relaxed)
+            }
+        });
+
+        threads[next].state = State::Running;
+        next
+    };
+
+    if next != prev {
+        CURRENT[cpu].store(next, Ordering::Relaxed);
+
+        let from = context(prev);
+        let to = context(next);
+
+        to.on_cpu.store(true, Ordering::Relaxed);
+        PREVIOUS[cpu].store(prev, Ordering::Relaxed);
+        from.fpu.save();
+        to.fpu.restore();
+        lockdep::switch(&mut from.locks, &to.locks);
+
+        unsafe { libos_switch_context(&mut from.rsp, to.rsp) };
+
+        /* Back on prev, whoever ran before is off its stack now */
+        finish_switch();
+    }
+
+    CPU::irq_restore(flags);
+}
+
+/* The thread we switched away from is parked in PREVIOUS until the next
+ * thread is on its own stack. */
+static PREVIOUS: [AtomicUsize; MAX_CPUS] = [THREAD_NONE; MAX_CPUS];
+
+fn finish_switch() {
+    let previous = PREVIOUS[this_cpu()].swap(NO_THREAD, Ordering::Relaxed);
+
+    if previous != NO_THREAD {
+        context(previous).on_cpu.store(false, Ordering::Release);
+
+        /* Nobody joins a detached thread, its slot is free once it is off
+         * its stack */
+        let mut threads = THREADS.lock();
+        if threads[previous].detached && threads[previous].state == State::Exited {
+            threads[previous].state = State::Free;
+        }
+    }
+}
+
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+
+    let id = current_id();
+
+    let (entry, arg) = {
+        let threads = THREADS.lock();
+        (threads[id].entry, threads[id].arg)
+    };
+
+    CPU::irq_enable();
+    entry(arg);
+    exit();
+}
+
+fn idle_loop(_arg: usize) {
+    let cpu = this_cpu();
+
+    loop {
+        interrupts::disable();
+
+        if RUNQUEUES[cpu].lock().len > 0 {
+            interrupts::enable();
+            yield_now();
+        } else {
+            interrupts::enable_interrupts_and_hlt();
+        }
+    }
+}
+
+/// Gives up the CPU to the next ready thread, if there is one.
+pub fn yield_now() {
+    if !initialized() {
+        return;
+    }
+
+    let flags = CPU::irq_save();
+
+    {
+        let mut threads = THREADS.lock();
+        let id = current_id();
+
+        /* The idle thread is what runs when the queue is empty, never queued */
+        if !threads[id].idle {
+            enqueue(&mut threads, id);
+        }
+    }
+
+    schedule();
+    CPU::irq_restore(flags);
+}
+
+/// Marks the running thread as blocked. It keeps running until `block()`,
+/// but a `wake()` in between is not lost.
+pub(crate) fn prepare_block() {
+    let mut threads = THREADS.lock();
+    let id = current_id();
+
+    threads[id].state = State::Blocked;
+}
+
+/// Switches away from a thread that called `prepare_block()`, returns once
+/// it was woken.
+pub(crate) fn block() {
+    schedule();
+}
+
+/// Makes a blocked thread runnable again, no-op for any other state or a
+/// stale handle.
+pub fn wake(thread: ThreadId) {
+    let mut threads = THREADS.lock();
+
+    if live(&threads, thread) && threads[thread.slot].state == State::Blocked {
+        enqueue(&mut threads, thread.slot);
+    }
+}
+
+fn wake_timer(thread: usize) {
+    wake(ThreadId::unpack(thread));
+}
+
+/// Blocks the running thread for `duration`.
+pub fn sleep(duration: Duration) {
+    let flags = CPU::irq_save();
+
+    let thread = current();
+
+    prepare_block();
+    if time::add_timer(duration, wake_timer, thread.pack()).is_some() {
+        block();
+    } else {
+        wake(current());
+    }
+
+    CPU::irq_restore(flags);
+}
+
+/// Ends the running thread, its slot is released by `join()`, or right
+/// away if it was detached.
+pub fn exit() -> ! {
+    CPU::irq_disable();
+
+    {
+        let id = current_id();
+
+        THREADS.lock()[id].state = State::Exited;
+        JOINERS[id].wake_all();
+    }
+
+    schedule();
+    unreachable!();
+}
+
+/// Waits for `thread` to exit and releases it. The thread must not be the
+/// running one, nor detached. Any number of threads may join the same one,
+/// and joining a thread that is already gone returns right away.
+pub fn join(thread: ThreadId) {
+    assert_ne!(thread.slot, current_id(), "a thread can not join itself");
+
+    if thread.slot >= MAX_THREADS {
+        return;
+    }
+
+    JOINERS[thread.slot].wait_until(|| {
+        let threads = THREADS.lock();
+        !live(&threads, thread) || threads[thread.slot].state == State::Exited
+    });
+
+    release(thread);
+}
+
+/* Frees an exited `thread` once its CPU is off its stack. Whoever comes
+ * second finds the slot free, or already reused under a new generation. */
+fn release(thread: ThreadId) {
+    let flags = CPU::irq_save();
+
+    while context(thread.slot).on_cpu.load(Ordering::Acquire) {
+        if !live(&THREADS.lock(), thread) {
+            break;
+        }
+        spin_loop_hint();
+    }
+
+    let mut threads = THREADS.lock();
+    if live(&threads, thread) && threads[thread.slot].state == State::Exited {
+        threads[thread.slot].state = State::Free;
+    }
+
+    drop(threads);
+    CPU::irq_restore(flags);
+}
+
+/// Has `thread` release its slot when it exits, it can not be joined
+/// anymore. No-op for a stale handle.
+pub fn detach(thread: ThreadId) {
+    let flags = CPU::irq_save();
+    let exited = {
+        let mut threads = THREADS.lock();
+
+        if !live(&threads, thread) {
+            drop(threads);
+            CPU::irq_restore(flags);
+            return;
+        }
+
+        threads[thread.slot].detached = true;
+        threads[thread.slot].state == State::Exited
+    };
+
+    /* Exited before, finish_switch() may have seen it not detached yet */
+    if exited {
+        release(thread);
+    }
+
+    CPU::irq_restore(flags);
+}
+
+fn tick(_arg: usize) {
+    NEED_RESCHED[this_cpu()].store(true, Ordering::Relaxed);
+}
+
+/// Called at the end of the timer interrupt, after the EOI. Switches to the
+/// next thread once the running one used up its time slice.
+pub fn preempt() {
+    if initialized() && NEED_RESCHED[this_cpu()].swap(false, Ordering::Relaxed) {
+        yield_now();
+    }
+}
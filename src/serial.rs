@@ -0,0 +1,471 @@
+#[allow(dead_code)]
+
+use core::fmt;
+use core::sync::atomic::spin_loop_hint;
+use x86_64::instructions::port::{PortRead, PortWrite};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::apic::APIC;
+use crate::console::Console;
+use crate::idt;
+use crate::interrupt_controller::InterruptController;
+use crate::ioapic::IOAPIC;
+use crate::pic::PIC;
+use crate::sync::lockdep;
+use crate::sync::{IrqSpinLock, WaitQueue};
+
+const PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+
+/* UART registers, offsets from the base port */
+const DATA: u16         = 0;
+const IER: u16          = 1;
+const DIVISOR_LOW: u16  = 0;
+const DIVISOR_HIGH: u16 = 1;
+const FCR: u16          = 2;
+const LCR: u16          = 3;
+const MCR: u16          = 4;
+const LSR: u16          = 5;
+const SCRATCH: u16      = 7;
+
+/* Interrupt enable register */
+const IER_RX_AVAILABLE: u8  = 1 << 0;
+
+/* FIFO control register */
+const FCR_ENABLE: u8        = 1 << 0;
+const FCR_CLEAR_RX: u8      = 1 << 1;
+const FCR_CLEAR_TX: u8      = 1 << 2;
+const FCR_TRIGGER_14: u8    = 0x3 << 6;
+
+/* Line control register */
+const LCR_DATA_BITS_8: u8   = 0x3;
+const LCR_STOP_BITS_2: u8   = 1 << 2;
+const LCR_PARITY_ODD: u8    = 0x1 << 3;
+const LCR_PARITY_EVEN: u8   = 0x3 << 3;
+const LCR_PARITY_MARK: u8   = 0x5 << 3;
+const LCR_PARITY_SPACE: u8  = 0x7 << 3;
+const LCR_DLAB: u8          = 1 << 7;
+
+/* Modem control register */
+const MCR_DTR: u8           = 1 << 0;
+const MCR_RTS: u8           = 1 << 1;
+const MCR_OUT1: u8          = 1 << 2;
+/* Gates the UART interrupt line on PCs */
+const MCR_OUT2: u8          = 1 << 3;
+const MCR_LOOPBACK: u8      = 1 << 4;
+
+/* Line status register */
+const LSR_DATA_READY: u8    = 1 << 0;
+const LSR_OVERRUN: u8       = 1 << 1;
+const LSR_THR_EMPTY: u8     = 1 << 5;
+
+const UART_CLOCK: u32 = 115200;
+
+/* Bounds the wait for the transmitter, so a missing UART can not hang us */
+const TX_TIMEOUT: usize = 100000;
+
+const RX_BUFFER_SIZE: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SerialParity {
+    SERIAL_PARITY_NONE,
+    SERIAL_PARITY_ODD,
+    SERIAL_PARITY_EVEN,
+    SERIAL_PARITY_MARK,
+    SERIAL_PARITY_SPACE,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SerialConfig {
+    pub baud: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    /// 1 or 2.
+    pub stop_bits: u8,
+    pub parity: SerialParity,
+}
+
+impl Default for SerialConfig {
+    /// 115200 8N1.
+    fn default() -> Self {
+        SerialConfig {
+            baud: 115200,
+            data_bits: 8,
+            stop_bits: 1,
+            parity: SerialParity::SERIAL_PARITY_NONE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SerialError {
+    SERIAL_ERROR_NOT_PRESENT,
+    SERIAL_ERROR_INVALID_CONFIG,
+    /// The IRQ would arrive on a vector something else handles.
+    SERIAL_ERROR_VECTOR_IN_USE,
+}
+
+struct PortState {
+    initialized: bool,
+    /* The last init() found no UART */
+    absent: bool,
+    /* RX is buffered by the interrupt handler instead of polled */
+    interrupts: bool,
+    rx: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+    overruns: usize,
+}
+
+impl PortState {
+    const EMPTY: IrqSpinLock<PortState> = IrqSpinLock::new(PortState {
+        initialized: false,
+        absent: false,
+        interrupts: false,
+        rx: [0; RX_BUFFER_SIZE],
+        head: 0,
+        len: 0,
+        overruns: 0,
+    });
+
+    fn push(&mut self, byte: u8) {
+        if self.len == RX_BUFFER_SIZE {
+            self.overruns += 1;
+            return;
+        }
+
+        self.rx[(self.head + self.len) % RX_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.rx[self.head];
+        self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+const NO_READERS: WaitQueue = WaitQueue::new();
+
+static STATE: [IrqSpinLock<PortState>; 4] = [PortState::EMPTY; 4];
+static READERS: [WaitQueue; 4] = [NO_READERS; 4];
+
+/// One of the four legacy 16550 UARTs.
+///
+/// COM1 and COM3 share IRQ4, COM2 and COM4 IRQ3. With the default PIC
+/// offsets IRQ3 arrives on the IPI vector, so COM2 and COM4 interrupts only
+/// go through the IOAPIC.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SerialPort(usize);
+
+impl SerialPort {
+    pub const COM1: SerialPort = SerialPort(0);
+    pub const COM2: SerialPort = SerialPort(1);
+    pub const COM3: SerialPort = SerialPort(2);
+    pub const COM4: SerialPort = SerialPort(3);
+
+    fn read_register(&self, register: u16) -> u8 {
+        unsafe { PortRead::read_from_port(PORTS[self.0] + register) }
+    }
+
+    fn write_register(&self, register: u16, value: u8) {
+        unsafe { PortWrite::write_to_port(PORTS[self.0] + register, value) }
+    }
+
+    pub fn irq(&self) -> u32 {
+        if self.0 % 2 == 0 { 4 } else { 3 }
+    }
+
+    /// Vector the IRQ arrives on through the PIC.
+    pub fn vector(&self) -> u8 {
+        32 + self.irq() as u8
+    }
+
+    /// Programs line settings, enables and clears the FIFOs and checks in
+    /// loopback mode that a UART is actually there.
+    pub fn init(&self, config: SerialConfig) -> Result<(), SerialError> {
+        if config.baud == 0 || config.baud > UART_CLOCK || UART_CLOCK % config.baud != 0 ||
+           config.data_bits < 5 || config.data_bits > 8 ||
+           config.stop_bits < 1 || config.stop_bits > 2 {
+            return Err(SerialError::SERIAL_ERROR_INVALID_CONFIG);
+        }
+
+        let divisor = (UART_CLOCK / config.baud) as u16;
+
+        let mut lcr = config.data_bits - 5;
+        if config.stop_bits == 2 {
+            lcr |= LCR_STOP_BITS_2;
+        }
+        lcr |= match config.parity {
+            SerialParity::SERIAL_PARITY_NONE => 0,
+            SerialParity::SERIAL_PARITY_ODD => LCR_PARITY_ODD,
+            SerialParity::SERIAL_PARITY_EVEN => LCR_PARITY_EVEN,
+            SerialParity::SERIAL_PARITY_MARK => LCR_PARITY_MARK,
+            SerialParity::SERIAL_PARITY_SPACE => LCR_PARITY_SPACE,
+        };
+
+        let mut state = STATE[self.0].lock();
+
+        self.write_register(IER, 0);
+        self.write_register(LCR, LCR_DLAB);
+        self.write_register(DIVISOR_LOW, divisor as u8);
+        self.write_register(DIVISOR_HIGH, (divisor >> 8) as u8);
+        /* 8N1 for the loopback test, fewer data bits would mask the byte */
+        self.write_register(LCR, LCR_DATA_BITS_8);
+        self.write_register(FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_TRIGGER_14);
+
+        /* Whatever we send in loopback mode has to come back */
+        let mcr = self.read_register(MCR);
+        self.write_register(MCR, MCR_RTS | MCR_OUT1 | MCR_OUT2 | MCR_LOOPBACK);
+        self.write_register(DATA, 0xae);
+        if self.read_register(DATA) != 0xae {
+            self.write_register(MCR, mcr);
+            state.initialized = false;
+            state.absent = true;
+            return Err(SerialError::SERIAL_ERROR_NOT_PRESENT);
+        }
+
+        self.write_register(LCR, lcr);
+        self.write_register(MCR, MCR_DTR | MCR_RTS | MCR_OUT1 | MCR_OUT2);
+        if state.interrupts {
+            self.write_register(IER, IER_RX_AVAILABLE);
+        }
+
+        state.initialized = true;
+        state.absent = false;
+        Ok(())
+    }
+
+    pub fn initialized(&self) -> bool {
+        STATE[self.0].lock().initialized
+    }
+
+    /// The scratch register holds whatever was written, unless there is no
+    /// UART at all.
+    pub fn probe(&self) -> bool {
+        self.write_register(SCRATCH, 0x5a);
+        self.read_register(SCRATCH) == 0x5a
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        for _ in 0..TX_TIMEOUT {
+            if (self.read_register(LSR) & LSR_THR_EMPTY) != 0 {
+                break;
+            }
+            spin_loop_hint();
+        }
+
+        self.write_register(DATA, byte);
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+    }
+
+    fn poll(&self) -> Option<u8> {
+        if (self.read_register(LSR) & LSR_DATA_READY) != 0 {
+            Some(self.read_register(DATA))
+        } else {
+            None
+        }
+    }
+
+    /// Returns a received byte if there is one.
+    pub fn try_read_byte(&self) -> Option<u8> {
+        let mut state = STATE[self.0].lock();
+
+        if state.interrupts {
+            state.pop()
+        } else {
+            self.poll()
+        }
+    }
+
+    /// Waits for a byte. Sleeps if the RX interrupt is routed, spins on the
+    /// line status register otherwise.
+    pub fn read_byte(&self) -> u8 {
+        let mut byte = None;
+
+        if STATE[self.0].lock().interrupts {
+            READERS[self.0].wait_until(|| {
+                byte = self.try_read_byte();
+                byte.is_some()
+            });
+        } else {
+            while byte.is_none() {
+                byte = self.poll();
+                spin_loop_hint();
+            }
+        }
+
+        byte.unwrap()
+    }
+
+    /// Reads until a newline or `buffer` is full, echoing the input and
+    /// handling backspace. Returns the length without the newline.
+    pub fn read_line(&self, buffer: &mut [u8]) -> usize {
+        let mut len = 0;
+
+        while len < buffer.len() {
+            match self.read_byte() {
+                b'\r' | b'\n' => {
+                    self.write_bytes(b"\r\n");
+                    break;
+                },
+                /* Backspace and DEL */
+                0x08 | 0x7f => {
+                    if len > 0 {
+                        len -= 1;
+                        self.write_bytes(b"\x08 \x08");
+                    }
+                },
+                byte => {
+                    buffer[len] = byte;
+                    len += 1;
+                    self.write_byte(byte);
+                },
+            }
+        }
+
+        len
+    }
+
+    /// Bytes dropped because the RX buffer or the UART FIFO was full.
+    pub fn overruns(&self) -> usize {
+        STATE[self.0].lock().overruns
+    }
+
+    fn enable_interrupts(&self) {
+        let mut state = STATE[self.0].lock();
+
+        state.interrupts = true;
+        /* Whatever is in the FIFO already would not raise an interrupt */
+        while let Some(byte) = self.poll() {
+            state.push(byte);
+        }
+        self.write_register(IER, IER_RX_AVAILABLE);
+    }
+
+    /// Buffers received bytes from now on, with the IRQ unmasked on the PIC.
+    /// Fails for COM2 and COM4, their vector is taken by the IPI handler.
+    pub fn route_pic(&self) -> Result<(), SerialError> {
+        if self.vector() == idt::IPI_VECTOR {
+            return Err(SerialError::SERIAL_ERROR_VECTOR_IN_USE);
+        }
+
+        idt::set_handler(self.vector(), irq4_handler);
+        self.enable_interrupts();
+        PIC::unmask(self.irq());
+        Ok(())
+    }
+
+    /// Like `route_pic`, but delivers the IRQ through the IOAPIC to `vector`
+    /// on the current CPU.
+    pub fn route_ioapic(&self, vector: u8) -> Option<()> {
+        idt::set_handler(vector, if self.irq() == 4 { irq4_ioapic_handler } else { irq3_ioapic_handler });
+        self.enable_interrupts();
+        IOAPIC::route_isa(self.irq() as u8, vector)
+    }
+
+    /* Drains the FIFOs of every port on `irq` */
+    fn interrupt(irq: u32) {
+        for index in 0..PORTS.len() {
+            let port = SerialPort(index);
+            if port.irq() != irq {
+                continue;
+            }
+
+            let received = {
+                let mut state = STATE[index].lock();
+                if !state.interrupts {
+                    continue;
+                }
+
+                let mut received = false;
+                loop {
+                    let status = port.read_register(LSR);
+                    if (status & LSR_OVERRUN) != 0 {
+                        state.overruns += 1;
+                    }
+                    if (status & LSR_DATA_READY) == 0 {
+                        break;
+                    }
+
+                    state.push(port.read_register(DATA));
+                    received = true;
+                }
+                received
+            };
+
+            if received {
+                READERS[index].wake_all();
+            }
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Console for SerialPort {
+    fn name(&self) -> &'static str {
+        ["com1", "com2", "com3", "com4"][self.0]
+    }
+
+    /// Sets the port up with `SerialConfig::default()` if nobody did. Once
+    /// that found no UART the output is dropped, every byte would wait out
+    /// the transmitter timeout otherwise.
+    fn write(&self, bytes: &[u8]) {
+        let (initialized, absent) = {
+            let state = STATE[self.0].lock();
+            (state.initialized, state.absent)
+        };
+
+        if absent || (!initialized && self.init(SerialConfig::default()).is_err()) {
+            return;
+        }
+
+        self.write_bytes(bytes);
+    }
+}
+
+extern "x86-interrupt" fn irq4_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    lockdep::irq_enter();
+    SerialPort::interrupt(4);
+
+    PIC::eoi(4);
+    APIC::eoi(0);
+    lockdep::irq_exit();
+}
+
+extern "x86-interrupt" fn irq4_ioapic_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    lockdep::irq_enter();
+    SerialPort::interrupt(4);
+
+    IOAPIC::eoi(4);
+    lockdep::irq_exit();
+}
+
+extern "x86-interrupt" fn irq3_ioapic_handler(
+    _stack_frame: &mut InterruptStackFrame)
+{
+    lockdep::irq_enter();
+    SerialPort::interrupt(3);
+
+    IOAPIC::eoi(3);
+    lockdep::irq_exit();
+}
@@ -0,0 +1,113 @@
+//! MSR and I/O permission maps, selecting which RDMSR/WRMSR and IN/OUT
+//! instructions cause #VMEXIT with the MSR_PROT and IOIO_PROT intercepts
+//! set.
+//!
+//! Same interface as `vmx::bitmap`. Both start out empty, nothing exits. The
+//! pages come from `page_alloc` and are never freed.
+
+use core::ops::RangeInclusive;
+
+use crate::msr::MSR;
+use crate::virt::{bit, set_bit};
+use crate::vm::VM;
+
+/* MSR permission map: 2 KiB per MSR range, 2 bits per MSR, read then write */
+const MSRPM_PAGES: usize    = 2;
+const MSRPM_RANGES: [(u32, usize); 3] = [
+    (0x0000_0000, 0x0000),
+    (0xc000_0000, 0x0800),
+    (0xc001_0000, 0x1000),
+];
+const MSRPM_RANGE_SIZE: u32 = 0x2000;
+
+/* I/O permission map: one bit per port, plus the bits of accesses running
+ * past port 0xffff */
+const IOPM_PAGES: usize     = 3;
+
+/// The MSR permission map, for MSRPM_BASE_PA.
+pub struct MsrBitmap {
+    base: u64,
+}
+
+impl MsrBitmap {
+    pub fn new() -> Option<Self> {
+        Some(MsrBitmap { base: VM::alloc_pages(MSRPM_PAGES)? })
+    }
+
+    /// Physical address for MSRPM_BASE_PA.
+    pub fn address(&self) -> u64 {
+        self.base
+    }
+
+    /* Bit offset of the read intercept of `msr`, the write one follows */
+    fn offset(msr: u32) -> Option<usize> {
+        MSRPM_RANGES.iter()
+            .find(|&&(start, _)| msr >= start && msr - start < MSRPM_RANGE_SIZE)
+            .map(|&(start, offset)| offset * 8 + (msr - start) as usize * 2)
+    }
+
+    /// Sets whether RDMSR and WRMSR of `msr` exit. Fails for MSRs outside
+    /// 0-0x1fff, 0xc0000000-0xc0001fff and 0xc0010000-0xc0011fff, those
+    /// always exit.
+    pub fn intercept_raw(&mut self, msr: u32, read: bool, write: bool) -> Option<()> {
+        let offset = MsrBitmap::offset(msr)?;
+
+        set_bit(self.base, offset, read);
+        set_bit(self.base, offset + 1, write);
+        Some(())
+    }
+
+    pub fn intercept(&mut self, msr: MSR, read: bool, write: bool) -> Option<()> {
+        self.intercept_raw(msr as u32, read, write)
+    }
+
+    /// Whether RDMSR of `msr` exits.
+    pub fn intercepts_read(&self, msr: u32) -> bool {
+        MsrBitmap::offset(msr).map_or(true, |offset| bit(self.base, offset))
+    }
+
+    /// Whether WRMSR of `msr` exits.
+    pub fn intercepts_write(&self, msr: u32) -> bool {
+        MsrBitmap::offset(msr).map_or(true, |offset| bit(self.base, offset + 1))
+    }
+
+    /// Has every access to the covered MSRs exit, or none.
+    pub fn intercept_all(&mut self, intercept: bool) {
+        let fill = if intercept { 0xff } else { 0 };
+        let size = MSRPM_RANGES.len() * MSRPM_RANGE_SIZE as usize * 2 / 8;
+
+        unsafe { core::ptr::write_bytes(VM::phys_to_virt(self.base) as *mut u8, fill, size) };
+    }
+}
+
+/// The I/O permission map, for IOPM_BASE_PA.
+pub struct IoBitmap {
+    base: u64,
+}
+
+impl IoBitmap {
+    pub fn new() -> Option<Self> {
+        Some(IoBitmap { base: VM::alloc_pages(IOPM_PAGES)? })
+    }
+
+    /// Physical address for IOPM_BASE_PA.
+    pub fn address(&self) -> u64 {
+        self.base
+    }
+
+    /// Sets whether IN and OUT to `ports` exit. An access that touches
+    /// several ports exits if any of them is intercepted.
+    pub fn intercept(&mut self, ports: RangeInclusive<u16>, intercept: bool) {
+        for port in ports {
+            set_bit(self.base, port as usize, intercept);
+        }
+    }
+
+    pub fn intercept_port(&mut self, port: u16, intercept: bool) {
+        self.intercept(port..=port, intercept)
+    }
+
+    pub fn intercepts(&self, port: u16) -> bool {
+        bit(self.base, port as usize)
+    }
+}
@@ -0,0 +1,30 @@
+//! AMD SVM support.
+//!
+//! Laid out like `vmx`: `enable()` per CPU, the VMCB instead of the VMCS,
+//! MSR/IO permission maps instead of bitmaps and nested page tables instead
+//! of EPT, with the same method names where the two agree.
+
+use crate::cpuid::{self, Feature};
+
+pub mod bitmap;
+pub mod npt;
+pub mod vmcb;
+mod svme;
+
+pub use self::svme::{enable, disable, enabled, SvmEnableError};
+
+/// SVM revision, from CPUID 0x8000000A.
+pub fn revision() -> Option<u32> {
+    match cpuid::has(Feature::FEATURE_SVM) {
+        true => Some(cpuid::query(0x8000000a, 0).eax & 0xff),
+        false => None,
+    }
+}
+
+/// Number of ASIDs, guests use 1 to this minus one, 0 is the host.
+pub fn asid_count() -> Option<u32> {
+    match cpuid::has(Feature::FEATURE_SVM) {
+        true => Some(cpuid::query(0x8000000a, 0).ebx),
+        false => None,
+    }
+}
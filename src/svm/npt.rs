@@ -0,0 +1,275 @@
+//! Nested page tables.
+//!
+//! Regular long mode page tables, walked with guest-physical addresses, so
+//! the interface follows `vmx::ept`. Guest accesses count as user accesses,
+//! every entry has the user bit. Memory types are PAT indices of the host,
+//! assumed to hold the power-on PAT; execute-disable needs EFER.NXE on the
+//! host.
+
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+
+use crate::msr::MemoryType;
+use crate::page_alloc::PageAllocFrames;
+use crate::virt::{self, EntryFormat, GuestTables, TableError};
+use crate::vm::VM;
+
+/* Access rights */
+pub const NPT_READ: u64     = 1 << 0;
+pub const NPT_WRITE: u64    = 1 << 1;
+pub const NPT_EXECUTE: u64  = 1 << 2;
+pub const NPT_RWX: u64      = NPT_READ | NPT_WRITE | NPT_EXECUTE;
+
+/* Entry fields */
+const PRESENT: u64          = 1 << 0;
+const WRITABLE: u64         = 1 << 1;
+const USER: u64             = 1 << 2;
+const WRITE_THROUGH: u64    = 1 << 3;
+const CACHE_DISABLE: u64    = 1 << 4;
+const ACCESSED: u64         = 1 << 5;
+const DIRTY: u64            = 1 << 6;
+const PAT_4K: u64           = 1 << 7;
+const PAT_LARGE: u64        = 1 << 12;
+const NO_EXECUTE: u64       = 1 << 63;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NptPageSize {
+    NPT_PAGE_4K,
+    NPT_PAGE_2M,
+    NPT_PAGE_1G,
+}
+
+impl NptPageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            NptPageSize::NPT_PAGE_4K => 0x1000,
+            NptPageSize::NPT_PAGE_2M => 0x20_0000,
+            NptPageSize::NPT_PAGE_1G => 0x4000_0000,
+        }
+    }
+
+    /* Table level holding the leaf entry, 0 for the PT */
+    fn level(self) -> usize {
+        match self {
+            NptPageSize::NPT_PAGE_4K => 0,
+            NptPageSize::NPT_PAGE_2M => 1,
+            NptPageSize::NPT_PAGE_1G => 2,
+        }
+    }
+
+    fn from_level(level: usize) -> Self {
+        match level {
+            0 => NptPageSize::NPT_PAGE_4K,
+            1 => NptPageSize::NPT_PAGE_2M,
+            _ => NptPageSize::NPT_PAGE_1G,
+        }
+    }
+
+    /* The PAT bit moves to bit 12 in large page entries */
+    fn pat_bit(self) -> u64 {
+        match self {
+            NptPageSize::NPT_PAGE_4K => PAT_4K,
+            _ => PAT_LARGE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NptError {
+    /// Addresses or size not aligned to the page size.
+    NPT_ERROR_UNALIGNED,
+    /// Part of the range is mapped already.
+    NPT_ERROR_ALREADY_MAPPED,
+    /// No read access, which page tables cannot express, or a memory type
+    /// the power-on PAT does not have (WC and WP).
+    NPT_ERROR_INVALID_MAPPING,
+    /// The allocator ran out of frames.
+    NPT_ERROR_NO_MEMORY,
+}
+
+/// Result of a guest-physical address lookup.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NptTranslation {
+    pub hpa: u64,
+    pub access: u64,
+    pub memory_type: Option<MemoryType>,
+    pub page_size: NptPageSize,
+    pub accessed: bool,
+    pub dirty: bool,
+}
+
+/* PAT index in the power-on PAT: WB, WT, UC-, UC, repeated */
+fn pat_index(memory_type: MemoryType) -> Option<u64> {
+    match memory_type {
+        MemoryType::MEMORY_TYPE_WB => Some(0),
+        MemoryType::MEMORY_TYPE_WT => Some(1),
+        MemoryType::MEMORY_TYPE_UC_MINUS => Some(2),
+        MemoryType::MEMORY_TYPE_UC => Some(3),
+        _ => None,
+    }
+}
+
+fn pat_type(index: u64) -> MemoryType {
+    match index & 3 {
+        0 => MemoryType::MEMORY_TYPE_WB,
+        1 => MemoryType::MEMORY_TYPE_WT,
+        2 => MemoryType::MEMORY_TYPE_UC_MINUS,
+        _ => MemoryType::MEMORY_TYPE_UC,
+    }
+}
+
+impl From<TableError> for NptError {
+    fn from(error: TableError) -> Self {
+        match error {
+            TableError::TABLE_ERROR_ALREADY_MAPPED => NptError::NPT_ERROR_ALREADY_MAPPED,
+            TableError::TABLE_ERROR_NO_MEMORY => NptError::NPT_ERROR_NO_MEMORY,
+        }
+    }
+}
+
+struct NptFormat;
+
+impl EntryFormat for NptFormat {
+    const TABLE_FLAGS: u64 = PRESENT | WRITABLE | USER;
+
+    fn present(entry: u64) -> bool {
+        entry & PRESENT != 0
+    }
+}
+
+pub struct Npt<A: FrameAllocator<Size4KiB>> {
+    tables: GuestTables<A, NptFormat>,
+    max_page: NptPageSize,
+}
+
+impl Npt<PageAllocFrames> {
+    /// Empty tables with frames from `page_alloc`.
+    pub fn new() -> Option<Self> {
+        Npt::with_allocator(PageAllocFrames, VM::phys_offset())
+    }
+}
+
+impl<A: FrameAllocator<Size4KiB>> Npt<A> {
+    /// Empty tables with frames from `allocator`, accessed at
+    /// `phys_offset` + their physical address.
+    pub fn with_allocator(allocator: A, phys_offset: u64) -> Option<Self> {
+        Some(Npt {
+            tables: GuestTables::new(allocator, phys_offset)?,
+            max_page: NptPageSize::NPT_PAGE_2M,
+        })
+    }
+
+    /// Physical address of the PML4.
+    pub fn root(&self) -> u64 {
+        self.tables.root()
+    }
+
+    /// Largest pages `map` uses, 2 MiB by default. Check
+    /// `cpuid::Feature::FEATURE_PAGE_1GB` before allowing 1 GiB ones.
+    pub fn set_max_page_size(&mut self, size: NptPageSize) {
+        self.max_page = size;
+    }
+
+    /// The value for the VMCB's N_CR3, the tables are write-back.
+    pub fn ncr3(&self) -> u64 {
+        self.root()
+    }
+
+    /* Leaf entry bits for `access` and `memory_type` */
+    fn leaf_flags(access: u64, memory_type: MemoryType, size: NptPageSize) -> Result<u64, NptError> {
+        let index = pat_index(memory_type).ok_or(NptError::NPT_ERROR_INVALID_MAPPING)?;
+
+        if access & !NPT_RWX != 0 || access & NPT_READ == 0 {
+            return Err(NptError::NPT_ERROR_INVALID_MAPPING);
+        }
+
+        let mut flags = PRESENT | USER;
+
+        if access & NPT_WRITE != 0 {
+            flags |= WRITABLE;
+        }
+        if access & NPT_EXECUTE == 0 {
+            flags |= NO_EXECUTE;
+        }
+        if index & 1 != 0 {
+            flags |= WRITE_THROUGH;
+        }
+        if index & 2 != 0 {
+            flags |= CACHE_DISABLE;
+        }
+
+        Ok(flags | virt::large_page(size.level()))
+    }
+
+    /// Maps one page of `size` at `gpa` to `hpa`.
+    pub fn map_page(&mut self, gpa: u64, hpa: u64, size: NptPageSize,
+                    access: u64, memory_type: MemoryType) -> Result<(), NptError> {
+        let flags = Self::leaf_flags(access, memory_type, size)?;
+
+        if (gpa | hpa) & (size.bytes() - 1) != 0 {
+            return Err(NptError::NPT_ERROR_UNALIGNED);
+        }
+
+        Ok(self.tables.map_leaf(gpa, size.level(), hpa | flags)?)
+    }
+
+    /// Maps `size` bytes at `gpa` to `hpa`, with the largest pages up to the
+    /// configured maximum that alignment allows. On error the part mapped so
+    /// far stays.
+    pub fn map(&mut self, gpa: u64, hpa: u64, size: u64,
+               access: u64, memory_type: MemoryType) -> Result<(), NptError> {
+        if (gpa | hpa | size) & 0xfff != 0 {
+            return Err(NptError::NPT_ERROR_UNALIGNED);
+        }
+
+        virt::for_each_page(gpa, hpa, size, self.max_page.level(), |gpa, hpa, level| {
+            self.map_page(gpa, hpa, NptPageSize::from_level(level), access, memory_type)
+        })
+    }
+
+    /* The leaf entry for `gpa` and the size it maps */
+    fn leaf(&self, gpa: u64) -> Option<(*mut u64, NptPageSize)> {
+        let (entry, level) = self.tables.leaf(gpa)?;
+        Some((entry, NptPageSize::from_level(level)))
+    }
+
+    /// Walks the tables like the CPU would for `gpa`.
+    pub fn translate(&self, gpa: u64) -> Option<NptTranslation> {
+        let (entry, page_size) = self.leaf(gpa)?;
+        let value = unsafe { entry.read() };
+        let offset = gpa & (page_size.bytes() - 1);
+
+        let mut access = NPT_READ;
+        if value & WRITABLE != 0 {
+            access |= NPT_WRITE;
+        }
+        if value & NO_EXECUTE == 0 {
+            access |= NPT_EXECUTE;
+        }
+
+        let index = (value & WRITE_THROUGH != 0) as u64 |
+                    ((value & CACHE_DISABLE != 0) as u64) << 1 |
+                    ((value & page_size.pat_bit() != 0) as u64) << 2;
+
+        Some(NptTranslation {
+            hpa: (virt::entry_address(value) & !(page_size.bytes() - 1)) + offset,
+            access,
+            memory_type: Some(pat_type(index)),
+            page_size,
+            accessed: value & ACCESSED != 0,
+            dirty: value & DIRTY != 0,
+        })
+    }
+
+    /// Clears the accessed and dirty flags of the page holding `gpa` and
+    /// returns what they were. The guest TLB has to be flushed through
+    /// TLB_CONTROL for the CPU to set them again.
+    pub fn clear_accessed_dirty(&mut self, gpa: u64) -> Option<(bool, bool)> {
+        let (entry, _) = self.leaf(gpa)?;
+
+        unsafe {
+            let value = entry.read();
+            entry.write(value & !(ACCESSED | DIRTY));
+            Some((value & ACCESSED != 0, value & DIRTY != 0))
+        }
+    }
+}
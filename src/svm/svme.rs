@@ -0,0 +1,80 @@
+//! Turning SVM on and off.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cpu::{CPU, MAX_CPUS};
+use crate::cpuid::{self, Feature};
+use crate::msr::{Efer, VmCr, MSR};
+use crate::vm::VM;
+
+/// Why `svm::enable()` failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SvmEnableError {
+    /// CPUID reports no SVM.
+    SVM_ENABLE_ERROR_UNSUPPORTED,
+    /// VM_CR.SVMDIS is set, by the firmware or a previous owner that locked
+    /// it.
+    SVM_ENABLE_ERROR_DISABLED_BY_FIRMWARE,
+    /// EFER.SVME is already set.
+    SVM_ENABLE_ERROR_ALREADY_ENABLED,
+    /// No frame left for the host save area.
+    SVM_ENABLE_ERROR_NO_MEMORY,
+}
+
+/* Physical address of each CPU's host save area, 0 if none yet */
+const NO_AREA: AtomicU64 = AtomicU64::new(0);
+static HSAVE_AREAS: [AtomicU64; MAX_CPUS] = [NO_AREA; MAX_CPUS];
+
+fn hsave_area() -> Result<u64, SvmEnableError> {
+    let cpu = CPU::id();
+    let area = HSAVE_AREAS[cpu].load(Ordering::Relaxed);
+
+    if area != 0 {
+        return Ok(area);
+    }
+
+    let area = VM::alloc_pages(1).ok_or(SvmEnableError::SVM_ENABLE_ERROR_NO_MEMORY)?;
+
+    HSAVE_AREAS[cpu].store(area, Ordering::Relaxed);
+    Ok(area)
+}
+
+/// Lets the running CPU execute VMRUN: checks CPUID and VM_CR, sets
+/// EFER.SVME and points VM_HSAVE_PA at a host save area from `page_alloc`.
+/// Every CPU that wants to use SVM has to call it.
+pub fn enable() -> Result<(), SvmEnableError> {
+    if !cpuid::has(Feature::FEATURE_SVM) {
+        return Err(SvmEnableError::SVM_ENABLE_ERROR_UNSUPPORTED);
+    }
+
+    if unsafe { VmCr::read() }.svm_disabled() {
+        return Err(SvmEnableError::SVM_ENABLE_ERROR_DISABLED_BY_FIRMWARE);
+    }
+
+    if enabled() {
+        return Err(SvmEnableError::SVM_ENABLE_ERROR_ALREADY_ENABLED);
+    }
+
+    let area = hsave_area()?;
+
+    unsafe {
+        Efer::read().set_svm_enabled(true).write();
+        MSR::VM_HSAVE_PA.write(area);
+    }
+
+    Ok(())
+}
+
+/// Clears EFER.SVME on the running CPU, no guest may be running. The host
+/// save area is kept for the next `enable()`.
+pub fn disable() {
+    unsafe {
+        MSR::VM_HSAVE_PA.write(0);
+        Efer::read().set_svm_enabled(false).write();
+    }
+}
+
+/// Whether the running CPU has EFER.SVME set.
+pub fn enabled() -> bool {
+    unsafe { Efer::read() }.svm_enabled()
+}
@@ -0,0 +1,378 @@
+//! The VMCB: a control area with intercepts and exit information, and the
+//! guest state save area, in one page the CPU reads on VMRUN and writes back
+//! on #VMEXIT.
+//!
+//! Offsets follow the AMD APM volume 2, appendix B. Unlike the VMCS it is
+//! plain memory, guest state is changed by writing the fields.
+
+use crate::vm::VM;
+
+/// Intercepts of the third to fifth intercept vectors, by (vector, bit).
+/// CR, DR and exception intercepts have their own setters.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SvmIntercept {
+    SVM_INTERCEPT_INTR,
+    SVM_INTERCEPT_NMI,
+    SVM_INTERCEPT_SMI,
+    SVM_INTERCEPT_INIT,
+    SVM_INTERCEPT_VINTR,
+    SVM_INTERCEPT_CR0_SEL_WRITE,
+    SVM_INTERCEPT_IDTR_READ,
+    SVM_INTERCEPT_GDTR_READ,
+    SVM_INTERCEPT_LDTR_READ,
+    SVM_INTERCEPT_TR_READ,
+    SVM_INTERCEPT_IDTR_WRITE,
+    SVM_INTERCEPT_GDTR_WRITE,
+    SVM_INTERCEPT_LDTR_WRITE,
+    SVM_INTERCEPT_TR_WRITE,
+    SVM_INTERCEPT_RDTSC,
+    SVM_INTERCEPT_RDPMC,
+    SVM_INTERCEPT_PUSHF,
+    SVM_INTERCEPT_POPF,
+    SVM_INTERCEPT_CPUID,
+    SVM_INTERCEPT_RSM,
+    SVM_INTERCEPT_IRET,
+    SVM_INTERCEPT_INTN,
+    SVM_INTERCEPT_INVD,
+    SVM_INTERCEPT_PAUSE,
+    SVM_INTERCEPT_HLT,
+    SVM_INTERCEPT_INVLPG,
+    SVM_INTERCEPT_INVLPGA,
+    /// Uses the I/O permission map.
+    SVM_INTERCEPT_IOIO_PROT,
+    /// Uses the MSR permission map.
+    SVM_INTERCEPT_MSR_PROT,
+    SVM_INTERCEPT_TASK_SWITCH,
+    SVM_INTERCEPT_FERR_FREEZE,
+    SVM_INTERCEPT_SHUTDOWN,
+
+    /// Has to be set, VMRUN fails otherwise.
+    SVM_INTERCEPT_VMRUN,
+    SVM_INTERCEPT_VMMCALL,
+    SVM_INTERCEPT_VMLOAD,
+    SVM_INTERCEPT_VMSAVE,
+    SVM_INTERCEPT_STGI,
+    SVM_INTERCEPT_CLGI,
+    SVM_INTERCEPT_SKINIT,
+    SVM_INTERCEPT_RDTSCP,
+    SVM_INTERCEPT_ICEBP,
+    SVM_INTERCEPT_WBINVD,
+    SVM_INTERCEPT_MONITOR,
+    SVM_INTERCEPT_MWAIT,
+    SVM_INTERCEPT_MWAIT_CONDITIONAL,
+    SVM_INTERCEPT_XSETBV,
+    SVM_INTERCEPT_RDPRU,
+    SVM_INTERCEPT_EFER_WRITE_TRAP,
+
+    SVM_INTERCEPT_INVLPGB,
+    SVM_INTERCEPT_INVLPGB_ILLEGAL,
+    SVM_INTERCEPT_INVPCID,
+    SVM_INTERCEPT_MCOMMIT,
+    SVM_INTERCEPT_TLBSYNC,
+}
+
+impl SvmIntercept {
+    /* (index into `VmcbControl::intercepts`, bit) */
+    fn location(self) -> (usize, u32) {
+        match self {
+            SvmIntercept::SVM_INTERCEPT_INTR => (0, 0),
+            SvmIntercept::SVM_INTERCEPT_NMI => (0, 1),
+            SvmIntercept::SVM_INTERCEPT_SMI => (0, 2),
+            SvmIntercept::SVM_INTERCEPT_INIT => (0, 3),
+            SvmIntercept::SVM_INTERCEPT_VINTR => (0, 4),
+            SvmIntercept::SVM_INTERCEPT_CR0_SEL_WRITE => (0, 5),
+            SvmIntercept::SVM_INTERCEPT_IDTR_READ => (0, 6),
+            SvmIntercept::SVM_INTERCEPT_GDTR_READ => (0, 7),
+            SvmIntercept::SVM_INTERCEPT_LDTR_READ => (0, 8),
+            SvmIntercept::SVM_INTERCEPT_TR_READ => (0, 9),
+            SvmIntercept::SVM_INTERCEPT_IDTR_WRITE => (0, 10),
+            SvmIntercept::SVM_INTERCEPT_GDTR_WRITE => (0, 11),
+            SvmIntercept::SVM_INTERCEPT_LDTR_WRITE => (0, 12),
+            SvmIntercept::SVM_INTERCEPT_TR_WRITE => (0, 13),
+            SvmIntercept::SVM_INTERCEPT_RDTSC => (0, 14),
+            SvmIntercept::SVM_INTERCEPT_RDPMC => (0, 15),
+            SvmIntercept::SVM_INTERCEPT_PUSHF => (0, 16),
+            SvmIntercept::SVM_INTERCEPT_POPF => (0, 17),
+            SvmIntercept::SVM_INTERCEPT_CPUID => (0, 18),
+            SvmIntercept::SVM_INTERCEPT_RSM => (0, 19),
+            SvmIntercept::SVM_INTERCEPT_IRET => (0, 20),
+            SvmIntercept::SVM_INTERCEPT_INTN => (0, 21),
+            SvmIntercept::SVM_INTERCEPT_INVD => (0, 22),
+            SvmIntercept::SVM_INTERCEPT_PAUSE => (0, 23),
+            SvmIntercept::SVM_INTERCEPT_HLT => (0, 24),
+            SvmIntercept::SVM_INTERCEPT_INVLPG => (0, 25),
+            SvmIntercept::SVM_INTERCEPT_INVLPGA => (0, 26),
+            SvmIntercept::SVM_INTERCEPT_IOIO_PROT => (0, 27),
+            SvmIntercept::SVM_INTERCEPT_MSR_PROT => (0, 28),
+            SvmIntercept::SVM_INTERCEPT_TASK_SWITCH => (0, 29),
+            SvmIntercept::SVM_INTERCEPT_FERR_FREEZE => (0, 30),
+            SvmIntercept::SVM_INTERCEPT_SHUTDOWN => (0, 31),
+
+            SvmIntercept::SVM_INTERCEPT_VMRUN => (1, 0),
+            SvmIntercept::SVM_INTERCEPT_VMMCALL => (1, 1),
+            SvmIntercept::SVM_INTERCEPT_VMLOAD => (1, 2),
+            SvmIntercept::SVM_INTERCEPT_VMSAVE => (1, 3),
+            SvmIntercept::SVM_INTERCEPT_STGI => (1, 4),
+            SvmIntercept::SVM_INTERCEPT_CLGI => (1, 5),
+            SvmIntercept::SVM_INTERCEPT_SKINIT => (1, 6),
+            SvmIntercept::SVM_INTERCEPT_RDTSCP => (1, 7),
+            SvmIntercept::SVM_INTERCEPT_ICEBP => (1, 8),
+            SvmIntercept::SVM_INTERCEPT_WBINVD => (1, 9),
+            SvmIntercept::SVM_INTERCEPT_MONITOR => (1, 10),
+            SvmIntercept::SVM_INTERCEPT_MWAIT => (1, 11),
+            SvmIntercept::SVM_INTERCEPT_MWAIT_CONDITIONAL => (1, 12),
+            SvmIntercept::SVM_INTERCEPT_XSETBV => (1, 13),
+            SvmIntercept::SVM_INTERCEPT_RDPRU => (1, 14),
+            SvmIntercept::SVM_INTERCEPT_EFER_WRITE_TRAP => (1, 15),
+
+            SvmIntercept::SVM_INTERCEPT_INVLPGB => (2, 0),
+            SvmIntercept::SVM_INTERCEPT_INVLPGB_ILLEGAL => (2, 1),
+            SvmIntercept::SVM_INTERCEPT_INVPCID => (2, 2),
+            SvmIntercept::SVM_INTERCEPT_MCOMMIT => (2, 3),
+            SvmIntercept::SVM_INTERCEPT_TLBSYNC => (2, 4),
+        }
+    }
+
+    /// The EXITCODE of the #VMEXIT the intercept causes, none for the fifth
+    /// vector, those have scattered codes.
+    pub fn exit_code(self) -> Option<u64> {
+        match self.location() {
+            (2, _) => None,
+            (index, bit) => Some(SVM_EXIT_INTR + index as u64 * 32 + bit as u64),
+        }
+    }
+}
+
+/* EXITCODE values, the intercept vectors map in order */
+pub const SVM_EXIT_CR_READ: u64         = 0x00;
+pub const SVM_EXIT_CR_WRITE: u64        = 0x10;
+pub const SVM_EXIT_DR_READ: u64         = 0x20;
+pub const SVM_EXIT_DR_WRITE: u64        = 0x30;
+pub const SVM_EXIT_EXCEPTION: u64       = 0x40;
+pub const SVM_EXIT_INTR: u64            = 0x60;
+pub const SVM_EXIT_NPF: u64             = 0x400;
+pub const SVM_EXIT_INVALID: u64         = !0;
+
+/* TLB_CONTROL values */
+pub const TLB_CONTROL_DO_NOTHING: u8        = 0;
+pub const TLB_CONTROL_FLUSH_ALL: u8         = 1;
+pub const TLB_CONTROL_FLUSH_ASID: u8        = 3;
+pub const TLB_CONTROL_FLUSH_ASID_LOCAL: u8  = 7;
+
+/* NP_CONTROL bits */
+pub const NP_ENABLE: u64 = 1 << 0;
+
+/* EVENTINJ */
+pub const EVENT_VALID: u64              = 1 << 31;
+pub const EVENT_ERROR_CODE_VALID: u64   = 1 << 11;
+pub const EVENT_TYPE_INTR: u64          = 0 << 8;
+pub const EVENT_TYPE_NMI: u64           = 2 << 8;
+pub const EVENT_TYPE_EXCEPTION: u64     = 3 << 8;
+pub const EVENT_TYPE_SOFT_INT: u64      = 4 << 8;
+
+/// The control area, the first 1 KiB.
+#[repr(C)]
+pub struct VmcbControl {
+    /// Reads of CR0-15 in bits 0-15, writes in 16-31.
+    pub intercept_cr: u32,
+    /// Same for DR0-15.
+    pub intercept_dr: u32,
+    /// One bit per exception vector.
+    pub intercept_exceptions: u32,
+    /// The third to fifth intercept vectors, see `SvmIntercept`.
+    pub intercepts: [u32; 3],
+    reserved0: [u8; 0x24],
+    pub pause_filter_threshold: u16,
+    pub pause_filter_count: u16,
+    /// Physical address of the I/O permission map.
+    pub iopm_base_pa: u64,
+    /// Physical address of the MSR permission map.
+    pub msrpm_base_pa: u64,
+    pub tsc_offset: u64,
+    pub guest_asid: u32,
+    pub tlb_control: u8,
+    reserved1: [u8; 3],
+    /// V_TPR, V_IRQ, V_INTR_PRIO, V_IGN_TPR, V_INTR_MASKING, V_INTR_VECTOR.
+    pub virtual_interrupt: u64,
+    pub interrupt_shadow: u64,
+    pub exit_code: u64,
+    pub exit_info1: u64,
+    pub exit_info2: u64,
+    pub exit_int_info: u64,
+    pub np_control: u64,
+    pub avic_apic_bar: u64,
+    pub ghcb_pa: u64,
+    pub event_injection: u64,
+    /// Nested page table root, see `Npt::ncr3()`.
+    pub ncr3: u64,
+    pub virtual_extensions: u64,
+    /// State the CPU may keep cached across VMRUNs, 0 reloads everything.
+    pub vmcb_clean: u32,
+    reserved2: u32,
+    /// RIP after the intercepted instruction, with NRIPS.
+    pub next_rip: u64,
+    pub instruction_length: u8,
+    pub instruction_bytes: [u8; 15],
+    pub avic_backing_page: u64,
+    reserved3: u64,
+    pub avic_logical_table: u64,
+    pub avic_physical_table: u64,
+    reserved4: u64,
+    pub vmsa_pa: u64,
+    reserved5: [u8; 0x2f0],
+}
+
+/// A segment register in the save area.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct VmcbSegment {
+    pub selector: u16,
+    /// Descriptor bits 40-47 and 52-55, packed to 12 bits.
+    pub attributes: u16,
+    pub limit: u32,
+    pub base: u64,
+}
+
+/// The guest state save area, after the control area. RAX is the only
+/// general purpose register in it, the rest is up to the VMRUN caller.
+#[repr(C)]
+pub struct VmcbSave {
+    pub es: VmcbSegment,
+    pub cs: VmcbSegment,
+    pub ss: VmcbSegment,
+    pub ds: VmcbSegment,
+    pub fs: VmcbSegment,
+    pub gs: VmcbSegment,
+    pub gdtr: VmcbSegment,
+    pub ldtr: VmcbSegment,
+    pub idtr: VmcbSegment,
+    pub tr: VmcbSegment,
+    reserved0: [u8; 0x2b],
+    pub cpl: u8,
+    reserved1: u32,
+    pub efer: u64,
+    reserved2: [u8; 0x70],
+    pub cr4: u64,
+    pub cr3: u64,
+    pub cr0: u64,
+    pub dr7: u64,
+    pub dr6: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    reserved3: [u8; 0x58],
+    pub rsp: u64,
+    reserved4: [u8; 0x18],
+    pub rax: u64,
+    pub star: u64,
+    pub lstar: u64,
+    pub cstar: u64,
+    pub sfmask: u64,
+    pub kernel_gs_base: u64,
+    pub sysenter_cs: u64,
+    pub sysenter_esp: u64,
+    pub sysenter_eip: u64,
+    pub cr2: u64,
+    reserved5: [u8; 0x20],
+    pub g_pat: u64,
+    pub debugctl: u64,
+    pub br_from: u64,
+    pub br_to: u64,
+    pub last_exception_from: u64,
+    pub last_exception_to: u64,
+    reserved6: [u8; 0x968],
+}
+
+#[repr(C, align(4096))]
+pub struct Vmcb {
+    pub control: VmcbControl,
+    pub save: VmcbSave,
+}
+
+/* The CPU has the layout hardcoded */
+const _: [(); 0x400] = [(); core::mem::size_of::<VmcbControl>()];
+const _: [(); 0x1000] = [(); core::mem::size_of::<Vmcb>()];
+
+impl Vmcb {
+    /// A zeroed VMCB from `page_alloc`, which is never freed.
+    pub fn new() -> Option<&'static mut Vmcb> {
+        let page = VM::alloc_pages(1)?;
+        Some(unsafe { &mut *(VM::phys_to_virt(page) as *mut Vmcb) })
+    }
+
+    /// Physical address for VMRUN.
+    pub fn address(&self) -> u64 {
+        VM::virt_to_phys(self as *const Vmcb as u64)
+    }
+}
+
+fn set_bit(word: &mut u32, bit: u32, value: bool) {
+    match value {
+        true => *word |= 1 << bit,
+        false => *word &= !(1 << bit),
+    }
+}
+
+impl VmcbControl {
+    pub fn set_intercept(&mut self, intercept: SvmIntercept, value: bool) {
+        let (index, bit) = intercept.location();
+        set_bit(&mut self.intercepts[index], bit, value);
+    }
+
+    pub fn intercepts(&self, intercept: SvmIntercept) -> bool {
+        let (index, bit) = intercept.location();
+        self.intercepts[index] & (1 << bit) != 0
+    }
+
+    /// Intercepts reads and writes of CR`cr`, 0 to 15. Fails for others.
+    pub fn set_cr_intercept(&mut self, cr: u32, read: bool, write: bool) -> Option<()> {
+        if cr >= 16 {
+            return None;
+        }
+
+        set_bit(&mut self.intercept_cr, cr, read);
+        set_bit(&mut self.intercept_cr, cr + 16, write);
+        Some(())
+    }
+
+    /// Intercepts reads and writes of DR`dr`, 0 to 15. Fails for others.
+    pub fn set_dr_intercept(&mut self, dr: u32, read: bool, write: bool) -> Option<()> {
+        if dr >= 16 {
+            return None;
+        }
+
+        set_bit(&mut self.intercept_dr, dr, read);
+        set_bit(&mut self.intercept_dr, dr + 16, write);
+        Some(())
+    }
+
+    /// Intercepts exception `vector`, 0 to 31. Fails for others.
+    pub fn set_exception_intercept(&mut self, vector: u8, value: bool) -> Option<()> {
+        if vector >= 32 {
+            return None;
+        }
+
+        set_bit(&mut self.intercept_exceptions, vector as u32, value);
+        Some(())
+    }
+
+    /// Uses the nested page tables rooted at `ncr3`, or none for 0.
+    pub fn set_nested_paging(&mut self, ncr3: u64) {
+        self.ncr3 = ncr3;
+        match ncr3 {
+            0 => self.np_control &= !NP_ENABLE,
+            _ => self.np_control |= NP_ENABLE,
+        }
+    }
+
+    /// Injects `vector` on the next VMRUN, `kind` one of the EVENT_TYPE
+    /// values.
+    pub fn inject_event(&mut self, vector: u8, kind: u64, error_code: Option<u32>) {
+        let mut event = EVENT_VALID | kind | vector as u64;
+
+        if let Some(error_code) = error_code {
+            event |= EVENT_ERROR_CODE_VALID | (error_code as u64) << 32;
+        }
+
+        self.event_injection = event;
+    }
+}
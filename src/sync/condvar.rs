@@ -0,0 +1,63 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::{MutexGuard, WaitQueue};
+
+/// A condition variable used together with `Mutex`.
+///
+/// Wakeups can be spurious, so waiters re-check their condition, which
+/// `wait_while` does for them. Notifying is safe from interrupt context.
+pub struct Condvar {
+    /* Bumped by every notify, waiters sleep until it moved */
+    sequence: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            sequence: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex, blocks until notified and locks it again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        /* Read before unlocking, a notify after the unlock changes it */
+        let sequence = self.sequence.load(Ordering::Acquire);
+
+        drop(guard);
+        self.waiters.wait_until(|| self.sequence.load(Ordering::Acquire) != sequence);
+        mutex.lock()
+    }
+
+    /// Waits as long as `condition` holds for the protected data.
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T>
+        where F: FnMut(&mut T) -> bool
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub async fn wait_async<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        let sequence = self.sequence.load(Ordering::Acquire);
+
+        drop(guard);
+        self.waiters.wait_until_async(|| self.sequence.load(Ordering::Acquire) != sequence).await;
+        mutex.lock_async().await
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
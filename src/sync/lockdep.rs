@@ -0,0 +1,405 @@
+//! Lock dependency validator.
+//!
+//! With the `lockdep` feature every lock acquisition is recorded per thread and
+//! checked against the order in which lock classes were taken before, so a
+//! possible deadlock is reported the first time both orders are seen instead
+//! of the first time they actually race. It also catches locks taken from
+//! interrupt handlers that are elsewhere held with interrupts enabled.
+//!
+//! A lock class is the place the lock was created, so the locks of an array
+//! built from one `const` item, or created in a loop, share a class. The
+//! scheduler hands the held locks over with `switch()`. Without the feature
+//! all hooks are empty and compile away.
+
+use core::panic::Location;
+
+pub type Site = &'static Location<'static>;
+
+#[cfg(feature = "lockdep")]
+pub use self::imp::{class, acquire, release, irq_enter, irq_exit, switch, Class, HeldLocks};
+
+#[cfg(not(feature = "lockdep"))]
+pub use self::noop::{class, acquire, release, irq_enter, irq_exit, switch, Class, HeldLocks};
+
+#[cfg(not(feature = "lockdep"))]
+mod noop {
+    use super::Site;
+
+    pub type Class = ();
+
+    pub struct HeldLocks;
+
+    impl HeldLocks {
+        pub const EMPTY: HeldLocks = HeldLocks;
+    }
+
+    #[inline(always)]
+    pub const fn class() -> Class {}
+
+    #[inline(always)]
+    pub fn acquire(_lock: usize, _class: Class, _site: Site, _read: bool, _trylock: bool) {}
+
+    #[inline(always)]
+    pub fn release(_lock: usize) {}
+
+    #[inline(always)]
+    pub fn irq_enter() {}
+
+    #[inline(always)]
+    pub fn irq_exit() {}
+
+    #[inline(always)]
+    pub fn switch(_prev: &mut HeldLocks, _next: &HeldLocks) {}
+}
+
+#[cfg(feature = "lockdep")]
+mod imp {
+    use core::cell::UnsafeCell;
+    use core::panic::Location;
+    use core::sync::atomic::{AtomicBool, Ordering, spin_loop_hint};
+
+    use super::Site;
+    use crate::cpu::{CPU, MAX_CPUS};
+
+    const MAX_CLASSES: usize    = 64;
+    const MAX_HELD: usize       = 16;
+
+    /// Where a lock was created, which identifies its class.
+    pub type Class = Site;
+
+    /// The class of a lock created by the caller.
+    #[track_caller]
+    pub const fn class() -> Class {
+        Location::caller()
+    }
+
+    #[derive(Clone, Copy)]
+    struct ClassInfo {
+        key: usize,
+        /* First acquisition from interrupt context */
+        irq_site: Option<Site>,
+        /* First acquisition with interrupts enabled */
+        irq_unsafe_site: Option<Site>,
+    }
+
+    impl ClassInfo {
+        const EMPTY: ClassInfo = ClassInfo {
+            key: 0,
+            irq_site: None,
+            irq_unsafe_site: None,
+        };
+    }
+
+    struct Graph {
+        classes: [ClassInfo; MAX_CLASSES],
+        count: usize,
+        /* Bit b of after[a] is set once b was taken while holding a */
+        after: [u64; MAX_CLASSES],
+        /* Where a and then b were taken the first time a -> b was seen */
+        sites: [[Option<(Site, Site)>; MAX_CLASSES]; MAX_CLASSES],
+    }
+
+    #[derive(Clone, Copy)]
+    struct Held {
+        lock: usize,
+        class: usize,
+        site: Option<Site>,
+        read: bool,
+    }
+
+    impl Held {
+        const EMPTY: Held = Held {
+            lock: 0,
+            class: 0,
+            site: None,
+            read: false,
+        };
+    }
+
+    /// The locks a thread holds, kept by the scheduler while it is not
+    /// running.
+    #[derive(Clone, Copy)]
+    pub struct HeldLocks {
+        held: [Held; MAX_HELD],
+        depth: usize,
+        irq_depth: usize,
+    }
+
+    impl HeldLocks {
+        pub const EMPTY: HeldLocks = HeldLocks {
+            held: [Held::EMPTY; MAX_HELD],
+            depth: 0,
+            irq_depth: 0,
+        };
+    }
+
+    /* What went wrong, printed once the graph is unlocked */
+    enum Report {
+        IrqUsage { lock: usize, irq_site: Site, irq_unsafe_site: Site },
+        Recursive { lock: usize, site: Site, held: Held },
+        Circular { lock: usize, site: Site, held: Held, first_class: Class, first: Option<(Site, Site)> },
+        TooManyHeld,
+        TooManyClasses,
+    }
+
+    struct Global<T>(UnsafeCell<T>);
+
+    /* Graph is serialized by GRAPH_LOCK, the HeldLocks of a CPU are only
+     * touched by that CPU with interrupts disabled. */
+    unsafe impl<T> Sync for Global<T> {}
+
+    static GRAPH_LOCK: AtomicBool = AtomicBool::new(false);
+    static GRAPH: Global<Graph> = Global(UnsafeCell::new(Graph {
+        classes: [ClassInfo::EMPTY; MAX_CLASSES],
+        count: 0,
+        after: [0; MAX_CLASSES],
+        sites: [[None; MAX_CLASSES]; MAX_CLASSES],
+    }));
+    /* The running thread's locks on each CPU */
+    static CPUS: Global<[HeldLocks; MAX_CPUS]> = Global(UnsafeCell::new([HeldLocks::EMPTY; MAX_CPUS]));
+
+    /* Stop checking after the first report, the state is suspect by then.
+     * This is also what keeps the console lock taken while printing a report
+     * from coming back into lockdep. */
+    static OFF: AtomicBool = AtomicBool::new(false);
+
+    fn cpu_state() -> &'static mut HeldLocks {
+        unsafe { &mut (*CPUS.0.get())[CPU::id()] }
+    }
+
+    fn lock_graph() -> &'static mut Graph {
+        while GRAPH_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            spin_loop_hint();
+        }
+
+        unsafe { &mut *GRAPH.0.get() }
+    }
+
+    fn unlock_graph() {
+        GRAPH_LOCK.store(false, Ordering::Release);
+    }
+
+    fn print_site(what: &str, lock: usize, site: Option<Site>) {
+        match site {
+            Some(site) => println!("lockdep:   {} lock {:#x} at {}", what, lock, site),
+            None => println!("lockdep:   {} lock {:#x} at <unknown>", what, lock),
+        }
+    }
+
+    fn print_class_site(what: &str, class: Class, site: Option<Site>) {
+        match site {
+            Some(site) => println!("lockdep:   {} lock created at {} at {}", what, class, site),
+            None => println!("lockdep:   {} lock created at {} at <unknown>", what, class),
+        }
+    }
+
+    fn print(report: Report) {
+        match report {
+            Report::IrqUsage { lock, irq_site, irq_unsafe_site } => {
+                println!("lockdep: lock used in interrupt context and with interrupts enabled on cpu {}", CPU::id());
+                print_site("taken in interrupt context", lock, Some(irq_site));
+                print_site("taken with interrupts enabled", lock, Some(irq_unsafe_site));
+            },
+            Report::Recursive { lock, site, held } => {
+                println!("lockdep: recursive locking detected on cpu {}", CPU::id());
+                print_site("acquiring", lock, Some(site));
+                print_site("already held, acquired", held.lock, held.site);
+            },
+            Report::Circular { lock, site, held, first_class, first } => {
+                println!("lockdep: possible circular locking dependency on cpu {}", CPU::id());
+                print_site("acquiring", lock, Some(site));
+                print_site("while holding", held.lock, held.site);
+                println!("lockdep: but the opposite order was seen before:");
+                print_class_site("first", first_class, first.map(|(first, _)| first));
+                print_site("then", held.lock, first.map(|(_, then)| then));
+            },
+            Report::TooManyHeld => println!("lockdep: too many locks held on cpu {}", CPU::id()),
+            Report::TooManyClasses => println!("lockdep: too many lock classes on cpu {}", CPU::id()),
+        }
+
+        println!("lockdep: further lock checking is disabled");
+    }
+
+    fn class_of(graph: &mut Graph, class: Class) -> Option<usize> {
+        let key = class as *const Location<'static> as usize;
+
+        if let Some(index) = graph.classes[..graph.count].iter().position(|c| c.key == key) {
+            return Some(index);
+        }
+
+        if graph.count == MAX_CLASSES {
+            return None;
+        }
+
+        let index = graph.count;
+        graph.classes[index] = ClassInfo { key, ..ClassInfo::EMPTY };
+        graph.count += 1;
+        Some(index)
+    }
+
+    /* All classes that can be taken after `class` */
+    fn reachable(graph: &Graph, class: usize) -> u64 {
+        let mut reach = graph.after[class];
+
+        loop {
+            let mut next = reach;
+
+            for index in 0..graph.count {
+                if (reach & (1 << index)) != 0 {
+                    next |= graph.after[index];
+                }
+            }
+
+            if next == reach {
+                return reach;
+            }
+
+            reach = next;
+        }
+    }
+
+    fn class_site(graph: &Graph, class: usize) -> Class {
+        unsafe { &*(graph.classes[class].key as *const Location<'static>) }
+    }
+
+    fn check_irq_usage(graph: &mut Graph, state: &HeldLocks, lock: usize, class: usize, site: Site,
+                       irq_enabled: bool) -> Option<Report> {
+        let in_irq = state.irq_depth > 0;
+        let entry = &mut graph.classes[class];
+
+        if in_irq && entry.irq_site.is_none() {
+            entry.irq_site = Some(site);
+        }
+
+        if !in_irq && irq_enabled && entry.irq_unsafe_site.is_none() {
+            entry.irq_unsafe_site = Some(site);
+        }
+
+        match (entry.irq_site, entry.irq_unsafe_site) {
+            (Some(irq_site), Some(irq_unsafe_site)) => Some(Report::IrqUsage { lock, irq_site, irq_unsafe_site }),
+            _ => None,
+        }
+    }
+
+    fn check_order(graph: &mut Graph, state: &HeldLocks, lock: usize, class: usize, site: Site,
+                   read: bool) -> Option<Report> {
+        let reach = reachable(graph, class);
+
+        for held in state.held[..state.depth].iter() {
+            if held.class == class {
+                if held.read && read {
+                    continue;
+                }
+
+                return Some(Report::Recursive { lock, site, held: *held });
+            }
+
+            if (reach & (1 << held.class)) != 0 {
+                /* Find the recorded edge that closes the cycle */
+                let closing = (0..graph.count)
+                    .filter(|&c| c == class || (reach & (1 << c)) != 0)
+                    .find(|&c| (graph.after[c] & (1 << held.class)) != 0)
+                    .unwrap_or(class);
+
+                return Some(Report::Circular {
+                    lock,
+                    site,
+                    held: *held,
+                    first_class: class_site(graph, closing),
+                    first: graph.sites[closing][held.class],
+                });
+            }
+        }
+
+        for held in state.held[..state.depth].iter() {
+            if (graph.after[held.class] & (1 << class)) == 0 {
+                graph.after[held.class] |= 1 << class;
+                if let Some(held_site) = held.site {
+                    graph.sites[held.class][class] = Some((held_site, site));
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn acquire(lock: usize, class: Class, site: Site, read: bool, trylock: bool) {
+        if OFF.load(Ordering::Relaxed) {
+            return;
+        }
+
+        /* IRQ state has to be sampled before we disable interrupts here */
+        let irq_enabled = CPU::irq_enabled();
+        let flags = CPU::irq_save();
+        let state = cpu_state();
+        let graph = lock_graph();
+
+        let report = match class_of(graph, class) {
+            Some(class) => {
+                /* A trylock can not deadlock, it only orders what comes after it */
+                let report = check_irq_usage(graph, state, lock, class, site, irq_enabled).or_else(|| {
+                    match trylock {
+                        true => None,
+                        false => check_order(graph, state, lock, class, site, read),
+                    }
+                });
+
+                if state.depth < MAX_HELD {
+                    state.held[state.depth] = Held { lock, class, site: Some(site), read };
+                    state.depth += 1;
+                    report
+                } else {
+                    report.or(Some(Report::TooManyHeld))
+                }
+            },
+            None => Some(Report::TooManyClasses),
+        };
+
+        /* Only one CPU gets to report */
+        let report = report.filter(|_| !OFF.swap(true, Ordering::Relaxed));
+
+        unlock_graph();
+
+        if let Some(report) = report {
+            print(report);
+        }
+
+        CPU::irq_restore(flags);
+    }
+
+    pub fn release(lock: usize) {
+        let flags = CPU::irq_save();
+        let state = cpu_state();
+
+        /* Locks do not have to be released in order */
+        if let Some(index) = state.held[..state.depth].iter().rposition(|h| h.lock == lock) {
+            state.held.copy_within(index + 1..state.depth, index);
+            state.depth -= 1;
+        }
+
+        CPU::irq_restore(flags);
+    }
+
+    pub fn irq_enter() {
+        let flags = CPU::irq_save();
+        cpu_state().irq_depth += 1;
+        CPU::irq_restore(flags);
+    }
+
+    pub fn irq_exit() {
+        let flags = CPU::irq_save();
+        let state = cpu_state();
+        state.irq_depth = state.irq_depth.saturating_sub(1);
+        CPU::irq_restore(flags);
+    }
+
+    /// Stores the locks of the thread switched away from in `prev` and
+    /// makes `next` those of the running thread. Interrupts have to be
+    /// disabled.
+    pub fn switch(prev: &mut HeldLocks, next: &HeldLocks) {
+        let state = cpu_state();
+
+        *prev = *state;
+        *state = *next;
+    }
+}
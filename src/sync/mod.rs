@@ -0,0 +1,16 @@
+mod spinlock;
+mod rwlock;
+mod once;
+mod waitqueue;
+mod mutex;
+mod semaphore;
+mod condvar;
+pub mod lockdep;
+
+pub use self::spinlock::{SpinLock, SpinLockGuard, IrqSpinLock, IrqSpinLockGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::once::Once;
+pub use self::waitqueue::{WaitQueue, WaitUntil};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::semaphore::Semaphore;
+pub use self::condvar::Condvar;
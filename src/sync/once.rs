@@ -0,0 +1,60 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering, spin_loop_hint};
+
+const INCOMPLETE: u8    = 0;
+const RUNNING: u8       = 1;
+const COMPLETE: u8      = 2;
+
+/// A value that is initialized exactly once and read-only afterwards.
+pub struct Once<T> {
+    state: AtomicU8,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Runs `f` if nobody initialized the value yet, otherwise waits for the
+    /// value to be ready. Either way a reference to the value is returned.
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        if self.state.compare_exchange(INCOMPLETE, RUNNING,
+                                       Ordering::Acquire, Ordering::Acquire).is_ok() {
+            unsafe { (*self.data.get()).as_mut_ptr().write(f()) };
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            spin_loop_hint();
+        }
+
+        unsafe { &*(*self.data.get()).as_ptr() }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            COMPLETE => Some(unsafe { &*(*self.data.get()).as_ptr() }),
+            _ => None,
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { core::ptr::drop_in_place((*self.data.get()).as_mut_ptr()) };
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering, spin_loop_hint};
+
+use crate::sync::lockdep;
+
+/* Lock word layout: bit 0 is the writer, readers are counted from bit 2 */
+const WRITER: usize         = 1 << 0;
+const WRITER_WAITING: usize = 1 << 1;
+const READER: usize         = 1 << 2;
+
+/// A spinning reader-writer lock.
+///
+/// A waiting writer stops new readers from getting in, so writers are not
+/// starved by a steady stream of readers.
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    class: lockdep::Class,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            class: lockdep::class(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn raw_try_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+
+        if (state & (WRITER | WRITER_WAITING)) != 0 {
+            return false;
+        }
+
+        self.state.compare_exchange_weak(state, state + READER,
+                                         Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn raw_try_write(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+
+        if (state & !WRITER_WAITING) != 0 {
+            return false;
+        }
+
+        self.state.compare_exchange(state, WRITER,
+                                    Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if !self.raw_try_read() {
+            return None;
+        }
+
+        lockdep::acquire(self.key(), self.class, Location::caller(), true, true);
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        lockdep::acquire(self.key(), self.class, Location::caller(), true, false);
+
+        while !self.raw_try_read() {
+            spin_loop_hint();
+        }
+
+        RwLockReadGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if !self.raw_try_write() {
+            return None;
+        }
+
+        lockdep::acquire(self.key(), self.class, Location::caller(), false, true);
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        lockdep::acquire(self.key(), self.class, Location::caller(), false, false);
+
+        while !self.raw_try_write() {
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            spin_loop_hint();
+        }
+
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.key());
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.key());
+        /* Also clears WRITER_WAITING, any other waiting writer sets it again */
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
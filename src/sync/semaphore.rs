@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::WaitQueue;
+
+/// A counting semaphore.
+///
+/// `release` may be called from interrupt handlers, e.g. once per received
+/// packet, with a thread or task consuming them through `acquire`.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+            if count > 0 { Some(count - 1) } else { None }
+        }).is_ok()
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub async fn acquire_async(&self) {
+        self.waiters.wait_until_async(|| self.try_acquire()).await;
+    }
+
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
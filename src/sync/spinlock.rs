@@ -0,0 +1,188 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering, spin_loop_hint};
+use x86_64::registers::rflags::RFlags;
+
+use crate::cpu::CPU;
+use crate::sync::lockdep;
+
+/// A ticket spinlock.
+///
+/// Waiters are served in the order they started spinning, so a CPU can not be
+/// starved by others repeatedly grabbing the lock.
+pub struct SpinLock<T> {
+    next: AtomicUsize,
+    owner: AtomicUsize,
+    class: lockdep::Class,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            next: AtomicUsize::new(0),
+            owner: AtomicUsize::new(0),
+            class: lockdep::class(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        lockdep::acquire(self.key(), self.class, Location::caller(), false, false);
+
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+
+        while self.owner.load(Ordering::Acquire) != ticket {
+            spin_loop_hint();
+        }
+
+        SpinLockGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let owner = self.owner.load(Ordering::Relaxed);
+
+        if self.next.compare_exchange(owner, owner.wrapping_add(1),
+                                      Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return None;
+        }
+
+        lockdep::acquire(self.key(), self.class, Location::caller(), false, true);
+
+        Some(SpinLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.owner.load(Ordering::Relaxed)
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// Only meant for code that has to give up a lock it can not return
+    /// through (e.g. a panic handler that wants to print).
+    pub unsafe fn force_unlock(&self) {
+        lockdep::release(self.key());
+        self.owner.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.key());
+        self.lock.owner.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// A spinlock that keeps interrupts disabled while it is held.
+///
+/// This is the lock to use for data that is also touched from interrupt
+/// handlers, otherwise an interrupt arriving on the CPU that holds the lock
+/// would spin forever.
+pub struct IrqSpinLock<T> {
+    lock: SpinLock<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: Option<SpinLockGuard<'a, T>>,
+    flags: RFlags,
+}
+
+impl<T> IrqSpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub const fn new(data: T) -> Self {
+        IrqSpinLock {
+            lock: SpinLock::new(data),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let flags = CPU::irq_save();
+
+        IrqSpinLockGuard {
+            guard: Some(self.lock.lock()),
+            flags,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let flags = CPU::irq_save();
+
+        match self.lock.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard { guard: Some(guard), flags }),
+            None => {
+                CPU::irq_restore(flags);
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+
+    /// See `SpinLock::force_unlock`, interrupts are left as they are.
+    pub unsafe fn force_unlock(&self) {
+        self.lock.force_unlock()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.lock.into_inner()
+    }
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        /* Release the lock before interrupts can come in again */
+        self.guard.take();
+        CPU::irq_restore(self.flags);
+    }
+}
@@ -0,0 +1,246 @@
+use core::future::Future;
+use core::marker::PhantomPinned;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::spin_loop_hint;
+use core::task::{Context, Poll, Waker};
+
+use crate::cpu::CPU;
+use crate::sched::{self, ThreadId};
+use crate::sync::IrqSpinLock;
+
+enum Waiter {
+    Thread(ThreadId),
+    Task(Waker),
+}
+
+impl Waiter {
+    fn wake(self) {
+        match self {
+            Waiter::Thread(thread) => sched::wake(thread),
+            Waiter::Task(waker) => waker.wake(),
+        }
+    }
+}
+
+/* A queued waiter. It lives with whoever waits, on the blocked thread's
+ * stack or in the pinned future, so the queue cannot fill up. Only touched
+ * with the queue locked, and unlinked before its owner goes away. */
+struct Node {
+    waiter: Option<Waiter>,
+    prev: *mut Node,
+    next: *mut Node,
+    queued: bool,
+}
+
+impl Node {
+    const fn new() -> Self {
+        Node {
+            waiter: None,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            queued: false,
+        }
+    }
+}
+
+struct Waiters {
+    /* FIFO, linked through `Node::next` */
+    head: *mut Node,
+    tail: *mut Node,
+}
+
+/* The nodes are only reached through the lock */
+unsafe impl Send for Waiters {}
+
+impl Waiters {
+    fn push(&mut self, node: &mut Node, waiter: Waiter) {
+        node.waiter = Some(waiter);
+        node.prev = self.tail;
+        node.next = ptr::null_mut();
+        node.queued = true;
+
+        match unsafe { self.tail.as_mut() } {
+            Some(tail) => tail.next = node,
+            None => self.head = node,
+        }
+        self.tail = node;
+    }
+
+    fn unlink(&mut self, node: &mut Node) {
+        match unsafe { node.prev.as_mut() } {
+            Some(prev) => prev.next = node.next,
+            None => self.head = node.next,
+        }
+        match unsafe { node.next.as_mut() } {
+            Some(next) => next.prev = node.prev,
+            None => self.tail = node.prev,
+        }
+
+        node.prev = ptr::null_mut();
+        node.next = ptr::null_mut();
+        node.queued = false;
+    }
+
+    fn pop(&mut self) -> Option<Waiter> {
+        let node = unsafe { self.head.as_mut()? };
+
+        self.unlink(node);
+        node.waiter.take()
+    }
+
+    /* Returns false if the node was not queued, i.e. it was woken */
+    fn remove(&mut self, node: &mut Node) -> bool {
+        if !node.queued {
+            return false;
+        }
+
+        self.unlink(node);
+        node.waiter = None;
+        true
+    }
+}
+
+/// A FIFO of threads and async tasks waiting for a condition.
+///
+/// Waiters pass the condition to `wait_until`, where it is checked with the
+/// queue locked. Code making a condition true changes its state first and
+/// calls `wake_one`/`wake_all` afterwards, so no wakeup gets lost. Waking is
+/// safe from interrupt context, waiting is not.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<Waiters>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSpinLock::new(Waiters {
+                head: ptr::null_mut(),
+                tail: ptr::null_mut(),
+            }),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns true. Spins if
+    /// the scheduler does not run on this CPU.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        loop {
+            /* Interrupts stay off until we are off the CPU, a preemption in
+             * between would put a blocked thread back on the run queue */
+            let flags = CPU::irq_save();
+            let mut waiters = self.waiters.lock();
+
+            if condition() {
+                drop(waiters);
+                CPU::irq_restore(flags);
+                return;
+            }
+
+            if !sched::initialized() {
+                drop(waiters);
+                CPU::irq_restore(flags);
+                spin_loop_hint();
+                continue;
+            }
+
+            let mut node = Node::new();
+            waiters.push(&mut node, Waiter::Thread(sched::current()));
+            sched::prepare_block();
+            drop(waiters);
+
+            sched::block();
+
+            /* Woken by something other than this queue, the node must not
+             * stay linked once this frame is gone */
+            self.waiters.lock().remove(&mut node);
+            CPU::irq_restore(flags);
+        }
+    }
+
+    /// Completes once `condition` returns true.
+    pub fn wait_until_async<F: FnMut() -> bool>(&self, condition: F) -> WaitUntil<'_, F> {
+        WaitUntil {
+            queue: self,
+            condition,
+            node: Node::new(),
+            waiting: false,
+            _pinned: PhantomPinned,
+        }
+    }
+
+    /// Wakes the longest waiting thread or task, returns false if there was
+    /// none.
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop();
+
+        match waiter {
+            Some(waiter) => {
+                waiter.wake();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Wakes everything waiting, returns how many.
+    pub fn wake_all(&self) -> usize {
+        let mut count = 0;
+
+        while self.wake_one() {
+            count += 1;
+        }
+
+        count
+    }
+}
+
+pub struct WaitUntil<'a, F: FnMut() -> bool> {
+    queue: &'a WaitQueue,
+    condition: F,
+    node: Node,
+    /* Queued since the last poll that completed */
+    waiting: bool,
+    /* The queue links to `node` */
+    _pinned: PhantomPinned,
+}
+
+impl<F: FnMut() -> bool> Future for WaitUntil<'_, F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut waiters = this.queue.waiters.lock();
+
+        if (this.condition)() {
+            if this.waiting {
+                waiters.remove(&mut this.node);
+                this.waiting = false;
+            }
+            return Poll::Ready(());
+        }
+
+        let waker = context.waker().clone();
+        if this.node.queued {
+            this.node.waiter = Some(Waiter::Task(waker));
+        } else {
+            /* Never queued, or popped by a wake */
+            waiters.push(&mut this.node, Waiter::Task(waker));
+            this.waiting = true;
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<F: FnMut() -> bool> Drop for WaitUntil<'_, F> {
+    fn drop(&mut self) {
+        if self.waiting {
+            let queued = self.queue.waiters.lock().remove(&mut self.node);
+
+            /* Woken but never got to run, pass the wakeup on */
+            if !queued {
+                self.queue.wake_one();
+            }
+        }
+    }
+}
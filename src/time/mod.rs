@@ -0,0 +1,274 @@
+//! Time keeping.
+//!
+//! Hardware drivers expose counters as `ClockSource`s and interrupt timers as
+//! `ClockEvent`s. The best rated of each is picked automatically; the source
+//! backs `now()` and the event drives the software timers in `timer`, so any
+//! number of timers can share one piece of hardware.
+
+use core::sync::atomic::{AtomicBool, Ordering, spin_loop_hint};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+use crate::apic::APICTimer;
+use crate::cpu::CPU;
+use crate::hpet::{HPET, HPETClockSource};
+use crate::pit::{PIT, PITTimer};
+use crate::sched;
+use crate::sync::{IrqSpinLock, Once};
+use crate::tsc::{TSC, TSCClockSource};
+
+mod timer;
+
+pub use self::timer::{TimerId, add_timer, add_timer_at, add_periodic_timer, cancel_timer};
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+const MAX_SOURCES: usize    = 8;
+const MAX_EVENTS: usize     = 8;
+
+/* How long the TSC and APIC timer are measured against the PIT/HPET */
+const CALIBRATION_MS: u64   = 50;
+
+/// A free running counter.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    /// Higher is better, used to pick the source behind `now()`.
+    fn rating(&self) -> u32;
+    fn frequency(&self) -> u64;
+    fn read(&self) -> u64;
+}
+
+/// A timer that can raise an interrupt on the timer vector. Tick counts are
+/// in units of `frequency()`.
+pub trait ClockEvent: Sync {
+    fn name(&self) -> &'static str;
+    /// Higher is better, used to pick the event device behind the timers.
+    fn rating(&self) -> u32;
+    fn frequency(&self) -> u64;
+    fn set_oneshot(&self, ticks: u64);
+    fn set_periodic(&self, ticks: u64);
+    fn stop(&self);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimeError {
+    /// Neither the HPET nor a calibrated TSC can back `now()`.
+    TIME_ERROR_NO_CLOCKSOURCE,
+}
+
+#[derive(Clone, Copy)]
+struct Current {
+    source: &'static dyn ClockSource,
+    /* now() at the time the source was selected, and its counter then */
+    base_ticks: u64,
+    base_ns: u64,
+}
+
+static SOURCES: IrqSpinLock<[Option<&'static dyn ClockSource>; MAX_SOURCES]> = IrqSpinLock::new([None; MAX_SOURCES]);
+static EVENTS: IrqSpinLock<[Option<&'static dyn ClockEvent>; MAX_EVENTS]> = IrqSpinLock::new([None; MAX_EVENTS]);
+
+static CURRENT_SOURCE: IrqSpinLock<Option<Current>> = IrqSpinLock::new(None);
+static CURRENT_EVENT: IrqSpinLock<Option<&'static dyn ClockEvent>> = IrqSpinLock::new(None);
+
+static TSC_SOURCE: Once<TSCClockSource> = Once::new();
+static HPET_SOURCE: Once<HPETClockSource> = Once::new();
+static APIC_EVENT: Once<APICTimer> = Once::new();
+static PIT_EVENT: PITTimer = PITTimer;
+
+fn ticks_to_ns(ticks: u64, frequency: u64) -> u64 {
+    ((ticks as u128) * (NSEC_PER_SEC as u128) / (frequency as u128)) as u64
+}
+
+fn ns_to_ticks(ns: u64, frequency: u64) -> u64 {
+    ((ns as u128) * (frequency as u128) / (NSEC_PER_SEC as u128)) as u64
+}
+
+/* Nanoseconds in `duration`, saturating at u64::MAX (584 years) */
+fn duration_ns(duration: Duration) -> u64 {
+    duration.as_nanos().min(u64::MAX as u128) as u64
+}
+
+/* now() + `duration`, saturating */
+fn deadline_ns(duration: Duration) -> u64 {
+    now_ns().saturating_add(duration_ns(duration))
+}
+
+fn elapsed_ns(current: &Current) -> u64 {
+    let ticks = current.source.read().wrapping_sub(current.base_ticks);
+    current.base_ns + ticks_to_ns(ticks, current.source.frequency())
+}
+
+/// Nanoseconds since the first clock source was registered, 0 before that.
+pub fn now_ns() -> u64 {
+    let current = *CURRENT_SOURCE.lock();
+    current.as_ref().map_or(0, elapsed_ns)
+}
+
+pub fn now() -> Duration {
+    Duration::from_nanos(now_ns())
+}
+
+pub fn register_clocksource(source: &'static dyn ClockSource) -> Option<()> {
+    let mut sources = SOURCES.lock();
+    let slot = sources.iter_mut().find(|s| s.is_none())?;
+    *slot = Some(source);
+
+    let mut current = CURRENT_SOURCE.lock();
+    let better = current.map_or(true, |c| source.rating() > c.source.rating());
+    if better {
+        /* Continue from where the old source left off */
+        let base_ns = current.as_ref().map_or(0, elapsed_ns);
+        *current = Some(Current { source, base_ticks: source.read(), base_ns });
+    }
+
+    Some(())
+}
+
+pub fn register_clockevent(event: &'static dyn ClockEvent) -> Option<()> {
+    let mut events = EVENTS.lock();
+    let slot = events.iter_mut().find(|e| e.is_none())?;
+    *slot = Some(event);
+
+    let mut current = CURRENT_EVENT.lock();
+    let better = current.map_or(true, |c| event.rating() > c.rating());
+    if better {
+        if let Some(old) = current.replace(event) {
+            old.stop();
+        }
+    }
+
+    Some(())
+}
+
+pub fn clocksource() -> Option<&'static dyn ClockSource> {
+    CURRENT_SOURCE.lock().map(|c| c.source)
+}
+
+pub fn clockevent() -> Option<&'static dyn ClockEvent> {
+    *CURRENT_EVENT.lock()
+}
+
+/* Arms the event device to fire `delta_ns` from now, used by the timers */
+fn program_event(delta_ns: u64) {
+    if let Some(event) = clockevent() {
+        let ticks = ns_to_ticks(delta_ns, event.frequency());
+        event.set_oneshot(if ticks == 0 { 1 } else { ticks });
+    }
+}
+
+fn stop_event() {
+    if let Some(event) = clockevent() {
+        event.stop();
+    }
+}
+
+/// Probes and registers the timer hardware libos knows about.
+///
+/// The TSC and APIC timer are calibrated against the HPET if there is one,
+/// otherwise against PIT channel 2. Whatever fails to calibrate is left out,
+/// only having no clock source at all is an error.
+pub fn init() -> Result<(), TimeError> {
+    let hpet = HPET::init()
+        .and_then(|_| HPETClockSource::new())
+        .ok()
+        .map(|source| HPET_SOURCE.call_once(|| source));
+
+    if let Some(hpet) = hpet {
+        register_clocksource(hpet);
+    }
+
+    let tsc_hz = match hpet {
+        Some(hpet) => calibrate_tsc(hpet),
+        None => PIT::calibrate_tsc(CALIBRATION_MS),
+    };
+    if let Some(tsc_hz) = tsc_hz {
+        register_clocksource(TSC_SOURCE.call_once(|| TSCClockSource::new(tsc_hz)));
+    }
+
+    if let Some(apic_hz) = PIT::calibrate_apic_timer(CALIBRATION_MS) {
+        register_clockevent(APIC_EVENT.call_once(|| APICTimer::new(apic_hz)));
+    }
+    register_clockevent(&PIT_EVENT);
+
+    match clocksource() {
+        Some(_) => Ok(()),
+        None => Err(TimeError::TIME_ERROR_NO_CLOCKSOURCE),
+    }
+}
+
+/* TSC frequency measured against the HPET main counter, None if either did
+ * not move */
+fn calibrate_tsc(hpet: &HPETClockSource) -> Option<u64> {
+    let start = (TSC::read(), hpet.read());
+    PIT::mdelay(CALIBRATION_MS);
+    let end = (TSC::read(), hpet.read());
+
+    let hpet_ns = ticks_to_ns(end.1.wrapping_sub(start.1), hpet.frequency());
+    if hpet_ns == 0 {
+        return None;
+    }
+
+    match (end.0.wrapping_sub(start.0) as u128 * NSEC_PER_SEC as u128 / hpet_ns as u128) as u64 {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Interrupt handler of the timer vector, runs the expired timers of this
+/// CPU and re-arms the event device for the next one.
+pub fn interrupt() {
+    timer::run_expired();
+}
+
+/// Spins until `duration` has passed, on PIT channel 2 before there is a
+/// clock source.
+pub fn busy_wait(duration: Duration) {
+    if clocksource().is_none() {
+        PIT::udelay(duration.as_micros().min(u64::MAX as u128) as u64);
+        return;
+    }
+
+    let deadline = deadline_ns(duration);
+
+    while now_ns() < deadline {
+        spin_loop_hint();
+    }
+}
+
+fn wake_flag(flag: usize) {
+    unsafe { (*(flag as *const AtomicBool)).store(true, Ordering::Release) }
+}
+
+/// Halts the CPU until `duration` has passed, or blocks just the running
+/// thread once the scheduler runs on this CPU.
+///
+/// Falls back to `busy_wait` with interrupts disabled, before `init()` or if
+/// no timer can be armed.
+pub fn sleep(duration: Duration) {
+    if clockevent().is_none() || clocksource().is_none() {
+        busy_wait(duration);
+        return;
+    }
+
+    if CPU::irq_enabled() && sched::initialized() {
+        sched::sleep(duration);
+        return;
+    }
+
+    let done = AtomicBool::new(false);
+
+    if !CPU::irq_enabled() || add_timer(duration, wake_flag, &done as *const _ as usize).is_none() {
+        busy_wait(duration);
+        return;
+    }
+
+    while !done.load(Ordering::Acquire) {
+        /* Close the window between the check and hlt */
+        interrupts::disable();
+        if done.load(Ordering::Acquire) {
+            interrupts::enable();
+            break;
+        }
+        interrupts::enable_interrupts_and_hlt();
+    }
+}
@@ -0,0 +1,276 @@
+use core::sync::atomic::spin_loop_hint;
+use core::time::Duration;
+
+use crate::cpu::{CPU, MAX_CPUS};
+use crate::sync::IrqSpinLock;
+
+use super::{deadline_ns, duration_ns, now_ns, program_event, stop_event};
+
+/* Pending timers per CPU */
+const MAX_TIMERS: usize = 32;
+
+/* Callbacks run outside the queue lock, this many per interrupt */
+const MAX_EXPIRED: usize = 8;
+
+/// Handle of a pending software timer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimerId {
+    cpu: u16,
+    slot: u16,
+    generation: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    deadline: u64,
+    period: u64,
+    callback: fn(usize),
+    data: usize,
+    generation: u32,
+    /* Position in the heap, only meaningful while active */
+    index: usize,
+    active: bool,
+    /* The callback is running, the slot cannot be reused until it returns */
+    running: bool,
+}
+
+fn nop(_data: usize) {}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        deadline: 0,
+        period: 0,
+        callback: nop,
+        data: 0,
+        generation: 0,
+        index: 0,
+        active: false,
+        running: false,
+    };
+}
+
+/// Binary min-heap of slot numbers ordered by deadline.
+struct TimerQueue {
+    slots: [Slot; MAX_TIMERS],
+    heap: [usize; MAX_TIMERS],
+    len: usize,
+}
+
+impl TimerQueue {
+    const EMPTY: TimerQueue = TimerQueue {
+        slots: [Slot::EMPTY; MAX_TIMERS],
+        heap: [0; MAX_TIMERS],
+        len: 0,
+    };
+
+    fn deadline(&self, index: usize) -> u64 {
+        self.slots[self.heap[index]].deadline
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.slots[self.heap[a]].index = a;
+        self.slots[self.heap[b]].index = b;
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.deadline(parent) <= self.deadline(index) {
+                break;
+            }
+            self.swap(parent, index);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let left = index * 2 + 1;
+            let right = left + 1;
+            let mut smallest = index;
+
+            if left < self.len && self.deadline(left) < self.deadline(smallest) {
+                smallest = left;
+            }
+            if right < self.len && self.deadline(right) < self.deadline(smallest) {
+                smallest = right;
+            }
+            if smallest == index {
+                break;
+            }
+
+            self.swap(index, smallest);
+            index = smallest;
+        }
+    }
+
+    fn push(&mut self, slot: usize) {
+        let index = self.len;
+        self.heap[index] = slot;
+        self.slots[slot].index = index;
+        self.len += 1;
+        self.sift_up(index);
+    }
+
+    fn remove(&mut self, index: usize) -> usize {
+        let slot = self.heap[index];
+
+        self.len -= 1;
+        if index != self.len {
+            self.swap(index, self.len);
+            self.sift_down(index);
+            self.sift_up(index);
+        }
+
+        slot
+    }
+
+    fn first(&self) -> Option<u64> {
+        if self.len == 0 { None } else { Some(self.deadline(0)) }
+    }
+
+    fn insert(&mut self, deadline: u64, period: u64, callback: fn(usize), data: usize) -> Option<(usize, u32)> {
+        let slot = self.slots.iter().position(|s| !s.active && !s.running)?;
+        let entry = &mut self.slots[slot];
+
+        entry.deadline = deadline;
+        entry.period = period;
+        entry.callback = callback;
+        entry.data = data;
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.active = true;
+        let generation = entry.generation;
+
+        self.push(slot);
+        Some((slot, generation))
+    }
+}
+
+const QUEUE: IrqSpinLock<TimerQueue> = IrqSpinLock::new(TimerQueue::EMPTY);
+static QUEUES: [IrqSpinLock<TimerQueue>; MAX_CPUS] = [QUEUE; MAX_CPUS];
+
+fn rearm(queue: &TimerQueue) {
+    match queue.first() {
+        Some(deadline) => program_event(deadline.saturating_sub(now_ns())),
+        None => stop_event(),
+    }
+}
+
+fn schedule(deadline: u64, period: u64, callback: fn(usize), data: usize) -> Option<TimerId> {
+    let cpu = CPU::id();
+    let mut queue = QUEUES[cpu].lock();
+    let (slot, generation) = queue.insert(deadline, period, callback, data)?;
+
+    /* Only a new earliest deadline needs the hardware reprogrammed */
+    if queue.slots[slot].index == 0 {
+        rearm(&queue);
+    }
+
+    Some(TimerId { cpu: cpu as u16, slot: slot as u16, generation })
+}
+
+/// Calls `callback(data)` from the timer interrupt on this CPU once `delay`
+/// has passed.
+pub fn add_timer(delay: Duration, callback: fn(usize), data: usize) -> Option<TimerId> {
+    schedule(deadline_ns(delay), 0, callback, data)
+}
+
+/// Like `add_timer` with an absolute deadline, as returned by `time::now()`.
+pub fn add_timer_at(deadline: Duration, callback: fn(usize), data: usize) -> Option<TimerId> {
+    schedule(duration_ns(deadline), 0, callback, data)
+}
+
+/// Calls `callback(data)` every `period` until the timer is cancelled.
+pub fn add_periodic_timer(period: Duration, callback: fn(usize), data: usize) -> Option<TimerId> {
+    let deadline = deadline_ns(period);
+    let period = duration_ns(period);
+    schedule(deadline, if period == 0 { 1 } else { period }, callback, data)
+}
+
+/// Returns false if the timer already fired (and was not periodic) or was
+/// cancelled before.
+///
+/// If the callback is running on another CPU this waits for it to return,
+/// so its `data` may be freed afterwards. Called from the callback itself,
+/// it returns right away.
+pub fn cancel_timer(id: TimerId) -> bool {
+    let slot = id.slot as usize;
+    let mut cancelled = false;
+
+    loop {
+        let mut queue = QUEUES[id.cpu as usize].lock();
+
+        if queue.slots[slot].generation != id.generation {
+            return cancelled;
+        }
+
+        if queue.slots[slot].active {
+            let index = queue.slots[slot].index;
+            queue.remove(index);
+            queue.slots[slot].active = false;
+            cancelled = true;
+
+            if index == 0 && id.cpu as usize == CPU::id() {
+                rearm(&queue);
+            }
+        }
+
+        /* On its own CPU the callback can only be running below us */
+        if !queue.slots[slot].running || id.cpu as usize == CPU::id() {
+            return cancelled;
+        }
+
+        drop(queue);
+        spin_loop_hint();
+    }
+}
+
+pub(super) fn run_expired() {
+    let cpu = CPU::id();
+
+    loop {
+        let mut expired = [(0usize, nop as fn(usize), 0usize); MAX_EXPIRED];
+        let mut count = 0;
+
+        {
+            let mut queue = QUEUES[cpu].lock();
+            let now = now_ns();
+
+            while count < MAX_EXPIRED {
+                match queue.first() {
+                    Some(deadline) if deadline <= now => {},
+                    _ => break,
+                }
+
+                let slot = queue.remove(0);
+                let entry = queue.slots[slot];
+                expired[count] = (slot, entry.callback, entry.data);
+                queue.slots[slot].running = true;
+                count += 1;
+
+                if entry.period != 0 {
+                    /* Skip periods that were missed instead of firing them all */
+                    let missed = (now - entry.deadline) / entry.period;
+                    queue.slots[slot].deadline = entry.deadline.saturating_add((missed + 1).saturating_mul(entry.period));
+                    queue.push(slot);
+                } else {
+                    queue.slots[slot].active = false;
+                }
+            }
+
+            if count < MAX_EXPIRED {
+                rearm(&queue);
+            }
+        }
+
+        for &(slot, callback, data) in expired[..count].iter() {
+            callback(data);
+            QUEUES[cpu].lock().slots[slot].running = false;
+        }
+
+        if count < MAX_EXPIRED {
+            break;
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use crate::time::ClockSource;
+
+pub struct TSC;
+
+impl TSC {
+    pub fn read() -> u64 {
+        let low: u32;
+        let high: u32;
+
+        unsafe {
+            llvm_asm!("rdtsc" : "={eax}" (low), "={edx}" (high) ::: "volatile");
+        }
+
+        ((high as u64) << 32) | (low as u64)
+    }
+}
+
+pub struct TSCClockSource {
+    frequency: u64,
+}
+
+impl TSCClockSource {
+    pub fn new(frequency: u64) -> Self {
+        TSCClockSource { frequency }
+    }
+}
+
+impl ClockSource for TSCClockSource {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn read(&self) -> u64 {
+        TSC::read()
+    }
+}
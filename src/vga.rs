@@ -0,0 +1,361 @@
+#[allow(dead_code)]
+
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+use crate::console::Console;
+use crate::sync::IrqSpinLock;
+use crate::vm::VM;
+
+const BUFFER: u64       = 0xb8000;
+pub const WIDTH: usize  = 80;
+pub const HEIGHT: usize = 25;
+
+/* CRT controller, colour mode ports */
+const CRTC_INDEX: u16   = 0x3d4;
+const CRTC_DATA: u16    = 0x3d5;
+
+/* CRTC registers */
+const CURSOR_START: u8      = 0x0a;
+const CURSOR_END: u8        = 0x0b;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8  = 0x0f;
+
+const CURSOR_DISABLE: u8    = 1 << 5;
+/* Underline shaped cursor */
+const CURSOR_FIRST_LINE: u8 = 14;
+const CURSOR_LAST_LINE: u8  = 15;
+
+const ESCAPE: u8 = 0x1b;
+const MAX_PARAMS: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum VGAColor {
+    VGA_COLOR_BLACK = 0,
+    VGA_COLOR_BLUE = 1,
+    VGA_COLOR_GREEN = 2,
+    VGA_COLOR_CYAN = 3,
+    VGA_COLOR_RED = 4,
+    VGA_COLOR_MAGENTA = 5,
+    VGA_COLOR_BROWN = 6,
+    VGA_COLOR_LIGHT_GREY = 7,
+    VGA_COLOR_DARK_GREY = 8,
+    VGA_COLOR_LIGHT_BLUE = 9,
+    VGA_COLOR_LIGHT_GREEN = 10,
+    VGA_COLOR_LIGHT_CYAN = 11,
+    VGA_COLOR_LIGHT_RED = 12,
+    VGA_COLOR_LIGHT_MAGENTA = 13,
+    VGA_COLOR_YELLOW = 14,
+    VGA_COLOR_WHITE = 15,
+}
+
+/* ANSI colour numbers are ordered red/green/blue bits, VGA ones blue/green/red */
+const ANSI_TO_VGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+const fn attribute(foreground: VGAColor, background: VGAColor) -> u8 {
+    ((background as u8) << 4) | (foreground as u8)
+}
+
+const DEFAULT_ATTRIBUTE: u8 = attribute(VGAColor::VGA_COLOR_LIGHT_GREY, VGAColor::VGA_COLOR_BLACK);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /* Got ESC */
+    Start,
+    /* Inside ESC [ ... */
+    Csi,
+}
+
+struct Screen {
+    row: usize,
+    column: usize,
+    attribute: u8,
+    escape: Escape,
+    params: [usize; MAX_PARAMS],
+    count: usize,
+}
+
+static SCREEN: IrqSpinLock<Screen> = IrqSpinLock::new(Screen {
+    row: 0,
+    column: 0,
+    attribute: DEFAULT_ATTRIBUTE,
+    escape: Escape::None,
+    params: [0; MAX_PARAMS],
+    count: 0,
+});
+
+/// The 80x25 VGA text mode buffer.
+///
+/// Output understands the common ANSI sequences: SGR colours (30-37, 40-47,
+/// 90-97, 100-107, bold as bright, 0 to reset), cursor movement (A-D, H/f),
+/// erase in display (J) and erase in line (K).
+pub struct VGA;
+
+impl VGA {
+    fn cell(row: usize, column: usize) -> *mut u16 {
+        (VM::phys_to_virt(BUFFER) as *mut u16).wrapping_add(row * WIDTH + column)
+    }
+
+    fn put(row: usize, column: usize, byte: u8, attribute: u8) {
+        unsafe { VGA::cell(row, column).write_volatile(((attribute as u16) << 8) | byte as u16) }
+    }
+
+    fn crtc_write(register: u8, value: u8) {
+        unsafe {
+            PortWrite::write_to_port(CRTC_INDEX, register);
+            PortWrite::write_to_port(CRTC_DATA, value);
+        }
+    }
+
+    fn crtc_read(register: u8) -> u8 {
+        unsafe {
+            PortWrite::write_to_port(CRTC_INDEX, register);
+            PortRead::read_from_port(CRTC_DATA)
+        }
+    }
+
+    fn clear_range(row: usize, from: usize, to: usize, attribute: u8) {
+        for column in from..to {
+            VGA::put(row, column, b' ', attribute);
+        }
+    }
+
+    fn scroll(screen: &mut Screen) {
+        for row in 1..HEIGHT {
+            for column in 0..WIDTH {
+                unsafe {
+                    let value = VGA::cell(row, column).read_volatile();
+                    VGA::cell(row - 1, column).write_volatile(value);
+                }
+            }
+        }
+
+        VGA::clear_range(HEIGHT - 1, 0, WIDTH, screen.attribute);
+        screen.row = HEIGHT - 1;
+    }
+
+    fn newline(screen: &mut Screen) {
+        screen.column = 0;
+        screen.row += 1;
+
+        if screen.row == HEIGHT {
+            VGA::scroll(screen);
+        }
+    }
+
+    fn update_cursor(screen: &Screen) {
+        let position = (screen.row * WIDTH + screen.column.min(WIDTH - 1)) as u16;
+
+        VGA::crtc_write(CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        VGA::crtc_write(CURSOR_LOCATION_LOW, position as u8);
+    }
+
+    /* Parameter `index`, with `default` for missing or zero ones */
+    fn param(screen: &Screen, index: usize, default: usize) -> usize {
+        if index < screen.count && screen.params[index] != 0 {
+            screen.params[index]
+        } else {
+            default
+        }
+    }
+
+    fn select_graphic_rendition(screen: &mut Screen) {
+        /* ESC[m is a reset */
+        let count = screen.count.max(1);
+
+        for index in 0..count {
+            let value = if index < screen.count { screen.params[index] } else { 0 };
+            let foreground = screen.attribute & 0x0f;
+            let background = screen.attribute & 0xf0;
+
+            screen.attribute = match value {
+                0 => DEFAULT_ATTRIBUTE,
+                1 => screen.attribute | 0x08,
+                22 => screen.attribute & !0x08,
+                30..=37 => background | (foreground & 0x08) | ANSI_TO_VGA[value - 30],
+                39 => background | (DEFAULT_ATTRIBUTE & 0x0f),
+                40..=47 => (ANSI_TO_VGA[value - 40] << 4) | foreground,
+                49 => (DEFAULT_ATTRIBUTE & 0xf0) | foreground,
+                90..=97 => background | 0x08 | ANSI_TO_VGA[value - 90],
+                100..=107 => ((ANSI_TO_VGA[value - 100] | 0x08) << 4) | foreground,
+                _ => screen.attribute,
+            };
+        }
+    }
+
+    fn erase_display(screen: &Screen) {
+        let (from, to) = match VGA::param(screen, 0, 0) {
+            0 => (screen.row * WIDTH + screen.column, HEIGHT * WIDTH),
+            1 => (0, screen.row * WIDTH + screen.column + 1),
+            _ => (0, HEIGHT * WIDTH),
+        };
+
+        for position in from..to.min(HEIGHT * WIDTH) {
+            VGA::put(position / WIDTH, position % WIDTH, b' ', screen.attribute);
+        }
+    }
+
+    fn erase_line(screen: &Screen) {
+        let (from, to) = match VGA::param(screen, 0, 0) {
+            0 => (screen.column, WIDTH),
+            1 => (0, (screen.column + 1).min(WIDTH)),
+            _ => (0, WIDTH),
+        };
+
+        VGA::clear_range(screen.row, from, to, screen.attribute);
+    }
+
+    fn control_sequence(screen: &mut Screen, command: u8) {
+        match command {
+            b'A' => screen.row = screen.row.saturating_sub(VGA::param(screen, 0, 1)),
+            b'B' => screen.row = (screen.row + VGA::param(screen, 0, 1)).min(HEIGHT - 1),
+            b'C' => screen.column = (screen.column + VGA::param(screen, 0, 1)).min(WIDTH - 1),
+            b'D' => screen.column = screen.column.saturating_sub(VGA::param(screen, 0, 1)),
+            /* 1-based row;column */
+            b'H' | b'f' => {
+                screen.row = VGA::param(screen, 0, 1).min(HEIGHT) - 1;
+                screen.column = VGA::param(screen, 1, 1).min(WIDTH) - 1;
+            },
+            b'J' => {
+                VGA::erase_display(screen);
+                if VGA::param(screen, 0, 0) == 2 {
+                    screen.row = 0;
+                    screen.column = 0;
+                }
+            },
+            b'K' => VGA::erase_line(screen),
+            b'm' => VGA::select_graphic_rendition(screen),
+            /* Anything else is dropped */
+            _ => {},
+        }
+    }
+
+    fn escape_byte(screen: &mut Screen, byte: u8) {
+        match screen.escape {
+            Escape::Start if byte == b'[' => {
+                screen.escape = Escape::Csi;
+                screen.params = [0; MAX_PARAMS];
+                screen.count = 0;
+            },
+            Escape::Start => screen.escape = Escape::None,
+            Escape::Csi => match byte {
+                b'0'..=b'9' => {
+                    if screen.count == 0 {
+                        screen.count = 1;
+                    }
+                    if screen.count <= MAX_PARAMS {
+                        let param = &mut screen.params[screen.count - 1];
+                        *param = param.saturating_mul(10).saturating_add((byte - b'0') as usize);
+                    }
+                },
+                /* An empty first parameter still takes a slot */
+                b';' => screen.count = screen.count.max(1) + 1,
+                /* Final byte */
+                0x40..=0x7e => {
+                    screen.count = screen.count.min(MAX_PARAMS);
+                    screen.escape = Escape::None;
+                    VGA::control_sequence(screen, byte);
+                },
+                _ => {},
+            },
+            Escape::None => {},
+        }
+    }
+
+    fn write_byte(screen: &mut Screen, byte: u8) {
+        if screen.escape != Escape::None {
+            VGA::escape_byte(screen, byte);
+            return;
+        }
+
+        match byte {
+            ESCAPE => screen.escape = Escape::Start,
+            b'\n' => VGA::newline(screen),
+            b'\r' => screen.column = 0,
+            0x08 => screen.column = screen.column.saturating_sub(1),
+            b'\t' => {
+                screen.column = (screen.column + 8) & !7;
+                if screen.column >= WIDTH {
+                    VGA::newline(screen);
+                }
+            },
+            byte => {
+                /* Wrap only once there is something to put on the next line */
+                if screen.column == WIDTH {
+                    VGA::newline(screen);
+                }
+
+                /* Anything outside printable ASCII shows as a block */
+                let byte = if byte >= 0x20 && byte < 0x7f { byte } else { 0xfe };
+
+                VGA::put(screen.row, screen.column, byte, screen.attribute);
+                screen.column += 1;
+            },
+        }
+    }
+
+    pub fn clear() {
+        let mut screen = SCREEN.lock();
+
+        for row in 0..HEIGHT {
+            VGA::clear_range(row, 0, WIDTH, screen.attribute);
+        }
+
+        screen.row = 0;
+        screen.column = 0;
+        VGA::update_cursor(&screen);
+    }
+
+    pub fn write_bytes(bytes: &[u8]) {
+        let mut screen = SCREEN.lock();
+
+        for &byte in bytes {
+            VGA::write_byte(&mut screen, byte);
+        }
+
+        VGA::update_cursor(&screen);
+    }
+
+    /// Colours for everything written from now on.
+    pub fn set_color(foreground: VGAColor, background: VGAColor) {
+        SCREEN.lock().attribute = attribute(foreground, background);
+    }
+
+    /// Moves the output position and the hardware cursor, 0-based.
+    pub fn set_position(row: usize, column: usize) {
+        let mut screen = SCREEN.lock();
+
+        screen.row = row.min(HEIGHT - 1);
+        screen.column = column.min(WIDTH - 1);
+        VGA::update_cursor(&screen);
+    }
+
+    /// Current (row, column).
+    pub fn position() -> (usize, usize) {
+        let screen = SCREEN.lock();
+        (screen.row, screen.column)
+    }
+
+    pub fn enable_cursor() {
+        let start = VGA::crtc_read(CURSOR_START) & 0xc0;
+        let end = VGA::crtc_read(CURSOR_END) & 0xe0;
+
+        VGA::crtc_write(CURSOR_START, start | CURSOR_FIRST_LINE);
+        VGA::crtc_write(CURSOR_END, end | CURSOR_LAST_LINE);
+    }
+
+    pub fn disable_cursor() {
+        VGA::crtc_write(CURSOR_START, CURSOR_DISABLE);
+    }
+}
+
+impl Console for VGA {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        VGA::write_bytes(bytes);
+    }
+}
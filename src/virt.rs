@@ -0,0 +1,192 @@
+//! Pieces `vmx` and `svm` share: the 4-level guest-physical table walk
+//! behind EPT and nested paging, and the bit helpers of the permission
+//! bitmaps.
+//!
+//! The two table formats differ in what makes an entry present and in the
+//! leaf bits, the walk only needs the former. The leaf encoding stays with
+//! `vmx::ept` and `svm::npt`.
+
+use core::marker::PhantomData;
+
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+
+use crate::vm::VM;
+
+/* Entry fields both formats agree on */
+const LARGE_PAGE: u64   = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const LEVELS: usize = 4;
+
+/// Table level of the largest pages, 0 for 4 KiB, 2 for 1 GiB.
+pub(crate) const MAX_PAGE_LEVEL: usize = 2;
+
+/// What makes an entry of a table format present.
+pub(crate) trait EntryFormat {
+    /// Bits of a non-leaf entry, they allow everything and the leaf decides.
+    const TABLE_FLAGS: u64;
+
+    /// Whether `entry` maps a table or a page.
+    fn present(entry: u64) -> bool;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum TableError {
+    TABLE_ERROR_ALREADY_MAPPED,
+    TABLE_ERROR_NO_MEMORY,
+}
+
+/// Bytes mapped by a leaf at `level`.
+pub(crate) fn level_bytes(level: usize) -> u64 {
+    1 << (12 + 9 * level)
+}
+
+/// Address bits of `entry`, the frame or next table it points to.
+pub(crate) fn entry_address(entry: u64) -> u64 {
+    entry & ADDRESS_MASK
+}
+
+/// Large page bit, for leaves above level 0.
+pub(crate) fn large_page(level: usize) -> u64 {
+    if level > 0 { LARGE_PAGE } else { 0 }
+}
+
+fn index(gpa: u64, level: usize) -> usize {
+    ((gpa >> (12 + 9 * level)) & 0x1ff) as usize
+}
+
+/// Splits `size` bytes at `gpa` and `hpa` into the largest pages up to
+/// `max_level` that alignment allows, and calls `map_page` with each page's
+/// addresses and level. Stops at the first error.
+pub(crate) fn for_each_page<E>(gpa: u64, hpa: u64, size: u64, max_level: usize,
+                               mut map_page: impl FnMut(u64, u64, usize) -> Result<(), E>)
+                               -> Result<(), E> {
+    let mut offset = 0;
+
+    while offset < size {
+        let level = (0..=max_level)
+            .rev()
+            .find(|&level| {
+                let bytes = level_bytes(level);
+                (gpa + offset) & (bytes - 1) == 0 && (hpa + offset) & (bytes - 1) == 0 &&
+                    size - offset >= bytes
+            })
+            .unwrap_or(0);
+
+        map_page(gpa + offset, hpa + offset, level)?;
+        offset += level_bytes(level);
+    }
+
+    Ok(())
+}
+
+/// Four level tables in format `F`. Frames come from `allocator` and are
+/// accessed at `phys_offset` plus their physical address.
+pub(crate) struct GuestTables<A: FrameAllocator<Size4KiB>, F: EntryFormat> {
+    allocator: A,
+    phys_offset: u64,
+    root: u64,
+    format: PhantomData<F>,
+}
+
+impl<A: FrameAllocator<Size4KiB>, F: EntryFormat> GuestTables<A, F> {
+    pub fn new(mut allocator: A, phys_offset: u64) -> Option<Self> {
+        let root = Self::alloc_table(&mut allocator, phys_offset)?;
+
+        Some(GuestTables {
+            allocator,
+            phys_offset,
+            root,
+            format: PhantomData,
+        })
+    }
+
+    fn alloc_table(allocator: &mut A, phys_offset: u64) -> Option<u64> {
+        let frame = allocator.allocate_frame()?.start_address().as_u64();
+
+        unsafe { core::ptr::write_bytes((phys_offset + frame) as *mut u8, 0, 4096) };
+        Some(frame)
+    }
+
+    fn entry(&self, table: u64, index: usize) -> *mut u64 {
+        ((self.phys_offset + table) as *mut u64).wrapping_add(index)
+    }
+
+    /// Physical address of the PML4.
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Writes `leaf` for `gpa` at `level`, allocating the tables above it.
+    pub fn map_leaf(&mut self, gpa: u64, level: usize, leaf: u64) -> Result<(), TableError> {
+        let mut table = self.root;
+
+        for level in (level + 1..LEVELS).rev() {
+            let entry = self.entry(table, index(gpa, level));
+            let value = unsafe { entry.read() };
+
+            table = if !F::present(value) {
+                let next = Self::alloc_table(&mut self.allocator, self.phys_offset)
+                    .ok_or(TableError::TABLE_ERROR_NO_MEMORY)?;
+
+                unsafe { entry.write(next | F::TABLE_FLAGS) };
+                next
+            } else if value & LARGE_PAGE != 0 {
+                return Err(TableError::TABLE_ERROR_ALREADY_MAPPED);
+            } else {
+                value & ADDRESS_MASK
+            };
+        }
+
+        let entry = self.entry(table, index(gpa, level));
+
+        /* Present leaf, or a table where a large page should go */
+        if F::present(unsafe { entry.read() }) {
+            return Err(TableError::TABLE_ERROR_ALREADY_MAPPED);
+        }
+
+        unsafe { entry.write(leaf) };
+        Ok(())
+    }
+
+    /// The leaf entry for `gpa` and its level.
+    pub fn leaf(&self, gpa: u64) -> Option<(*mut u64, usize)> {
+        let mut table = self.root;
+
+        for level in (0..LEVELS).rev() {
+            let entry = self.entry(table, index(gpa, level));
+            let value = unsafe { entry.read() };
+
+            if !F::present(value) {
+                return None;
+            }
+
+            if level == 0 || (level <= MAX_PAGE_LEVEL && value & LARGE_PAGE != 0) {
+                return Some((entry, level));
+            }
+
+            table = value & ADDRESS_MASK;
+        }
+
+        None
+    }
+}
+
+/// Sets bit `index` of the bitmap at physical `base`, which may span
+/// several contiguous pages.
+pub(crate) fn set_bit(base: u64, index: usize, value: bool) {
+    let byte = (VM::phys_to_virt(base) as *mut u8).wrapping_add(index / 8);
+    let mask = 1 << (index % 8);
+
+    unsafe {
+        match value {
+            true => byte.write(byte.read() | mask),
+            false => byte.write(byte.read() & !mask),
+        }
+    }
+}
+
+pub(crate) fn bit(base: u64, index: usize) -> bool {
+    let byte = (VM::phys_to_virt(base) as *const u8).wrapping_add(index / 8);
+    unsafe { byte.read() & (1 << (index % 8)) != 0 }
+}
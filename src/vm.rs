@@ -1,19 +1,36 @@
-use x86_64::structures::paging::PhysFrame;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::page_alloc::page_alloc;
+use crate::sync::Once;
 
 pub struct VM;
 
-static mut PHYS_OFFSET: Option<u64> = None;
-static mut ROOT: Option<PhysFrame> = None;
+static PHYS_OFFSET: Once<u64> = Once::new();
+static ROOT: Once<PhysFrame> = Once::new();
+
+/* Virtual window for device memory that must not go through the cacheable
+ * physical memory mapping set up by the bootloader. */
+const MMIO_BASE: u64    = 0x4000_0000_0000;
+const MMIO_SIZE: u64    = 0x4000_0000;
+
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_BASE);
+
+/* Kernel stacks, each with an unmapped guard page below it */
+const STACK_BASE: u64   = 0x5000_0000_0000;
+const STACK_SIZE: u64   = 0x4000_0000;
+
+static STACK_NEXT: AtomicU64 = AtomicU64::new(STACK_BASE);
 
 impl VM {
     pub fn phys_offset() -> u64 {
-        unsafe { PHYS_OFFSET.unwrap() }
+        *PHYS_OFFSET.get().unwrap()
     }
 
     pub fn set_phys_offset(offset: u64) {
-        unsafe {
-            PHYS_OFFSET.replace(offset);
-        }
+        PHYS_OFFSET.call_once(|| offset);
     }
 
     pub fn phys_to_virt(phys: u64) -> u64 {
@@ -25,6 +42,78 @@ impl VM {
     }
 
     pub fn root_mm() -> PhysFrame {
-        unsafe { ROOT.unwrap() }
+        *ROOT.get().unwrap()
+    }
+
+    fn active_page_table() -> OffsetPageTable<'static> {
+        let (frame, _) = Cr3::read();
+        let table = VM::phys_to_virt(frame.start_address().as_u64()) as *mut PageTable;
+
+        unsafe { OffsetPageTable::new(&mut *table, VirtAddr::new(VM::phys_offset())) }
+    }
+
+    /// Maps the 4 KiB page at `virt` to the frame at `phys` in the active
+    /// address space. Page table pages come from `page_alloc`.
+    pub fn map(virt: u64, phys: u64, flags: PageTableFlags) -> Option<()> {
+        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(virt));
+        let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+        let mut table = VM::active_page_table();
+
+        unsafe {
+            table.map_to(page, frame, flags | PageTableFlags::PRESENT, &mut *page_alloc())
+                 .ok()?
+                 .flush();
+        }
+
+        Some(())
+    }
+
+    /// Maps `size` bytes of device memory at `phys` uncached and returns the
+    /// virtual address of `phys`.
+    pub fn map_mmio(phys: u64, size: u64) -> Option<u64> {
+        let start = phys & !0xfff;
+        let end = (phys + size + 0xfff) & !0xfff;
+        let virt = MMIO_NEXT.fetch_add(end - start, Ordering::Relaxed);
+
+        if virt + (end - start) > MMIO_BASE + MMIO_SIZE {
+            return None;
+        }
+
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        for offset in (0..end - start).step_by(4096) {
+            VM::map(virt + offset, start + offset, flags)?;
+        }
+
+        Some(virt + (phys - start))
+    }
+
+    /// Allocates and maps a stack of `pages` pages and returns its top.
+    ///
+    /// The page below the stack is left unmapped so an overflow faults
+    /// instead of corrupting memory. Stacks are never freed.
+    pub fn alloc_stack(pages: u64) -> Option<u64> {
+        let size = (pages + 1) * 4096;
+        let guard = STACK_NEXT.fetch_add(size, Ordering::Relaxed);
+
+        if guard + size > STACK_BASE + STACK_SIZE {
+            return None;
+        }
+
+        let flags = PageTableFlags::WRITABLE;
+        for page in 1..=pages {
+            let frame = page_alloc().allocate_frame()?;
+            VM::map(guard + page * 4096, frame.start_address().as_u64(), flags)?;
+        }
+
+        Some(guard + size)
+    }
+
+    /// Allocates `count` zeroed, physically contiguous frames and returns
+    /// the physical address of the first. They are never freed.
+    pub fn alloc_pages(count: usize) -> Option<u64> {
+        let frame = page_alloc().allocate_contiguous(count)?.start_address().as_u64();
+
+        unsafe { core::ptr::write_bytes(VM::phys_to_virt(frame) as *mut u8, 0, count * 4096) };
+        Some(frame)
     }
 }
@@ -0,0 +1,128 @@
+//! MSR and I/O bitmaps, selecting which RDMSR/WRMSR and IN/OUT instructions
+//! cause VM exits.
+//!
+//! Both start out empty, nothing exits. The pages come from `page_alloc` and
+//! are never freed, a bitmap is meant to live as long as its guest.
+
+use core::ops::RangeInclusive;
+
+use crate::msr::MSR;
+use crate::virt::{bit, set_bit};
+use crate::vm::VM;
+
+/* Offsets of the four 1 KiB regions of the MSR bitmap */
+const MSR_READ_LOW: usize   = 0;
+const MSR_READ_HIGH: usize  = 1024;
+const MSR_WRITE_LOW: usize  = 2048;
+const MSR_WRITE_HIGH: usize = 3072;
+
+/* MSR ranges the bitmap covers, accesses to others always exit */
+const MSR_LOW_START: u32    = 0x0000_0000;
+const MSR_LOW_END: u32      = 0x0000_1fff;
+const MSR_HIGH_START: u32   = 0xc000_0000;
+const MSR_HIGH_END: u32     = 0xc000_1fff;
+
+/// The MSR bitmap, for the MSR_BITMAP field with "use MSR bitmaps" set in
+/// the primary processor-based controls.
+pub struct MsrBitmap {
+    page: u64,
+}
+
+impl MsrBitmap {
+    pub fn new() -> Option<Self> {
+        Some(MsrBitmap { page: VM::alloc_pages(1)? })
+    }
+
+    /// Physical address for the MSR_BITMAP field.
+    pub fn address(&self) -> u64 {
+        self.page
+    }
+
+    /* Bit offsets of `msr` in the read and write regions */
+    fn offsets(msr: u32) -> Option<(usize, usize)> {
+        let (read, write, start) = match msr {
+            MSR_LOW_START..=MSR_LOW_END => (MSR_READ_LOW, MSR_WRITE_LOW, MSR_LOW_START),
+            MSR_HIGH_START..=MSR_HIGH_END => (MSR_READ_HIGH, MSR_WRITE_HIGH, MSR_HIGH_START),
+            _ => return None,
+        };
+        let index = (msr - start) as usize;
+
+        Some((read * 8 + index, write * 8 + index))
+    }
+
+    /// Sets whether RDMSR and WRMSR of `msr` exit. Fails for MSRs outside
+    /// 0-0x1fff and 0xc0000000-0xc0001fff, those always exit.
+    pub fn intercept_raw(&mut self, msr: u32, read: bool, write: bool) -> Option<()> {
+        let (read_bit, write_bit) = MsrBitmap::offsets(msr)?;
+
+        set_bit(self.page, read_bit, read);
+        set_bit(self.page, write_bit, write);
+        Some(())
+    }
+
+    pub fn intercept(&mut self, msr: MSR, read: bool, write: bool) -> Option<()> {
+        self.intercept_raw(msr as u32, read, write)
+    }
+
+    /// Whether RDMSR of `msr` exits.
+    pub fn intercepts_read(&self, msr: u32) -> bool {
+        MsrBitmap::offsets(msr).map_or(true, |(read, _)| bit(self.page, read))
+    }
+
+    /// Whether WRMSR of `msr` exits.
+    pub fn intercepts_write(&self, msr: u32) -> bool {
+        MsrBitmap::offsets(msr).map_or(true, |(_, write)| bit(self.page, write))
+    }
+
+    /// Has every access to the covered MSRs exit, or none.
+    pub fn intercept_all(&mut self, intercept: bool) {
+        let fill = if intercept { 0xff } else { 0 };
+        unsafe { core::ptr::write_bytes(VM::phys_to_virt(self.page) as *mut u8, fill, 4096) };
+    }
+}
+
+/// The I/O bitmaps, for the IO_BITMAP_A and IO_BITMAP_B fields with "use I/O
+/// bitmaps" set in the primary processor-based controls. A covers ports
+/// 0-0x7fff, B 0x8000-0xffff.
+pub struct IoBitmap {
+    pages: [u64; 2],
+}
+
+impl IoBitmap {
+    pub fn new() -> Option<Self> {
+        let base = VM::alloc_pages(2)?;
+        Some(IoBitmap { pages: [base, base + 4096] })
+    }
+
+    /// Physical address for the IO_BITMAP_A field.
+    pub fn address_a(&self) -> u64 {
+        self.pages[0]
+    }
+
+    /// Physical address for the IO_BITMAP_B field.
+    pub fn address_b(&self) -> u64 {
+        self.pages[1]
+    }
+
+    fn page(&self, port: u16) -> (u64, usize) {
+        (self.pages[(port >> 15) as usize], (port & 0x7fff) as usize)
+    }
+
+    /// Sets whether IN and OUT to `ports` exit. An access that touches
+    /// several ports exits if any of them is intercepted.
+    pub fn intercept(&mut self, ports: RangeInclusive<u16>, intercept: bool) {
+        for port in ports {
+            let (page, index) = self.page(port);
+            set_bit(page, index, intercept);
+        }
+    }
+
+    pub fn intercept_port(&mut self, port: u16, intercept: bool) {
+        self.intercept(port..=port, intercept)
+    }
+
+    pub fn intercepts(&self, port: u16) -> bool {
+        let (page, index) = self.page(port);
+        bit(page, index)
+    }
+}
@@ -0,0 +1,227 @@
+//! Decoding of the VMX capability MSRs.
+//!
+//! The decoders work on raw MSR values so they can be fed recorded ones;
+//! `VmxMsrs::read()` gets them from the running CPU.
+
+use crate::msr::{MemoryType, MSR};
+
+/// The VMX controls that have allowed-0/allowed-1 capability MSRs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmxControl {
+    VMX_CONTROL_PINBASED,
+    VMX_CONTROL_PROCBASED,
+    VMX_CONTROL_PROCBASED2,
+    VMX_CONTROL_EXIT,
+    VMX_CONTROL_ENTRY,
+}
+
+/* Primary processor-based control enabling the secondary ones */
+const ACTIVATE_SECONDARY_CONTROLS: u32 = 1 << 31;
+
+/// IA32_VMX_BASIC.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VmxBasic(pub u64);
+
+impl VmxBasic {
+    /// Has to be in the first 31 bits of VMXON and VMCS regions.
+    pub fn revision_id(self) -> u32 {
+        (self.0 & 0x7fffffff) as u32
+    }
+
+    /// Bytes to allocate for VMXON and VMCS regions, at most 4096.
+    pub fn region_size(self) -> usize {
+        ((self.0 >> 32) & 0x1fff) as usize
+    }
+
+    /// Regions and structures referenced from the VMCS must be below 4 GiB.
+    pub fn physical_address_32bit(self) -> bool {
+        self.0 & (1 << 48) != 0
+    }
+
+    pub fn dual_monitor(self) -> bool {
+        self.0 & (1 << 49) != 0
+    }
+
+    /// Memory type the CPU uses to access the VMCS and the structures it
+    /// points to.
+    pub fn memory_type(self) -> Option<MemoryType> {
+        match (self.0 >> 50) & 0xf {
+            0 => Some(MemoryType::MEMORY_TYPE_UC),
+            6 => Some(MemoryType::MEMORY_TYPE_WB),
+            _ => None,
+        }
+    }
+
+    /// VM exits due to INS/OUTS report instruction information.
+    pub fn ins_outs_info(self) -> bool {
+        self.0 & (1 << 54) != 0
+    }
+
+    /// The IA32_VMX_TRUE_*_CTLS MSRs exist and have to be used instead of
+    /// the plain ones, they allow clearing some default-1 controls.
+    pub fn true_controls(self) -> bool {
+        self.0 & (1 << 55) != 0
+    }
+
+    /// Hardware exceptions may be injected without an error code.
+    pub fn no_error_code_required(self) -> bool {
+        self.0 & (1 << 56) != 0
+    }
+}
+
+/// The settings a control allows: allowed-0 bits must be 1, bits clear in
+/// allowed-1 must be 0.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AllowedControls {
+    pub allowed0: u32,
+    pub allowed1: u32,
+}
+
+impl AllowedControls {
+    /// From a capability MSR, allowed-0 in the low and allowed-1 in the
+    /// high half.
+    pub fn from_msr(value: u64) -> Self {
+        AllowedControls {
+            allowed0: value as u32,
+            allowed1: (value >> 32) as u32,
+        }
+    }
+
+    /// Bits that have to be set.
+    pub fn must_be_one(self) -> u32 {
+        self.allowed0
+    }
+
+    /// Bits that can be set.
+    pub fn may_be_one(self) -> u32 {
+        self.allowed1
+    }
+
+    /// Whether all of `bits` can be set.
+    pub fn allows(self, bits: u32) -> bool {
+        bits & !self.allowed1 == 0
+    }
+
+    /// `desired` with the required bits added. Fails with the desired bits
+    /// the CPU does not support.
+    pub fn adjust(self, desired: u32) -> Result<u32, u32> {
+        match desired & !self.allowed1 {
+            0 => Ok(desired | self.allowed0),
+            unsupported => Err(unsupported),
+        }
+    }
+}
+
+/// Raw values of the VMX capability MSRs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct VmxMsrs {
+    pub basic: u64,
+    pub pinbased: u64,
+    pub procbased: u64,
+    pub exit: u64,
+    pub entry: u64,
+    pub true_pinbased: u64,
+    pub true_procbased: u64,
+    pub true_exit: u64,
+    pub true_entry: u64,
+    pub procbased2: u64,
+}
+
+impl VmxMsrs {
+    /// Reads the MSRs of the running CPU, VMX has to be supported. MSRs the
+    /// CPU does not have are left 0.
+    pub unsafe fn read() -> Self {
+        let basic = VmxBasic(MSR::IA32_VMX_BASIC.read());
+        let procbased = MSR::IA32_VMX_PROCBASED_CTLS.read();
+        let mut msrs = VmxMsrs {
+            basic: basic.0,
+            pinbased: MSR::IA32_VMX_PINBASED_CTLS.read(),
+            procbased,
+            exit: MSR::IA32_VMX_EXIT_CTLS.read(),
+            entry: MSR::IA32_VMX_ENTRY_CTLS.read(),
+            ..VmxMsrs::default()
+        };
+
+        if basic.true_controls() {
+            msrs.true_pinbased = MSR::IA32_VMX_TRUE_PINBASED_CTLS.read();
+            msrs.true_procbased = MSR::IA32_VMX_TRUE_PROCBASED_CTLS.read();
+            msrs.true_exit = MSR::IA32_VMX_TRUE_EXIT_CTLS.read();
+            msrs.true_entry = MSR::IA32_VMX_TRUE_ENTRY_CTLS.read();
+        }
+
+        if AllowedControls::from_msr(procbased).allows(ACTIVATE_SECONDARY_CONTROLS) {
+            msrs.procbased2 = MSR::IA32_VMX_PROCBASED_CTLS2.read();
+        }
+
+        msrs
+    }
+}
+
+/// Decoded VMX capabilities.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VmxCaps {
+    pub basic: VmxBasic,
+    pub pinbased: AllowedControls,
+    pub procbased: AllowedControls,
+    pub procbased2: AllowedControls,
+    pub exit: AllowedControls,
+    pub entry: AllowedControls,
+}
+
+impl VmxCaps {
+    /// Uses the TRUE control MSRs when IA32_VMX_BASIC says so. Secondary
+    /// controls are all disallowed if they cannot be activated.
+    pub fn new(msrs: &VmxMsrs) -> Self {
+        let basic = VmxBasic(msrs.basic);
+        let pick = |plain, true_msr| {
+            AllowedControls::from_msr(if basic.true_controls() { true_msr } else { plain })
+        };
+
+        let procbased = pick(msrs.procbased, msrs.true_procbased);
+        let procbased2 = if procbased.allows(ACTIVATE_SECONDARY_CONTROLS) {
+            /* All secondary controls are default-0, allowed-0 is 0 */
+            AllowedControls::from_msr(msrs.procbased2)
+        } else {
+            AllowedControls { allowed0: 0, allowed1: 0 }
+        };
+
+        VmxCaps {
+            basic,
+            pinbased: pick(msrs.pinbased, msrs.true_pinbased),
+            procbased,
+            procbased2,
+            exit: pick(msrs.exit, msrs.true_exit),
+            entry: pick(msrs.entry, msrs.true_entry),
+        }
+    }
+
+    /// Capabilities of the running CPU, VMX has to be supported.
+    pub unsafe fn read() -> Self {
+        VmxCaps::new(&VmxMsrs::read())
+    }
+
+    pub fn allowed(&self, control: VmxControl) -> AllowedControls {
+        match control {
+            VmxControl::VMX_CONTROL_PINBASED => self.pinbased,
+            VmxControl::VMX_CONTROL_PROCBASED => self.procbased,
+            VmxControl::VMX_CONTROL_PROCBASED2 => self.procbased2,
+            VmxControl::VMX_CONTROL_EXIT => self.exit,
+            VmxControl::VMX_CONTROL_ENTRY => self.entry,
+        }
+    }
+
+    /// A value for `control` with all `desired` bits and the ones the CPU
+    /// requires. Fails with the desired bits it does not support.
+    pub fn adjust(&self, control: VmxControl, desired: u32) -> Result<u32, u32> {
+        self.allowed(control).adjust(desired)
+    }
+}
+
+/// `value` with the bits IA32_VMX_CR*_FIXED0 requires set and those
+/// IA32_VMX_CR*_FIXED1 forbids clear. Fails with the bits that are both.
+pub fn apply_fixed(value: u64, fixed0: u64, fixed1: u64) -> Result<u64, u64> {
+    match fixed0 & !fixed1 {
+        0 => Ok((value | fixed0) & fixed1),
+        conflict => Err(conflict),
+    }
+}
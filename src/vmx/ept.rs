@@ -0,0 +1,239 @@
+//! Extended page tables.
+//!
+//! Four level tables, like the regular ones, translating guest-physical to
+//! host-physical addresses. Table frames come from any `FrameAllocator` and
+//! are accessed at `phys_offset` plus their physical address, the way
+//! `OffsetPageTable` does it; `Ept::new()` uses `page_alloc` and the
+//! bootloader's physical memory mapping.
+
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+
+use crate::msr::MemoryType;
+use crate::virt::{self, EntryFormat, GuestTables, TableError};
+use crate::vm::VM;
+
+pub use crate::page_alloc::PageAllocFrames;
+
+/* Access rights */
+pub const EPT_READ: u64     = 1 << 0;
+pub const EPT_WRITE: u64    = 1 << 1;
+pub const EPT_EXECUTE: u64  = 1 << 2;
+pub const EPT_RWX: u64      = EPT_READ | EPT_WRITE | EPT_EXECUTE;
+
+/* Entry fields */
+const MEMORY_TYPE_SHIFT: u64    = 3;
+const MEMORY_TYPE_MASK: u64     = 0x7 << MEMORY_TYPE_SHIFT;
+const IGNORE_PAT: u64           = 1 << 6;
+const ACCESSED: u64             = 1 << 8;
+const DIRTY: u64                = 1 << 9;
+
+/* EPTP fields */
+const EPTP_MEMORY_TYPE_WB: u64  = 6;
+const EPTP_WALK_LENGTH_4: u64   = 3 << 3;
+const EPTP_ACCESSED_DIRTY: u64  = 1 << 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EptPageSize {
+    EPT_PAGE_4K,
+    EPT_PAGE_2M,
+    EPT_PAGE_1G,
+}
+
+impl EptPageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            EptPageSize::EPT_PAGE_4K => 0x1000,
+            EptPageSize::EPT_PAGE_2M => 0x20_0000,
+            EptPageSize::EPT_PAGE_1G => 0x4000_0000,
+        }
+    }
+
+    /* Table level holding the leaf entry, 0 for the PT */
+    fn level(self) -> usize {
+        match self {
+            EptPageSize::EPT_PAGE_4K => 0,
+            EptPageSize::EPT_PAGE_2M => 1,
+            EptPageSize::EPT_PAGE_1G => 2,
+        }
+    }
+
+    fn from_level(level: usize) -> Self {
+        match level {
+            0 => EptPageSize::EPT_PAGE_4K,
+            1 => EptPageSize::EPT_PAGE_2M,
+            _ => EptPageSize::EPT_PAGE_1G,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EptError {
+    /// Addresses or size not aligned to the page size.
+    EPT_ERROR_UNALIGNED,
+    /// Part of the range is mapped already.
+    EPT_ERROR_ALREADY_MAPPED,
+    /// No access at all, or write without read, which is an EPT
+    /// misconfiguration. Same for the UC- memory type.
+    EPT_ERROR_INVALID_MAPPING,
+    /// The allocator ran out of frames.
+    EPT_ERROR_NO_MEMORY,
+}
+
+/// Result of a guest-physical address lookup.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EptTranslation {
+    pub hpa: u64,
+    pub access: u64,
+    pub memory_type: Option<MemoryType>,
+    pub page_size: EptPageSize,
+    pub accessed: bool,
+    pub dirty: bool,
+}
+
+impl From<TableError> for EptError {
+    fn from(error: TableError) -> Self {
+        match error {
+            TableError::TABLE_ERROR_ALREADY_MAPPED => EptError::EPT_ERROR_ALREADY_MAPPED,
+            TableError::TABLE_ERROR_NO_MEMORY => EptError::EPT_ERROR_NO_MEMORY,
+        }
+    }
+}
+
+/* Any access right makes an entry present */
+struct EptFormat;
+
+impl EntryFormat for EptFormat {
+    const TABLE_FLAGS: u64 = EPT_RWX;
+
+    fn present(entry: u64) -> bool {
+        entry & EPT_RWX != 0
+    }
+}
+
+pub struct Ept<A: FrameAllocator<Size4KiB>> {
+    tables: GuestTables<A, EptFormat>,
+    accessed_dirty: bool,
+    max_page: EptPageSize,
+}
+
+impl Ept<PageAllocFrames> {
+    /// Empty tables with frames from `page_alloc`.
+    pub fn new() -> Option<Self> {
+        Ept::with_allocator(PageAllocFrames, VM::phys_offset())
+    }
+}
+
+impl<A: FrameAllocator<Size4KiB>> Ept<A> {
+    /// Empty tables with frames from `allocator`, accessed at
+    /// `phys_offset` + their physical address.
+    pub fn with_allocator(allocator: A, phys_offset: u64) -> Option<Self> {
+        Some(Ept {
+            tables: GuestTables::new(allocator, phys_offset)?,
+            accessed_dirty: false,
+            max_page: EptPageSize::EPT_PAGE_2M,
+        })
+    }
+
+    /// Physical address of the PML4.
+    pub fn root(&self) -> u64 {
+        self.tables.root()
+    }
+
+    /// Largest pages `map` uses, 2 MiB by default. Check
+    /// IA32_VMX_EPT_VPID_CAP before allowing 1 GiB ones.
+    pub fn set_max_page_size(&mut self, size: EptPageSize) {
+        self.max_page = size;
+    }
+
+    /// Has the CPU set accessed and dirty flags, if IA32_VMX_EPT_VPID_CAP
+    /// allows it. Takes effect through the next `eptp()`.
+    pub fn set_accessed_dirty(&mut self, enable: bool) {
+        self.accessed_dirty = enable;
+    }
+
+    /// The EPT pointer for the VMCS: write-back tables, 4-level walk.
+    pub fn eptp(&self) -> u64 {
+        let mut eptp = self.root() | EPTP_WALK_LENGTH_4 | EPTP_MEMORY_TYPE_WB;
+
+        if self.accessed_dirty {
+            eptp |= EPTP_ACCESSED_DIRTY;
+        }
+
+        eptp
+    }
+
+    fn check(access: u64, memory_type: MemoryType) -> Result<(), EptError> {
+        let write_without_read = access & EPT_WRITE != 0 && access & EPT_READ == 0;
+
+        if access & !EPT_RWX != 0 || access == 0 || write_without_read ||
+           memory_type == MemoryType::MEMORY_TYPE_UC_MINUS {
+            return Err(EptError::EPT_ERROR_INVALID_MAPPING);
+        }
+
+        Ok(())
+    }
+
+    /// Maps one page of `size` at `gpa` to `hpa`.
+    pub fn map_page(&mut self, gpa: u64, hpa: u64, size: EptPageSize,
+                    access: u64, memory_type: MemoryType) -> Result<(), EptError> {
+        Self::check(access, memory_type)?;
+
+        if (gpa | hpa) & (size.bytes() - 1) != 0 {
+            return Err(EptError::EPT_ERROR_UNALIGNED);
+        }
+
+        let leaf = hpa | access | ((memory_type as u64) << MEMORY_TYPE_SHIFT) | IGNORE_PAT |
+                   virt::large_page(size.level());
+
+        Ok(self.tables.map_leaf(gpa, size.level(), leaf)?)
+    }
+
+    /// Maps `size` bytes at `gpa` to `hpa`, with the largest pages up to the
+    /// configured maximum that alignment allows. On error the part mapped so
+    /// far stays.
+    pub fn map(&mut self, gpa: u64, hpa: u64, size: u64,
+               access: u64, memory_type: MemoryType) -> Result<(), EptError> {
+        if (gpa | hpa | size) & 0xfff != 0 {
+            return Err(EptError::EPT_ERROR_UNALIGNED);
+        }
+
+        virt::for_each_page(gpa, hpa, size, self.max_page.level(), |gpa, hpa, level| {
+            self.map_page(gpa, hpa, EptPageSize::from_level(level), access, memory_type)
+        })
+    }
+
+    /* The leaf entry for `gpa` and the size it maps */
+    fn leaf(&self, gpa: u64) -> Option<(*mut u64, EptPageSize)> {
+        let (entry, level) = self.tables.leaf(gpa)?;
+        Some((entry, EptPageSize::from_level(level)))
+    }
+
+    /// Walks the tables like the CPU would for `gpa`.
+    pub fn translate(&self, gpa: u64) -> Option<EptTranslation> {
+        let (entry, page_size) = self.leaf(gpa)?;
+        let value = unsafe { entry.read() };
+        let offset = gpa & (page_size.bytes() - 1);
+
+        Some(EptTranslation {
+            hpa: (virt::entry_address(value) & !(page_size.bytes() - 1)) + offset,
+            access: value & EPT_RWX,
+            memory_type: MemoryType::from_bits((value & MEMORY_TYPE_MASK) >> MEMORY_TYPE_SHIFT),
+            page_size,
+            accessed: value & ACCESSED != 0,
+            dirty: value & DIRTY != 0,
+        })
+    }
+
+    /// Clears the accessed and dirty flags of the page holding `gpa` and
+    /// returns what they were. The guest TLB has to be flushed with INVEPT
+    /// for the CPU to set them again.
+    pub fn clear_accessed_dirty(&mut self, gpa: u64) -> Option<(bool, bool)> {
+        let (entry, _) = self.leaf(gpa)?;
+
+        unsafe {
+            let value = entry.read();
+            entry.write(value & !(ACCESSED | DIRTY));
+            Some((value & ACCESSED != 0, value & DIRTY != 0))
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Intel VMX support.
+
+pub mod bitmap;
+pub mod caps;
+pub mod ept;
+pub mod vmcs;
+mod vmxon;
+
+pub use self::vmxon::{enable, disable, enabled, VmxEnableError};
+
+/// How a VMX instruction failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmxError {
+    /// VMfailInvalid, there is no current VMCS.
+    VMX_ERROR_FAIL_INVALID,
+    /// VMfailValid, with the VM-instruction error number from the VMCS.
+    VMX_ERROR_FAIL_VALID(u32),
+}
+
+impl VmxError {
+    /* From CF and ZF after the instruction */
+    pub(crate) fn check(invalid: u8, failed: u8) -> Result<(), VmxError> {
+        if invalid != 0 {
+            return Err(VmxError::VMX_ERROR_FAIL_INVALID);
+        }
+
+        if failed != 0 {
+            let number = unsafe { vmcs::VMCS::instruction_error() };
+            return Err(VmxError::VMX_ERROR_FAIL_VALID(number));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,527 @@
+//! VMCS field encodings, VMREAD/VMWRITE and a software copy of VMCS contents.
+//!
+//! An encoding holds the access type in bit 0 (full, or the high half of a
+//! 64-bit field), the index in bits 9:1, the type in bits 11:10 and the
+//! width in bits 14:13. Only the full encodings are listed, see
+//! `VmcsField::encoding_high()` for the others. The list covers appendix B
+//! of the SDM up to the HLAT, IPI virtualization and notify window fields.
+
+use super::VmxError;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmcsFieldWidth {
+    VMCS_WIDTH_16,
+    VMCS_WIDTH_64,
+    VMCS_WIDTH_32,
+    VMCS_WIDTH_NATURAL,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmcsFieldType {
+    VMCS_TYPE_CONTROL,
+    /* VM-exit information */
+    VMCS_TYPE_READ_ONLY,
+    VMCS_TYPE_GUEST,
+    VMCS_TYPE_HOST,
+}
+
+macro_rules! vmcs_fields {
+    ($($name:ident = $encoding:expr,)*) => {
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        #[repr(u32)]
+        pub enum VmcsField {
+            $($name = $encoding,)*
+        }
+
+        impl VmcsField {
+            /// Every field, in encoding order.
+            pub const ALL: &'static [VmcsField] = &[$(VmcsField::$name,)*];
+
+            /* Position in `ALL` */
+            fn position(self) -> usize {
+                #[allow(non_camel_case_types)]
+                enum Position {
+                    $($name,)*
+                }
+
+                match self {
+                    $(VmcsField::$name => Position::$name as usize,)*
+                }
+            }
+        }
+    };
+}
+
+vmcs_fields! {
+    /* 16-bit control */
+    VIRTUAL_PROCESSOR_ID = 0x0000,
+    POSTED_INTR_NV = 0x0002,
+    EPTP_INDEX = 0x0004,
+    HLAT_PREFIX_SIZE = 0x0006,
+    LAST_PID_POINTER_INDEX = 0x0008,
+
+    /* 16-bit guest state */
+    GUEST_ES_SELECTOR = 0x0800,
+    GUEST_CS_SELECTOR = 0x0802,
+    GUEST_SS_SELECTOR = 0x0804,
+    GUEST_DS_SELECTOR = 0x0806,
+    GUEST_FS_SELECTOR = 0x0808,
+    GUEST_GS_SELECTOR = 0x080a,
+    GUEST_LDTR_SELECTOR = 0x080c,
+    GUEST_TR_SELECTOR = 0x080e,
+    GUEST_INTR_STATUS = 0x0810,
+    GUEST_PML_INDEX = 0x0812,
+    GUEST_UINV = 0x0814,
+
+    /* 16-bit host state */
+    HOST_ES_SELECTOR = 0x0c00,
+    HOST_CS_SELECTOR = 0x0c02,
+    HOST_SS_SELECTOR = 0x0c04,
+    HOST_DS_SELECTOR = 0x0c06,
+    HOST_FS_SELECTOR = 0x0c08,
+    HOST_GS_SELECTOR = 0x0c0a,
+    HOST_TR_SELECTOR = 0x0c0c,
+
+    /* 64-bit control */
+    IO_BITMAP_A = 0x2000,
+    IO_BITMAP_B = 0x2002,
+    MSR_BITMAP = 0x2004,
+    VM_EXIT_MSR_STORE_ADDR = 0x2006,
+    VM_EXIT_MSR_LOAD_ADDR = 0x2008,
+    VM_ENTRY_MSR_LOAD_ADDR = 0x200a,
+    EXECUTIVE_VMCS_POINTER = 0x200c,
+    PML_ADDRESS = 0x200e,
+    TSC_OFFSET = 0x2010,
+    VIRTUAL_APIC_PAGE_ADDR = 0x2012,
+    APIC_ACCESS_ADDR = 0x2014,
+    POSTED_INTR_DESC_ADDR = 0x2016,
+    VM_FUNCTION_CONTROL = 0x2018,
+    EPT_POINTER = 0x201a,
+    EOI_EXIT_BITMAP0 = 0x201c,
+    EOI_EXIT_BITMAP1 = 0x201e,
+    EOI_EXIT_BITMAP2 = 0x2020,
+    EOI_EXIT_BITMAP3 = 0x2022,
+    EPTP_LIST_ADDRESS = 0x2024,
+    VMREAD_BITMAP = 0x2026,
+    VMWRITE_BITMAP = 0x2028,
+    VE_INFORMATION_ADDRESS = 0x202a,
+    XSS_EXIT_BITMAP = 0x202c,
+    ENCLS_EXITING_BITMAP = 0x202e,
+    SPP_TABLE_POINTER = 0x2030,
+    TSC_MULTIPLIER = 0x2032,
+    TERTIARY_VM_EXEC_CONTROL = 0x2034,
+    ENCLV_EXITING_BITMAP = 0x2036,
+    PCONFIG_EXITING_BITMAP = 0x203e,
+    HLATP = 0x2040,
+    PID_POINTER_TABLE = 0x2042,
+    SECONDARY_VM_EXIT_CONTROLS = 0x2044,
+    SPEC_CTRL_MASK = 0x204a,
+    SPEC_CTRL_SHADOW = 0x204c,
+
+    /* 64-bit read-only */
+    GUEST_PHYSICAL_ADDRESS = 0x2400,
+
+    /* 64-bit guest state */
+    VMCS_LINK_POINTER = 0x2800,
+    GUEST_IA32_DEBUGCTL = 0x2802,
+    GUEST_IA32_PAT = 0x2804,
+    GUEST_IA32_EFER = 0x2806,
+    GUEST_IA32_PERF_GLOBAL_CTRL = 0x2808,
+    GUEST_PDPTR0 = 0x280a,
+    GUEST_PDPTR1 = 0x280c,
+    GUEST_PDPTR2 = 0x280e,
+    GUEST_PDPTR3 = 0x2810,
+    GUEST_BNDCFGS = 0x2812,
+    GUEST_IA32_RTIT_CTL = 0x2814,
+    GUEST_IA32_LBR_CTL = 0x2816,
+    GUEST_IA32_PKRS = 0x2818,
+
+    /* 64-bit host state */
+    HOST_IA32_PAT = 0x2c00,
+    HOST_IA32_EFER = 0x2c02,
+    HOST_IA32_PERF_GLOBAL_CTRL = 0x2c04,
+    HOST_IA32_PKRS = 0x2c06,
+
+    /* 32-bit control */
+    PIN_BASED_VM_EXEC_CONTROL = 0x4000,
+    CPU_BASED_VM_EXEC_CONTROL = 0x4002,
+    EXCEPTION_BITMAP = 0x4004,
+    PAGE_FAULT_ERROR_CODE_MASK = 0x4006,
+    PAGE_FAULT_ERROR_CODE_MATCH = 0x4008,
+    CR3_TARGET_COUNT = 0x400a,
+    VM_EXIT_CONTROLS = 0x400c,
+    VM_EXIT_MSR_STORE_COUNT = 0x400e,
+    VM_EXIT_MSR_LOAD_COUNT = 0x4010,
+    VM_ENTRY_CONTROLS = 0x4012,
+    VM_ENTRY_MSR_LOAD_COUNT = 0x4014,
+    VM_ENTRY_INTR_INFO_FIELD = 0x4016,
+    VM_ENTRY_EXCEPTION_ERROR_CODE = 0x4018,
+    VM_ENTRY_INSTRUCTION_LEN = 0x401a,
+    TPR_THRESHOLD = 0x401c,
+    SECONDARY_VM_EXEC_CONTROL = 0x401e,
+    PLE_GAP = 0x4020,
+    PLE_WINDOW = 0x4022,
+    NOTIFY_WINDOW = 0x4024,
+
+    /* 32-bit read-only */
+    VM_INSTRUCTION_ERROR = 0x4400,
+    VM_EXIT_REASON = 0x4402,
+    VM_EXIT_INTR_INFO = 0x4404,
+    VM_EXIT_INTR_ERROR_CODE = 0x4406,
+    IDT_VECTORING_INFO_FIELD = 0x4408,
+    IDT_VECTORING_ERROR_CODE = 0x440a,
+    VM_EXIT_INSTRUCTION_LEN = 0x440c,
+    VMX_INSTRUCTION_INFO = 0x440e,
+
+    /* 32-bit guest state */
+    GUEST_ES_LIMIT = 0x4800,
+    GUEST_CS_LIMIT = 0x4802,
+    GUEST_SS_LIMIT = 0x4804,
+    GUEST_DS_LIMIT = 0x4806,
+    GUEST_FS_LIMIT = 0x4808,
+    GUEST_GS_LIMIT = 0x480a,
+    GUEST_LDTR_LIMIT = 0x480c,
+    GUEST_TR_LIMIT = 0x480e,
+    GUEST_GDTR_LIMIT = 0x4810,
+    GUEST_IDTR_LIMIT = 0x4812,
+    GUEST_ES_AR_BYTES = 0x4814,
+    GUEST_CS_AR_BYTES = 0x4816,
+    GUEST_SS_AR_BYTES = 0x4818,
+    GUEST_DS_AR_BYTES = 0x481a,
+    GUEST_FS_AR_BYTES = 0x481c,
+    GUEST_GS_AR_BYTES = 0x481e,
+    GUEST_LDTR_AR_BYTES = 0x4820,
+    GUEST_TR_AR_BYTES = 0x4822,
+    GUEST_INTERRUPTIBILITY_INFO = 0x4824,
+    GUEST_ACTIVITY_STATE = 0x4826,
+    GUEST_SMBASE = 0x4828,
+    GUEST_SYSENTER_CS = 0x482a,
+    VMX_PREEMPTION_TIMER_VALUE = 0x482e,
+
+    /* 32-bit host state */
+    HOST_IA32_SYSENTER_CS = 0x4c00,
+
+    /* Natural-width control */
+    CR0_GUEST_HOST_MASK = 0x6000,
+    CR4_GUEST_HOST_MASK = 0x6002,
+    CR0_READ_SHADOW = 0x6004,
+    CR4_READ_SHADOW = 0x6006,
+    CR3_TARGET_VALUE0 = 0x6008,
+    CR3_TARGET_VALUE1 = 0x600a,
+    CR3_TARGET_VALUE2 = 0x600c,
+    CR3_TARGET_VALUE3 = 0x600e,
+
+    /* Natural-width read-only */
+    EXIT_QUALIFICATION = 0x6400,
+    EXIT_IO_RCX = 0x6402,
+    EXIT_IO_RSI = 0x6404,
+    EXIT_IO_RDI = 0x6406,
+    EXIT_IO_RIP = 0x6408,
+    GUEST_LINEAR_ADDRESS = 0x640a,
+
+    /* Natural-width guest state */
+    GUEST_CR0 = 0x6800,
+    GUEST_CR3 = 0x6802,
+    GUEST_CR4 = 0x6804,
+    GUEST_ES_BASE = 0x6806,
+    GUEST_CS_BASE = 0x6808,
+    GUEST_SS_BASE = 0x680a,
+    GUEST_DS_BASE = 0x680c,
+    GUEST_FS_BASE = 0x680e,
+    GUEST_GS_BASE = 0x6810,
+    GUEST_LDTR_BASE = 0x6812,
+    GUEST_TR_BASE = 0x6814,
+    GUEST_GDTR_BASE = 0x6816,
+    GUEST_IDTR_BASE = 0x6818,
+    GUEST_DR7 = 0x681a,
+    GUEST_RSP = 0x681c,
+    GUEST_RIP = 0x681e,
+    GUEST_RFLAGS = 0x6820,
+    GUEST_PENDING_DBG_EXCEPTIONS = 0x6822,
+    GUEST_SYSENTER_ESP = 0x6824,
+    GUEST_SYSENTER_EIP = 0x6826,
+    GUEST_S_CET = 0x6828,
+    GUEST_SSP = 0x682a,
+    GUEST_INTR_SSP_TABLE = 0x682c,
+
+    /* Natural-width host state */
+    HOST_CR0 = 0x6c00,
+    HOST_CR3 = 0x6c02,
+    HOST_CR4 = 0x6c04,
+    HOST_FS_BASE = 0x6c06,
+    HOST_GS_BASE = 0x6c08,
+    HOST_TR_BASE = 0x6c0a,
+    HOST_GDTR_BASE = 0x6c0c,
+    HOST_IDTR_BASE = 0x6c0e,
+    HOST_IA32_SYSENTER_ESP = 0x6c10,
+    HOST_IA32_SYSENTER_EIP = 0x6c12,
+    HOST_RSP = 0x6c14,
+    HOST_RIP = 0x6c16,
+    HOST_S_CET = 0x6c18,
+    HOST_SSP = 0x6c1a,
+    HOST_INTR_SSP_TABLE = 0x6c1c,
+}
+
+/// Number of fields in `VmcsField::ALL`.
+pub const VMCS_FIELD_COUNT: usize = VmcsField::ALL.len();
+
+impl VmcsField {
+    pub fn encoding(self) -> u32 {
+        self as u32
+    }
+
+    /// Encoding of the upper 32 bits, for 64-bit fields only.
+    pub fn encoding_high(self) -> Option<u32> {
+        match self.width() {
+            VmcsFieldWidth::VMCS_WIDTH_64 => Some(self.encoding() | 1),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u32 {
+        (self.encoding() >> 1) & 0x1ff
+    }
+
+    pub fn field_type(self) -> VmcsFieldType {
+        match (self.encoding() >> 10) & 0x3 {
+            0 => VmcsFieldType::VMCS_TYPE_CONTROL,
+            1 => VmcsFieldType::VMCS_TYPE_READ_ONLY,
+            2 => VmcsFieldType::VMCS_TYPE_GUEST,
+            _ => VmcsFieldType::VMCS_TYPE_HOST,
+        }
+    }
+
+    pub fn width(self) -> VmcsFieldWidth {
+        match (self.encoding() >> 13) & 0x3 {
+            0 => VmcsFieldWidth::VMCS_WIDTH_16,
+            1 => VmcsFieldWidth::VMCS_WIDTH_64,
+            2 => VmcsFieldWidth::VMCS_WIDTH_32,
+            _ => VmcsFieldWidth::VMCS_WIDTH_NATURAL,
+        }
+    }
+
+    /// Bits a value of this field can have.
+    pub fn mask(self) -> u64 {
+        match self.width() {
+            VmcsFieldWidth::VMCS_WIDTH_16 => 0xffff,
+            VmcsFieldWidth::VMCS_WIDTH_32 => 0xffffffff,
+            _ => u64::MAX,
+        }
+    }
+
+    /// The field with `encoding`, not for high halves.
+    pub fn from_encoding(encoding: u32) -> Option<VmcsField> {
+        VmcsField::ALL.iter().copied().find(|field| field.encoding() == encoding)
+    }
+}
+
+/// VMREAD/VMWRITE and the VMCS pointer instructions. All of them need VMX
+/// operation, see `vmx::enable()`.
+pub struct VMCS;
+
+impl VMCS {
+    /// Reads `field` of the current VMCS.
+    pub unsafe fn read(field: VmcsField) -> Result<u64, VmxError> {
+        let value: u64;
+        let invalid: u8;
+        let failed: u8;
+
+        llvm_asm!("vmread $3, $0; setc $1; setz $2"
+                  : "=r" (value), "=r" (invalid), "=r" (failed)
+                  : "r" (field.encoding() as u64)
+                  : "cc", "memory" : "volatile");
+
+        VmxError::check(invalid, failed).map(|_| value)
+    }
+
+    /* VM_INSTRUCTION_ERROR without checking how VMREAD went, which is what
+     * `VmxError::check` needs */
+    pub(crate) unsafe fn instruction_error() -> u32 {
+        let mut value: u64 = 0;
+
+        llvm_asm!("vmread $1, $0"
+                  : "+r" (value)
+                  : "r" (VmcsField::VM_INSTRUCTION_ERROR.encoding() as u64)
+                  : "cc", "memory" : "volatile");
+
+        value as u32
+    }
+
+    /// Writes `field` of the current VMCS. Bits above the field width are
+    /// ignored.
+    pub unsafe fn write(field: VmcsField, value: u64) -> Result<(), VmxError> {
+        let invalid: u8;
+        let failed: u8;
+
+        llvm_asm!("vmwrite $3, $2; setc $0; setz $1"
+                  : "=r" (invalid), "=r" (failed)
+                  : "r" (field.encoding() as u64), "r" (value & field.mask())
+                  : "cc", "memory" : "volatile");
+
+        VmxError::check(invalid, failed)
+    }
+
+    pub unsafe fn read16(field: VmcsField) -> Result<u16, VmxError> {
+        debug_assert_eq!(field.width(), VmcsFieldWidth::VMCS_WIDTH_16);
+        VMCS::read(field).map(|value| value as u16)
+    }
+
+    pub unsafe fn read32(field: VmcsField) -> Result<u32, VmxError> {
+        debug_assert_eq!(field.width(), VmcsFieldWidth::VMCS_WIDTH_32);
+        VMCS::read(field).map(|value| value as u32)
+    }
+
+    pub unsafe fn read64(field: VmcsField) -> Result<u64, VmxError> {
+        debug_assert_ne!(field.width(), VmcsFieldWidth::VMCS_WIDTH_16);
+        debug_assert_ne!(field.width(), VmcsFieldWidth::VMCS_WIDTH_32);
+        VMCS::read(field)
+    }
+
+    pub unsafe fn write16(field: VmcsField, value: u16) -> Result<(), VmxError> {
+        debug_assert_eq!(field.width(), VmcsFieldWidth::VMCS_WIDTH_16);
+        VMCS::write(field, value as u64)
+    }
+
+    pub unsafe fn write32(field: VmcsField, value: u32) -> Result<(), VmxError> {
+        debug_assert_eq!(field.width(), VmcsFieldWidth::VMCS_WIDTH_32);
+        VMCS::write(field, value as u64)
+    }
+
+    pub unsafe fn write64(field: VmcsField, value: u64) -> Result<(), VmxError> {
+        debug_assert_ne!(field.width(), VmcsFieldWidth::VMCS_WIDTH_16);
+        debug_assert_ne!(field.width(), VmcsFieldWidth::VMCS_WIDTH_32);
+        VMCS::write(field, value)
+    }
+
+    /// Makes the VMCS at physical address `vmcs` current and active.
+    pub unsafe fn load(vmcs: u64) -> Result<(), VmxError> {
+        let invalid: u8;
+        let failed: u8;
+
+        llvm_asm!("vmptrld ($2); setc $0; setz $1"
+                  : "=r" (invalid), "=r" (failed)
+                  : "r" (&vmcs)
+                  : "cc", "memory" : "volatile");
+
+        VmxError::check(invalid, failed)
+    }
+
+    /// Flushes the VMCS at physical address `vmcs` to memory and makes it
+    /// inactive, it has to be launched again afterwards.
+    pub unsafe fn clear(vmcs: u64) -> Result<(), VmxError> {
+        let invalid: u8;
+        let failed: u8;
+
+        llvm_asm!("vmclear ($2); setc $0; setz $1"
+                  : "=r" (invalid), "=r" (failed)
+                  : "r" (&vmcs)
+                  : "cc", "memory" : "volatile");
+
+        VmxError::check(invalid, failed)
+    }
+
+    /// Physical address of the current VMCS, `u64::MAX` if there is none.
+    pub unsafe fn current() -> u64 {
+        let mut vmcs: u64 = 0;
+
+        llvm_asm!("vmptrst ($0)" :: "r" (&mut vmcs) : "memory" : "volatile");
+        vmcs
+    }
+}
+
+/// A change between two `VmcsShadow`s, `None` for fields not set.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VmcsChange {
+    pub field: VmcsField,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+}
+
+/// VMCS contents in memory, any subset of the fields. Used to build a VMCS
+/// before writing it and to see what changed between two snapshots.
+#[derive(Clone)]
+pub struct VmcsShadow {
+    values: [Option<u64>; VMCS_FIELD_COUNT],
+}
+
+impl VmcsShadow {
+    pub const fn new() -> Self {
+        VmcsShadow { values: [None; VMCS_FIELD_COUNT] }
+    }
+
+    pub fn get(&self, field: VmcsField) -> Option<u64> {
+        self.values[field.position()]
+    }
+
+    /// Stores `value` cut to the field width.
+    pub fn set(&mut self, field: VmcsField, value: u64) {
+        self.values[field.position()] = Some(value & field.mask());
+    }
+
+    pub fn remove(&mut self, field: VmcsField) {
+        self.values[field.position()] = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.values = [None; VMCS_FIELD_COUNT];
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The fields that are set, in encoding order.
+    pub fn iter(&self) -> impl Iterator<Item = (VmcsField, u64)> + '_ {
+        VmcsField::ALL.iter()
+            .zip(self.values.iter())
+            .filter_map(|(&field, value)| value.map(|value| (field, value)))
+    }
+
+    /// Fields set differently in `self` and `other`, going from `self` to
+    /// `other`.
+    pub fn diff<'a>(&'a self, other: &'a VmcsShadow) -> impl Iterator<Item = VmcsChange> + 'a {
+        VmcsField::ALL.iter()
+            .zip(self.values.iter().zip(other.values.iter()))
+            .filter(|(_, (old, new))| old != new)
+            .map(|(&field, (&old, &new))| VmcsChange { field, old, new })
+    }
+
+    /// Reads `fields` from the current VMCS.
+    pub unsafe fn capture(&mut self, fields: &[VmcsField]) -> Result<(), VmxError> {
+        for &field in fields {
+            self.set(field, VMCS::read(field)?);
+        }
+
+        Ok(())
+    }
+
+    /// Writes every set field, except read-only ones, to the current VMCS.
+    pub unsafe fn store(&self) -> Result<(), VmxError> {
+        for (field, value) in self.iter() {
+            if field.field_type() != VmcsFieldType::VMCS_TYPE_READ_ONLY {
+                VMCS::write(field, value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes only the fields that differ from `previous`, which is what the
+    /// current VMCS is assumed to hold.
+    pub unsafe fn store_changes(&self, previous: &VmcsShadow) -> Result<(), VmxError> {
+        for change in previous.diff(self) {
+            let read_only = change.field.field_type() == VmcsFieldType::VMCS_TYPE_READ_ONLY;
+
+            match change.new {
+                Some(value) if !read_only => VMCS::write(change.field, value)?,
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+}
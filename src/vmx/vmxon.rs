@@ -0,0 +1,174 @@
+//! Entering and leaving VMX operation.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr0, Cr4, Cr4Flags};
+
+use super::caps::{apply_fixed, VmxBasic};
+use super::VmxError;
+use crate::cpu::{CPU, MAX_CPUS};
+use crate::cpuid::{self, Feature};
+use crate::msr::{FeatureControl, MSR};
+use crate::vm::VM;
+
+/// Why `vmx::enable()` failed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmxEnableError {
+    /// CPUID reports no VMX.
+    VMX_ENABLE_ERROR_UNSUPPORTED,
+    /// IA32_FEATURE_CONTROL is locked with VMX outside SMX disabled, only
+    /// the firmware setup can change that.
+    VMX_ENABLE_ERROR_DISABLED_BY_FIRMWARE,
+    /// CR4.VMXE is already set, by this CPU or someone else.
+    VMX_ENABLE_ERROR_ALREADY_ENABLED,
+    /// IA32_VMX_CR0_FIXED0 requires bits IA32_VMX_CR0_FIXED1 forbids, the
+    /// value has the conflicting bits.
+    VMX_ENABLE_ERROR_CR0_FIXED(u64),
+    /// Same for CR4.
+    VMX_ENABLE_ERROR_CR4_FIXED(u64),
+    /// IA32_VMX_BASIC reports a VMXON region larger than a page.
+    VMX_ENABLE_ERROR_REGION_SIZE(usize),
+    /// No frame left for the VMXON region, or none below 4 GiB when
+    /// IA32_VMX_BASIC asks for 32-bit addresses.
+    VMX_ENABLE_ERROR_NO_MEMORY,
+    /// VMXON itself failed.
+    VMX_ENABLE_ERROR_VMXON(VmxError),
+}
+
+/* Physical address of each CPU's VMXON region, 0 if none yet */
+const NO_REGION: AtomicU64 = AtomicU64::new(0);
+static VMXON_REGIONS: [AtomicU64; MAX_CPUS] = [NO_REGION; MAX_CPUS];
+
+/* Lets VMXON work outside SMX, locking the MSR if the firmware did not */
+fn enable_feature_control() -> Result<(), VmxEnableError> {
+    let control = unsafe { FeatureControl::read() };
+
+    if control.locked() {
+        return match control.vmx_outside_smx() {
+            true => Ok(()),
+            false => Err(VmxEnableError::VMX_ENABLE_ERROR_DISABLED_BY_FIRMWARE),
+        };
+    }
+
+    unsafe {
+        control.set_vmx_outside_smx(true)
+            .set_locked(true)
+            .write();
+    }
+
+    Ok(())
+}
+
+fn vmxon_region(basic: VmxBasic) -> Result<u64, VmxEnableError> {
+    let cpu = CPU::id();
+    let region = VMXON_REGIONS[cpu].load(Ordering::Relaxed);
+
+    if region != 0 {
+        return match basic.physical_address_32bit() && region >= 1 << 32 {
+            true => Err(VmxEnableError::VMX_ENABLE_ERROR_NO_MEMORY),
+            false => Ok(region),
+        };
+    }
+
+    if basic.region_size() > 4096 {
+        return Err(VmxEnableError::VMX_ENABLE_ERROR_REGION_SIZE(basic.region_size()));
+    }
+
+    let region = VM::alloc_pages(1).ok_or(VmxEnableError::VMX_ENABLE_ERROR_NO_MEMORY)?;
+
+    unsafe { (VM::phys_to_virt(region) as *mut u32).write_volatile(basic.revision_id()) };
+
+    /* Kept even if it is too high, page_alloc can not take it back and
+     * another try would only get a higher one */
+    VMXON_REGIONS[cpu].store(region, Ordering::Relaxed);
+    Ok(region)
+}
+
+/// Puts the running CPU in VMX root operation: checks CPUID, enables VMX in
+/// IA32_FEATURE_CONTROL, adjusts CR0/CR4 to the VMX fixed bits, sets
+/// CR4.VMXE and executes VMXON with a region from `page_alloc`. Every CPU
+/// that wants to use VMX has to call it. CR0 and CR4 are left alone if it
+/// fails.
+pub fn enable() -> Result<(), VmxEnableError> {
+    if !cpuid::has(Feature::FEATURE_VMX) {
+        return Err(VmxEnableError::VMX_ENABLE_ERROR_UNSUPPORTED);
+    }
+
+    /* Nothing else may run on this CPU between the CR4.VMXE check, picking
+     * its region and VMXON */
+    let flags = CPU::irq_save();
+    let result = enable_irqs_off();
+    CPU::irq_restore(flags);
+
+    result
+}
+
+fn enable_irqs_off() -> Result<(), VmxEnableError> {
+    if enabled() {
+        return Err(VmxEnableError::VMX_ENABLE_ERROR_ALREADY_ENABLED);
+    }
+
+    enable_feature_control()?;
+
+    let (old_cr0, old_cr4) = (Cr0::read_raw(), Cr4::read_raw());
+
+    let (basic, cr0, cr4) = unsafe {
+        let cr0 = apply_fixed(old_cr0,
+                              MSR::IA32_VMX_CR0_FIXED0.read(),
+                              MSR::IA32_VMX_CR0_FIXED1.read())
+            .map_err(VmxEnableError::VMX_ENABLE_ERROR_CR0_FIXED)?;
+        let cr4 = apply_fixed(old_cr4 | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits(),
+                              MSR::IA32_VMX_CR4_FIXED0.read(),
+                              MSR::IA32_VMX_CR4_FIXED1.read())
+            .map_err(VmxEnableError::VMX_ENABLE_ERROR_CR4_FIXED)?;
+
+        (VmxBasic(MSR::IA32_VMX_BASIC.read()), cr0, cr4)
+    };
+
+    let region = vmxon_region(basic)?;
+
+    unsafe {
+        Cr0::write_raw(cr0);
+        Cr4::write_raw(cr4);
+    }
+
+    let invalid: u8;
+    let failed: u8;
+
+    unsafe {
+        llvm_asm!("vmxon ($2); setc $0; setz $1"
+                  : "=r" (invalid), "=r" (failed)
+                  : "r" (&region)
+                  : "cc", "memory" : "volatile");
+    }
+
+    VmxError::check(invalid, failed).map_err(|error| {
+        unsafe {
+            Cr4::write_raw(old_cr4);
+            Cr0::write_raw(old_cr0);
+        }
+        VmxEnableError::VMX_ENABLE_ERROR_VMXON(error)
+    })
+}
+
+/// Leaves VMX operation on the running CPU. The VMXON region is kept for the
+/// next `enable()`.
+pub fn disable() -> Result<(), VmxError> {
+    let invalid: u8;
+    let failed: u8;
+
+    unsafe {
+        llvm_asm!("vmxoff; setc $0; setz $1"
+                  : "=r" (invalid), "=r" (failed)
+                  :: "cc", "memory" : "volatile");
+    }
+
+    VmxError::check(invalid, failed)?;
+
+    unsafe { Cr4::write_raw(Cr4::read_raw() & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits()) };
+    Ok(())
+}
+
+/// Whether the running CPU has CR4.VMXE set.
+pub fn enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS)
+}
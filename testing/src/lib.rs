@@ -12,6 +12,12 @@ extern crate libos;
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
@@ -33,6 +39,581 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 fn simple_register_access() {
 }
 
+/* Capability MSRs recorded on a Skylake host */
+const VMX_MSRS: libos::vmx::caps::VmxMsrs = libos::vmx::caps::VmxMsrs {
+    basic: 0x00da040000000004,
+    pinbased: 0x0000007f00000016,
+    procbased: 0xfff9fffe0401e172,
+    exit: 0x01ffffff00036dff,
+    entry: 0x0003ffff000011ff,
+    true_pinbased: 0x0000007f00000016,
+    true_procbased: 0xfff9fffe04006172,
+    true_exit: 0x01ffffff00036dfb,
+    true_entry: 0x0003ffff000011fb,
+    procbased2: 0x00551cfe00000000,
+};
+
+#[test_case]
+fn vmx_basic_decoding() {
+    use libos::msr::MemoryType;
+    use libos::vmx::caps::VmxBasic;
+
+    let basic = VmxBasic(VMX_MSRS.basic);
+
+    assert_eq!(basic.revision_id(), 4);
+    assert_eq!(basic.region_size(), 0x400);
+    assert_eq!(basic.memory_type(), Some(MemoryType::MEMORY_TYPE_WB));
+    assert!(basic.ins_outs_info());
+    assert!(basic.true_controls());
+    assert!(!basic.physical_address_32bit());
+}
+
+#[test_case]
+fn cpu_logical_index() {
+    use libos::cpu::{CPU, MAX_CPUS};
+
+    /* The boot CPU registered first, registering again keeps its index */
+    assert_eq!(CPU::id(), 0);
+    assert_eq!(CPU::register(), Some(0));
+    assert_eq!(CPU::count(), 1);
+    assert_eq!(CPU::apic_id_of(0), Some(CPU::apic_id()));
+    assert_eq!(CPU::apic_id_of(1), None);
+    assert_eq!(CPU::apic_id_of(MAX_CPUS), None);
+}
+
+#[test_case]
+fn msr_pat_mtrr_bounds() {
+    use libos::msr::{MemoryType, MtrrRange, Pat};
+
+    let pat = Pat(0x0007040600070406).set_entry(7, MemoryType::MEMORY_TYPE_WC).unwrap();
+    assert_eq!(pat.0, 0x0107040600070406);
+    assert_eq!(pat.entry(7), Some(MemoryType::MEMORY_TYPE_WC));
+    assert_eq!(pat.entry(Pat::ENTRIES), None);
+    assert_eq!(pat.set_entry(8, MemoryType::MEMORY_TYPE_UC), None);
+
+    let range = MtrrRange::new(0x8000_0000, 0x4000_0000, MemoryType::MEMORY_TYPE_UC).unwrap();
+    assert_eq!((range.address(), range.size()), (0x8000_0000, 0x4000_0000));
+    assert!(range.valid());
+    assert_eq!(MtrrRange::new(0, 0, MemoryType::MEMORY_TYPE_WB), None);
+    assert_eq!(MtrrRange::new(0, 0x800, MemoryType::MEMORY_TYPE_WB), None);
+    assert_eq!(MtrrRange::new(0, 0x3000, MemoryType::MEMORY_TYPE_WB), None);
+    assert_eq!(MtrrRange::new(0x1000, 0x2000, MemoryType::MEMORY_TYPE_WB), None);
+}
+
+#[test_case]
+fn vmx_true_controls() {
+    use libos::vmx::caps::VmxCaps;
+
+    let caps = VmxCaps::new(&VMX_MSRS);
+    assert_eq!(caps.procbased.allowed0, 0x04006172);
+    assert_eq!(caps.exit.allowed0, 0x00036dfb);
+    assert_eq!(caps.entry.allowed0, 0x000011fb);
+
+    /* Without bit 55 the plain MSRs apply */
+    let msrs = libos::vmx::caps::VmxMsrs { basic: VMX_MSRS.basic & !(1 << 55), ..VMX_MSRS };
+    let caps = VmxCaps::new(&msrs);
+    assert_eq!(caps.procbased.allowed0, 0x0401e172);
+    assert_eq!(caps.exit.allowed0, 0x00036dff);
+}
+
+#[test_case]
+fn vmx_control_adjust() {
+    use libos::vmx::caps::{VmxCaps, VmxControl};
+
+    let caps = VmxCaps::new(&VMX_MSRS);
+
+    assert_eq!(caps.adjust(VmxControl::VMX_CONTROL_PINBASED, 0), Ok(0x16));
+    assert_eq!(caps.adjust(VmxControl::VMX_CONTROL_PINBASED, 1 << 0), Ok(0x17));
+    assert_eq!(caps.adjust(VmxControl::VMX_CONTROL_PINBASED, 1 << 7), Err(1 << 7));
+    /* Unrestricted guest and EPT */
+    assert_eq!(caps.adjust(VmxControl::VMX_CONTROL_PROCBASED2, (1 << 7) | (1 << 1)),
+               Ok((1 << 7) | (1 << 1)));
+
+    /* No secondary controls without the activate bit */
+    let msrs = libos::vmx::caps::VmxMsrs { true_procbased: 0x7ff9fffe04006172, ..VMX_MSRS };
+    let caps = VmxCaps::new(&msrs);
+    assert_eq!(caps.adjust(VmxControl::VMX_CONTROL_PROCBASED2, 1 << 1), Err(1 << 1));
+}
+
+#[test_case]
+fn vmx_apply_fixed() {
+    use libos::vmx::caps::apply_fixed;
+
+    /* CR0: PE, NE and PG required, nothing forbidden */
+    assert_eq!(apply_fixed(0x11, 0x80000021, 0xffffffff), Ok(0x80000031));
+    /* CR4: VMXE required, bit 22 forbidden */
+    assert_eq!(apply_fixed(0x400020, 0x2000, 0x3727ff), Ok(0x2020));
+    /* Bit 1 required and forbidden */
+    assert_eq!(apply_fixed(0, 0x3, 0x1), Err(0x2));
+}
+
+#[test_case]
+fn vmcs_field_encoding() {
+    use libos::vmx::vmcs::{VmcsField, VmcsFieldType, VmcsFieldWidth};
+
+    let rip = VmcsField::GUEST_RIP;
+    assert_eq!(rip.width(), VmcsFieldWidth::VMCS_WIDTH_NATURAL);
+    assert_eq!(rip.field_type(), VmcsFieldType::VMCS_TYPE_GUEST);
+    assert_eq!(rip.index(), 0xf);
+    assert_eq!(rip.encoding_high(), None);
+
+    assert_eq!(VmcsField::EPT_POINTER.width(), VmcsFieldWidth::VMCS_WIDTH_64);
+    assert_eq!(VmcsField::EPT_POINTER.encoding_high(), Some(0x201b));
+    assert_eq!(VmcsField::VM_EXIT_REASON.field_type(), VmcsFieldType::VMCS_TYPE_READ_ONLY);
+    assert_eq!(VmcsField::HOST_TR_SELECTOR.width(), VmcsFieldWidth::VMCS_WIDTH_16);
+    assert_eq!(VmcsField::from_encoding(0x681e), Some(VmcsField::GUEST_RIP));
+    assert_eq!(VmcsField::from_encoding(0x201b), None);
+    assert_eq!(VmcsField::NOTIFY_WINDOW.width(), VmcsFieldWidth::VMCS_WIDTH_32);
+    assert_eq!(VmcsField::SPEC_CTRL_SHADOW.encoding_high(), Some(0x204d));
+    assert_eq!(VmcsField::GUEST_UINV.field_type(), VmcsFieldType::VMCS_TYPE_GUEST);
+
+    /* Sorted and no encoding listed twice */
+    for pair in VmcsField::ALL.windows(2) {
+        assert!(pair[0].encoding() < pair[1].encoding());
+    }
+}
+
+#[test_case]
+fn vmcs_shadow_diff() {
+    use libos::vmx::vmcs::{VmcsChange, VmcsField, VmcsShadow};
+
+    let mut old = VmcsShadow::new();
+    old.set(VmcsField::GUEST_RIP, 0x1000);
+    old.set(VmcsField::GUEST_RSP, 0x8000);
+    old.set(VmcsField::EXCEPTION_BITMAP, 1 << 14);
+
+    let mut new = old.clone();
+    assert_eq!(old.diff(&new).count(), 0);
+
+    /* Cut to 16 bits */
+    new.set(VmcsField::GUEST_CS_SELECTOR, 0x10008);
+    assert_eq!(new.get(VmcsField::GUEST_CS_SELECTOR), Some(0x8));
+
+    new.set(VmcsField::GUEST_RIP, 0x1003);
+    new.remove(VmcsField::EXCEPTION_BITMAP);
+    assert_eq!(new.len(), 3);
+
+    let mut changes = old.diff(&new);
+    assert_eq!(changes.next(), Some(VmcsChange { field: VmcsField::GUEST_CS_SELECTOR, old: None, new: Some(0x8) }));
+    assert_eq!(changes.next(), Some(VmcsChange { field: VmcsField::EXCEPTION_BITMAP, old: Some(1 << 14), new: None }));
+    assert_eq!(changes.next(), Some(VmcsChange { field: VmcsField::GUEST_RIP, old: Some(0x1000), new: Some(0x1003) }));
+    assert_eq!(changes.next(), None);
+
+    /* Every field has a slot of its own */
+    let mut all = VmcsShadow::new();
+    for &field in VmcsField::ALL {
+        all.set(field, field.encoding() as u64);
+    }
+    assert_eq!(all.len(), VmcsField::ALL.len());
+    assert!(all.iter().all(|(field, value)| value == field.encoding() as u64));
+}
+
+/* Page table frames for the EPT tests, "physical" addresses are the virtual
+ * ones, with a zero offset */
+const EPT_POOL_PAGES: usize = 8;
+
+#[repr(C, align(4096))]
+struct EptPool([[u64; 512]; EPT_POOL_PAGES]);
+
+static mut EPT_POOL: EptPool = EptPool([[0; 512]; EPT_POOL_PAGES]);
+
+struct EptPoolFrames {
+    next: usize,
+    limit: usize,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for EptPoolFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.next == self.limit {
+            return None;
+        }
+
+        let address = unsafe { EPT_POOL.0[self.next].as_ptr() as u64 };
+        self.next += 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(address)))
+    }
+}
+
+#[test_case]
+fn ept_map_translate() {
+    use libos::msr::MemoryType;
+    use libos::vmx::ept::{Ept, EptPageSize, EPT_READ, EPT_RWX};
+
+    let mut ept = Ept::with_allocator(EptPoolFrames { next: 0, limit: EPT_POOL_PAGES }, 0).unwrap();
+
+    /* Two 2 MiB pages and two 4 KiB ones */
+    ept.map(0, 0x1_0000_0000, 0x40_2000, EPT_RWX, MemoryType::MEMORY_TYPE_WB).unwrap();
+    ept.map(0x8000_0000, 0xfee0_0000, 0x1000, EPT_READ, MemoryType::MEMORY_TYPE_UC).unwrap();
+
+    let large = ept.translate(0x20_1234).unwrap();
+    assert_eq!(large.hpa, 0x1_0020_1234);
+    assert_eq!(large.page_size, EptPageSize::EPT_PAGE_2M);
+    assert_eq!(large.access, EPT_RWX);
+    assert_eq!(large.memory_type, Some(MemoryType::MEMORY_TYPE_WB));
+
+    let small = ept.translate(0x40_1008).unwrap();
+    assert_eq!(small.hpa, 0x1_0040_1008);
+    assert_eq!(small.page_size, EptPageSize::EPT_PAGE_4K);
+
+    let mmio = ept.translate(0x8000_0030).unwrap();
+    assert_eq!(mmio.hpa, 0xfee0_0030);
+    assert_eq!(mmio.access, EPT_READ);
+    assert_eq!(mmio.memory_type, Some(MemoryType::MEMORY_TYPE_UC));
+
+    assert_eq!(ept.translate(0x40_2000), None);
+    assert_eq!(ept.clear_accessed_dirty(0x1000), Some((false, false)));
+
+    /* Write-back, 4 levels, then with accessed/dirty flags */
+    assert_eq!(ept.eptp(), ept.root() | 0x1e);
+    ept.set_accessed_dirty(true);
+    assert_eq!(ept.eptp(), ept.root() | 0x5e);
+}
+
+#[test_case]
+fn ept_huge_pages() {
+    use libos::msr::MemoryType;
+    use libos::vmx::ept::{Ept, EptPageSize, EPT_RWX};
+
+    let mut ept = Ept::with_allocator(EptPoolFrames { next: 0, limit: EPT_POOL_PAGES }, 0).unwrap();
+    ept.set_max_page_size(EptPageSize::EPT_PAGE_1G);
+    ept.map(0x4000_0000, 0x8000_0000, 0x4000_0000, EPT_RWX, MemoryType::MEMORY_TYPE_WB).unwrap();
+
+    let huge = ept.translate(0x7fff_fff8).unwrap();
+    assert_eq!(huge.hpa, 0xbfff_fff8);
+    assert_eq!(huge.page_size, EptPageSize::EPT_PAGE_1G);
+}
+
+#[test_case]
+fn ept_errors() {
+    use libos::msr::MemoryType;
+    use libos::vmx::ept::{Ept, EptError, EptPageSize, EPT_RWX, EPT_WRITE};
+
+    let wb = MemoryType::MEMORY_TYPE_WB;
+    let mut ept = Ept::with_allocator(EptPoolFrames { next: 0, limit: EPT_POOL_PAGES }, 0).unwrap();
+
+    assert_eq!(ept.map(0x800, 0, 0x1000, EPT_RWX, wb), Err(EptError::EPT_ERROR_UNALIGNED));
+    assert_eq!(ept.map_page(0x1000, 0, EptPageSize::EPT_PAGE_2M, EPT_RWX, wb),
+               Err(EptError::EPT_ERROR_UNALIGNED));
+    assert_eq!(ept.map(0, 0, 0x1000, EPT_WRITE, wb), Err(EptError::EPT_ERROR_INVALID_MAPPING));
+    assert_eq!(ept.map(0, 0, 0x1000, 0, wb), Err(EptError::EPT_ERROR_INVALID_MAPPING));
+
+    ept.map(0, 0, 0x20_0000, EPT_RWX, wb).unwrap();
+    assert_eq!(ept.map(0x1000, 0, 0x1000, EPT_RWX, wb), Err(EptError::EPT_ERROR_ALREADY_MAPPED));
+
+    /* Room for the PML4 and one more table only */
+    let mut ept = Ept::with_allocator(EptPoolFrames { next: 0, limit: 2 }, 0).unwrap();
+    assert_eq!(ept.map(0, 0, 0x1000, EPT_RWX, wb), Err(EptError::EPT_ERROR_NO_MEMORY));
+}
+
+#[test_case]
+fn vmx_msr_bitmap_ranges() {
+    use libos::vm::VM;
+    use libos::vmx::bitmap::MsrBitmap;
+
+    let mut bitmap = MsrBitmap::new().unwrap();
+    let page = VM::phys_to_virt(bitmap.address()) as *const u8;
+
+    /* Covered MSRs start out passed through, the others always exit */
+    assert!(!bitmap.intercepts_read(0x1fff));
+    assert!(bitmap.intercepts_read(0x2000));
+    assert!(bitmap.intercepts_write(0xbfffffff));
+    assert_eq!(bitmap.intercept_raw(0x2000, true, true), None);
+    assert_eq!(bitmap.intercept_raw(0xc0002000, true, true), None);
+
+    /* Last bit of the low read region, first of the high write region */
+    bitmap.intercept_raw(0x1fff, true, false).unwrap();
+    bitmap.intercept_raw(0xc0000000, false, true).unwrap();
+    assert!(bitmap.intercepts_read(0x1fff) && !bitmap.intercepts_write(0x1fff));
+    assert!(!bitmap.intercepts_read(0xc0000000) && bitmap.intercepts_write(0xc0000000));
+    assert!(!bitmap.intercepts_read(0x1ffe) && !bitmap.intercepts_read(0xc0001fff));
+    assert_eq!(unsafe { page.add(1023).read() }, 0x80);
+    assert_eq!(unsafe { page.add(1024).read() }, 0);
+    assert_eq!(unsafe { page.add(3072).read() }, 0x01);
+}
+
+#[test_case]
+fn vmx_io_bitmap_halves() {
+    use libos::vm::VM;
+    use libos::vmx::bitmap::IoBitmap;
+
+    let mut bitmap = IoBitmap::new().unwrap();
+    let a = VM::phys_to_virt(bitmap.address_a()) as *const u8;
+    let b = VM::phys_to_virt(bitmap.address_b()) as *const u8;
+
+    /* One access spanning both bitmaps */
+    bitmap.intercept(0x7fff..=0x8000, true);
+    assert!(bitmap.intercepts(0x7fff) && bitmap.intercepts(0x8000));
+    assert!(!bitmap.intercepts(0x7ffe) && !bitmap.intercepts(0x8001));
+    assert_eq!(unsafe { a.add(0xfff).read() }, 0x80);
+    assert_eq!(unsafe { b.read() }, 0x01);
+
+    bitmap.intercept_port(0xffff, true);
+    assert_eq!(unsafe { b.add(0xfff).read() }, 0x80);
+    bitmap.intercept(0x7fff..=0xffff, false);
+    assert!(!bitmap.intercepts(0x8000) && !bitmap.intercepts(0xffff));
+}
+
+#[test_case]
+fn npt_map_translate() {
+    use libos::msr::MemoryType;
+    use libos::svm::npt::{Npt, NptError, NptPageSize, NPT_READ, NPT_RWX, NPT_WRITE};
+
+    let wb = MemoryType::MEMORY_TYPE_WB;
+    let mut npt = Npt::with_allocator(EptPoolFrames { next: 0, limit: EPT_POOL_PAGES }, 0).unwrap();
+
+    npt.map(0, 0x1_0000_0000, 0x40_2000, NPT_RWX, wb).unwrap();
+    npt.map(0x8000_0000, 0xfee0_0000, 0x1000, NPT_READ, MemoryType::MEMORY_TYPE_UC).unwrap();
+
+    let large = npt.translate(0x20_1234).unwrap();
+    assert_eq!(large.hpa, 0x1_0020_1234);
+    assert_eq!(large.page_size, NptPageSize::NPT_PAGE_2M);
+    assert_eq!(large.access, NPT_RWX);
+    assert_eq!(large.memory_type, Some(wb));
+
+    let small = npt.translate(0x40_1008).unwrap();
+    assert_eq!(small.hpa, 0x1_0040_1008);
+    assert_eq!(small.page_size, NptPageSize::NPT_PAGE_4K);
+
+    /* Read only, no execute, uncached */
+    let mmio = npt.translate(0x8000_0030).unwrap();
+    assert_eq!(mmio.hpa, 0xfee0_0030);
+    assert_eq!(mmio.access, NPT_READ);
+    assert_eq!(mmio.memory_type, Some(MemoryType::MEMORY_TYPE_UC));
+
+    assert_eq!(npt.translate(0x40_2000), None);
+    assert_eq!(npt.ncr3(), npt.root());
+
+    assert_eq!(npt.map(0, 0, 0x1000, NPT_WRITE, wb), Err(NptError::NPT_ERROR_INVALID_MAPPING));
+    assert_eq!(npt.map(0x9000_0000, 0, 0x1000, NPT_RWX, MemoryType::MEMORY_TYPE_WC),
+               Err(NptError::NPT_ERROR_INVALID_MAPPING));
+    assert_eq!(npt.map(0x1000, 0, 0x1000, NPT_RWX, wb), Err(NptError::NPT_ERROR_ALREADY_MAPPED));
+}
+
+#[test_case]
+fn svm_intercepts() {
+    use libos::svm::vmcb::{SvmIntercept, Vmcb, SVM_EXIT_INTR};
+
+    let mut vmcb: Vmcb = unsafe { core::mem::zeroed() };
+    let control = &mut vmcb.control;
+
+    control.set_intercept(SvmIntercept::SVM_INTERCEPT_VMRUN, true);
+    control.set_intercept(SvmIntercept::SVM_INTERCEPT_CPUID, true);
+    control.set_intercept(SvmIntercept::SVM_INTERCEPT_INVPCID, true);
+    assert_eq!(control.intercepts, [1 << 18, 1 << 0, 1 << 2]);
+    assert!(control.intercepts(SvmIntercept::SVM_INTERCEPT_CPUID));
+
+    control.set_intercept(SvmIntercept::SVM_INTERCEPT_CPUID, false);
+    assert!(!control.intercepts(SvmIntercept::SVM_INTERCEPT_CPUID));
+
+    control.set_cr_intercept(3, false, true).unwrap();
+    control.set_exception_intercept(14, true).unwrap();
+    assert_eq!(control.intercept_cr, 1 << 19);
+    assert_eq!(control.intercept_exceptions, 1 << 14);
+
+    /* Out of range registers and vectors leave the masks alone */
+    assert_eq!(control.set_cr_intercept(16, true, true), None);
+    assert_eq!(control.set_dr_intercept(32, true, true), None);
+    assert_eq!(control.set_exception_intercept(32, true), None);
+    assert_eq!((control.intercept_cr, control.intercept_dr), (1 << 19, 0));
+    assert_eq!(control.intercept_exceptions, 1 << 14);
+
+    assert_eq!(SvmIntercept::SVM_INTERCEPT_INTR.exit_code(), Some(SVM_EXIT_INTR));
+    assert_eq!(SvmIntercept::SVM_INTERCEPT_CPUID.exit_code(), Some(0x72));
+    assert_eq!(SvmIntercept::SVM_INTERCEPT_VMMCALL.exit_code(), Some(0x81));
+    assert_eq!(SvmIntercept::SVM_INTERCEPT_TLBSYNC.exit_code(), None);
+}
+
+#[test_case]
+fn debug_control_encoding() {
+    use libos::debugreg::{BreakpointKind, BreakpointLength, DebugControl, DebugStatus};
+
+    let control = DebugControl::new()
+        .set(1, BreakpointKind::BREAKPOINT_WRITE, BreakpointLength::BREAKPOINT_LENGTH_4)
+        .set(3, BreakpointKind::BREAKPOINT_EXECUTE, BreakpointLength::BREAKPOINT_LENGTH_1);
+
+    /* L1, L3, LE, reserved bit 10, then R/W and LEN of 1 and 3 */
+    assert_eq!(control.0, 0x4 | 0x40 | 0x100 | 0x400 | 0xd << 20);
+    assert!(control.enabled(1) && control.enabled(3) && !control.enabled(0));
+    assert_eq!(control.kind(1), Some(BreakpointKind::BREAKPOINT_WRITE));
+    assert_eq!(control.length(1), Some(BreakpointLength::BREAKPOINT_LENGTH_4));
+
+    /* There is no DR4 breakpoint, the shifts would run into other fields */
+    let unchanged = control.set(4, BreakpointKind::BREAKPOINT_WRITE,
+                                BreakpointLength::BREAKPOINT_LENGTH_8);
+    assert_eq!(unchanged, control);
+    assert_eq!(control.clear(16), control);
+    assert!(!control.enabled(32));
+    assert_eq!(control.kind(4), None);
+    assert_eq!(control.length(64), None);
+    assert_eq!(libos::debugreg::address(4), None);
+
+    let control = control.clear(1);
+    assert!(!control.enabled(1));
+    assert_eq!(control.0 & (0xf << 20), 0);
+
+    let status = DebugStatus(0xffff0ff0 | 1 << 2 | 1 << 14);
+    assert!(status.triggered(2) && !status.triggered(0));
+    assert!(!status.triggered(4) && !status.triggered(64));
+    assert!(status.single_step() && !status.task_switch());
+}
+
+static WATCHED: AtomicU64 = AtomicU64::new(0);
+static WATCH_HITS: AtomicU64 = AtomicU64::new(0);
+static WATCH_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+fn watch_handler(event: libos::debugreg::DebugEvent) {
+    WATCH_HITS.fetch_add(1, Ordering::SeqCst);
+    WATCH_ADDRESS.store(event.address.unwrap_or(0), Ordering::SeqCst);
+}
+
+#[test_case]
+fn debug_watchpoint_hit() {
+    use libos::debugreg::{self, BreakpointKind, BreakpointLength, DebugError};
+
+    libos::idt::init_idt();
+    debugreg::set_handler(Some(watch_handler));
+
+    let address = &WATCHED as *const AtomicU64 as u64;
+    assert_eq!(debugreg::add_breakpoint(address + 1, BreakpointKind::BREAKPOINT_WRITE,
+                                        BreakpointLength::BREAKPOINT_LENGTH_8),
+               Err(DebugError::DEBUG_ERROR_UNALIGNED));
+
+    let index = debugreg::add_breakpoint(address, BreakpointKind::BREAKPOINT_WRITE,
+                                         BreakpointLength::BREAKPOINT_LENGTH_8).unwrap();
+
+    /* Reads do not fire a write watchpoint */
+    assert_eq!(WATCHED.load(Ordering::SeqCst), 0);
+    WATCHED.store(1, Ordering::SeqCst);
+    assert_eq!(WATCH_HITS.load(Ordering::SeqCst), 1);
+    assert_eq!(WATCH_ADDRESS.load(Ordering::SeqCst), address);
+
+    debugreg::clear_breakpoint(index);
+    WATCHED.store(2, Ordering::SeqCst);
+    assert_eq!(WATCH_HITS.load(Ordering::SeqCst), 1);
+
+    debugreg::set_handler(None);
+}
+
+static NOOP_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(noop_clone, noop, noop, noop);
+
+unsafe fn noop_clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &NOOP_WAKER_VTABLE)
+}
+
+unsafe fn noop(_data: *const ()) {}
+
+fn poll_once<F: Future>(future: &mut F) -> Poll<F::Output> {
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &NOOP_WAKER_VTABLE)) };
+    let mut context = Context::from_waker(&waker);
+
+    unsafe { Pin::new_unchecked(future) }.poll(&mut context)
+}
+
+/* Keeps `depth` waiters queued at once, one per stack frame */
+fn wait_nested(queue: &libos::sync::WaitQueue, depth: usize) -> usize {
+    if depth == 0 {
+        return queue.wake_all();
+    }
+
+    let mut wait = queue.wait_until_async(|| false);
+    assert_eq!(poll_once(&mut wait), Poll::Pending);
+    wait_nested(queue, depth - 1)
+}
+
+#[test_case]
+fn waitqueue_many_waiters() {
+    use libos::sched::MAX_THREADS;
+    use libos::sync::WaitQueue;
+
+    let queue = WaitQueue::new();
+
+    /* More than there are threads, the queue has no fixed size */
+    assert_eq!(wait_nested(&queue, MAX_THREADS * 2), MAX_THREADS * 2);
+    assert_eq!(queue.wake_all(), 0);
+}
+
+#[test_case]
+fn waitqueue_dropped_waiters() {
+    use libos::sync::WaitQueue;
+
+    let queue = WaitQueue::new();
+
+    /* Each dropped future has to unlink itself */
+    for _ in 0..64 {
+        let mut wait = queue.wait_until_async(|| false);
+        assert_eq!(poll_once(&mut wait), Poll::Pending);
+    }
+
+    /* A removal in the middle keeps the others queued, in order */
+    let mut first = queue.wait_until_async(|| false);
+    let mut second = queue.wait_until_async(|| false);
+    let mut third = queue.wait_until_async(|| false);
+    assert_eq!(poll_once(&mut first), Poll::Pending);
+    assert_eq!(poll_once(&mut second), Poll::Pending);
+    assert_eq!(poll_once(&mut third), Poll::Pending);
+
+    drop(second);
+    assert_eq!(queue.wake_all(), 2);
+    assert_eq!(queue.wake_all(), 0);
+}
+
+#[test_case]
+fn framebuffer_drawing() {
+    use libos::font::{self, FONT_HEIGHT, FONT_WIDTH};
+    use libos::framebuffer::{Color, Framebuffer, FramebufferInfo, PixelFormat};
+    use libos::vm::VM;
+
+    /* Plain RAM stands in for the device, stride wider than a line */
+    let info = FramebufferInfo {
+        address: VM::alloc_pages(2).unwrap(),
+        width: 32,
+        height: 26,
+        stride: 256,
+        bytes_per_pixel: 4,
+        format: PixelFormat::PIXEL_FORMAT_BGR,
+    };
+    let base = VM::phys_to_virt(info.address) as *const u8;
+    let pixel = |x: usize, y: usize| -> [u8; 4] {
+        let offset = y * info.stride + x * info.bytes_per_pixel;
+        unsafe { core::ptr::read_volatile(base.add(offset) as *const [u8; 4]) }
+    };
+
+    Framebuffer::init(info).unwrap();
+    assert_eq!(pixel(0, 0), [0, 0, 0, 0]);
+
+    Framebuffer::put_pixel(1, 2, Color::from_rgb(0x123456));
+    assert_eq!(pixel(1, 2), [0x56, 0x34, 0x12, 0]);
+
+    /* Off screen pixels are dropped, not written past the line */
+    Framebuffer::put_pixel(info.width, 2, Color::WHITE);
+    assert_eq!(pixel(info.width, 2), [0, 0, 0, 0]);
+
+    /* Clipped at the right and bottom edges */
+    Framebuffer::fill_rect(30, 24, 8, 8, Color::from_rgb(0xff0000));
+    assert_eq!(pixel(31, 25), [0, 0, 0xff, 0]);
+    assert_eq!(pixel(29, 25), [0, 0, 0, 0]);
+    assert_eq!(pixel(info.width, 25), [0, 0, 0, 0]);
+
+    Framebuffer::draw_char(8, 13, b'A', Color::WHITE, Color::BLACK);
+    for (row, bits) in font::glyph(b'A').iter().enumerate() {
+        for column in 0..FONT_WIDTH {
+            let expected = if bits & (0x80 >> column) != 0 { 0xff } else { 0 };
+            assert_eq!(pixel(8 + column, 13 + row), [expected, expected, expected, 0]);
+        }
+    }
+
+    /* The console scrolls by a text row once the last one is full */
+    Framebuffer::clear();
+    Framebuffer::set_colors(Color::WHITE, Color::BLACK);
+    Framebuffer::write_bytes(b"\nA\n");
+    let glyph = font::glyph(b'A');
+    for row in 0..FONT_HEIGHT {
+        let expected = if glyph[row] & 0x80 != 0 { 0xff } else { 0 };
+        assert_eq!(pixel(0, row), [expected, expected, expected, 0]);
+        assert_eq!(pixel(0, FONT_HEIGHT + row), [0, 0, 0, 0]);
+    }
+}
+
 pub fn test_runner(tests: &[&dyn Fn()]) {
     for test in tests {
         test();
@@ -43,6 +624,10 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
 #[cfg(test)]
 entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    libos::vm::VM::set_phys_offset(boot_info.physical_memory_offset);
+    libos::page_alloc::page_alloc_init(boot_info);
+    libos::cpu::CPU::register().unwrap();
+
     #[cfg(test)]
     test_main();
 